@@ -1,28 +1,91 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
+use super::linker::link;
+use super::object::Object;
+use super::parser::parse_tokens;
 use crate::assembler::exporter::{write_as_bin, write_as_str};
+use crate::assembler::lexer::export_tokens;
+use crate::compiler::ass_gen::memory_manager::{allocated_vars, pinned_addrs, reserved_ranges};
 use crate::utils::command_line::Args;
+use crate::utils::hardware_conf::Hardware;
 use crate::{assembler::lexer::tokenize, utils::nid_fs::read_file};
-use super::parser::parse_tokens;
 
-pub fn assemble_program(args: &Args, program: &Path) -> PathBuf {
+/// Assembles an ASS file and links it on its own into a binary program.
+pub fn assemble_program(args: &Args, program: &Path, hardware_conf: &Hardware) -> PathBuf {
     // Generate the correct filename
-    let output_name: PathBuf = PathBuf::from(program.to_str().unwrap().replace(".ass", ".out"));
+    let output_name: PathBuf = match &args.output {
+        Some(output) => output.clone(),
+        None => program.with_extension("out"),
+    };
 
-    // Generate assembly tokens
+    let object: Object = assemble_object(args, program, hardware_conf);
+    let binary: Vec<u32> = link(vec![object], hardware_conf);
+    write_binary(args, &output_name, &binary);
+
+    // Return binary program name
+    output_name
+}
+
+/// Assembles an ASS file into an object file, to be linked later.
+pub fn assemble_to_object_file(args: &Args, program: &Path, hardware_conf: &Hardware) -> PathBuf {
+    let output_name: PathBuf = match &args.output {
+        Some(output) => output.clone(),
+        None => program.with_extension("o"),
+    };
+
+    let object: Object = assemble_object(args, program, hardware_conf);
+    if args.verbose {
+        println!("Writing to {} ...", output_name.display())
+    }
+    object.write(&output_name);
+
+    output_name
+}
+
+/// Links object files into a binary program.
+pub fn link_objects(args: &Args, hardware_conf: &Hardware) -> PathBuf {
+    let output_name: PathBuf = match &args.output {
+        Some(output) => output.clone(),
+        None => args.objects[0].with_extension("out"),
+    };
+
+    let objects: Vec<Object> = args.objects.iter().map(|path| Object::from(path)).collect();
+    let binary: Vec<u32> = link(objects, hardware_conf);
+    write_binary(args, &output_name, &binary);
+
+    output_name
+}
+
+/// Assembles an ASS file into an object. Variables placed by the compiler while compiling the file
+/// can be moved by the linker, while every other address is left as is.
+fn assemble_object(args: &Args, program: &Path, hardware_conf: &Hardware) -> Object {
     let code = read_file(program);
-    let tokens = tokenize(code);
 
-    let test_byte: Vec<u32> = vec![947];
+    // Generate assembly tokens
+    let mut tokens = tokenize(code);
+    if args.verbose {
+        export_tokens(&tokens);
+    }
+
+    let vars: HashMap<u16, u32> = allocated_vars()
+        .iter()
+        .map(|item| (item.addr, item.var_id))
+        .collect();
+    let mut object: Object = parse_tokens(&mut tokens, &vars);
+    object.mem_addresses = hardware_conf.mem_addresses;
+    object.pinned = pinned_addrs();
+    object.reserved = reserved_ranges();
+    object
+}
+
+fn write_binary(args: &Args, output_name: &Path, binary: &[u32]) {
     if args.verbose {
         println!("Writing to {} ...", output_name.display())
     }
     if args.string_output {
-        write_as_str(&output_name, &test_byte)
+        write_as_str(output_name, binary)
     } else {
-        write_as_bin(&output_name, &test_byte)
+        write_as_bin(output_name, binary)
     }
-
-    // Return binary program name
-    output_name
 }
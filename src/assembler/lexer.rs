@@ -3,6 +3,8 @@
 * turning it into tokens that the assembler can understand.
 */
 
+use crate::compiler::ass_gen::memory_manager::VAR_PREFIX;
+use crate::utils::literals::escaped_char;
 use core::panic;
 use std::collections::VecDeque;
 
@@ -12,7 +14,10 @@ pub enum TokenType {
     Amode,
     Register,
     Numeric,
-    RoutineName,
+    Variable,    // Address of a variable placed by the compiler, eg. `@4`
+    RoutineName, // Start of a routine, eg. `name:`
+    Label,       // Branch label, eg. `#label`
+    Identifier,  // Name of a routine used as an operand, eg. `call name`
     Eol,
 }
 #[derive(Debug, Clone)]
@@ -28,20 +33,6 @@ pub fn export_tokens(tokens: &VecDeque<Token>) {
     }
 }
 
-/// Removes comments from program
-pub fn remove_comments(file_content: &str) -> String {
-    let mut new_program: String = String::new();
-
-    for line in file_content.lines() {
-        let mut trimmed_line: &str = line;
-        if let Some(pos) = line.find(";") {
-            trimmed_line = &trimmed_line[..pos];
-        }
-        new_program.push_str(trimmed_line)
-    }
-    new_program
-}
-
 /// Converts the source code from a contious string of text to a queue of tokens.
 pub fn tokenize(file_content: String) -> VecDeque<Token> {
     // Strip any comments from the ASS code
@@ -65,19 +56,32 @@ pub fn tokenize(file_content: String) -> VecDeque<Token> {
                 value: String::new(),
                 token_type: TokenType::Eol,
             }
-        } else if current_char == ',' || current_char == ' ' {
+        } else if current_char == ',' || current_char.is_whitespace() {
             continue; // Skip seperating characters
+        } else if current_char == '#' {
+            Token {
+                value: format!("#{}", build_word(&mut src_code)),
+                token_type: TokenType::Label,
+            }
         } else if is_letter(current_char) {
             src_code.push_front(current_char);
             let asm_word = build_word(&mut src_code);
-            if current_char == 'r' && is_register(&src_code) {
-                let register = build_num(&mut src_code);
+            let follows_op: bool = token_queue
+                .back()
+                .is_some_and(|token| token.token_type == TokenType::Operation);
+
+            if src_code.front() == Some(&':') {
+                src_code.pop_front();
+                Token {
+                    value: asm_word,
+                    token_type: TokenType::RoutineName,
+                }
+            } else if let Some(register) = numbered(&asm_word, 'r') {
                 Token {
                     value: register,
                     token_type: TokenType::Register,
                 }
-            } else if current_char == 'a' && is_amode(&src_code) {
-                let mode = build_num(&mut src_code);
+            } else if let Some(mode) = numbered(&asm_word, 'a').filter(|_| follows_op) {
                 Token {
                     value: mode,
                     token_type: TokenType::Amode,
@@ -87,21 +91,35 @@ pub fn tokenize(file_content: String) -> VecDeque<Token> {
                     value: asm_word,
                     token_type: TokenType::Operation,
                 }
-            } else if *src_code.front().unwrap() == ':' {
+            } else {
                 Token {
                     value: asm_word,
-                    token_type: TokenType::RoutineName,
+                    token_type: TokenType::Identifier,
                 }
-            } else {
-                panic!("Invalid word found!")
             }
         } else if is_num(current_char) {
             src_code.push_front(current_char);
-            let num = build_value(&mut src_code);
+            let num = build_num(&mut src_code);
             Token {
                 value: num,
                 token_type: TokenType::Numeric,
             }
+        } else if current_char == '-' && src_code.front().is_some_and(|c| is_num(*c)) {
+            Token {
+                value: format!("-{}", build_num(&mut src_code)),
+                token_type: TokenType::Numeric,
+            }
+        } else if current_char == VAR_PREFIX {
+            Token {
+                value: build_num(&mut src_code),
+                token_type: TokenType::Variable,
+            }
+        } else if current_char == '\'' {
+            // Chars are numbers in ASS, eg. 'A' is 65
+            Token {
+                value: (build_char(&mut src_code) as u32).to_string(),
+                token_type: TokenType::Numeric,
+            }
         } else {
             panic!("Invalid token detected! | {}", current_char);
         };
@@ -126,17 +144,22 @@ fn is_reserved_keyword(word: &str) -> bool {
     let keyword_map: Vec<&str> = vec![
         "nop", "ld", "ldi", "st", "psh", "pop", "add", "addi", "sub", "subi", "cmp", "cmpi", "mul",
         "muli", "div", "divi", "and", "andi", "or", "ori", "not", "xor", "xori", "call", "ret",
-        "jmp", "jmpi", "beq", "bne", "bpr", "bnr", "bge", "blt",
+        "jmp", "jmpi", "beq", "bne", "bpr", "bnr", "bge", "blt", "lsl", "lsr", "kbd", "byk",
+        "wait",
     ];
 
     keyword_map.contains(&word)
 }
 
-/// Builds a string (a word or number) from a series of chars.
+/// Builds a word from a series of chars. Words can contain numbers and ., eg. `r12` or
+/// `physics.step`.
 fn build_word(src_code: &mut VecDeque<char>) -> String {
     let mut string_val: String = String::new();
 
-    while is_letter(*src_code.front().unwrap()) {
+    while src_code
+        .front()
+        .is_some_and(|c| is_letter(*c) || is_num(*c) || *c == '.')
+    {
         string_val.push(*src_code.front().unwrap());
         src_code.pop_front();
     }
@@ -144,33 +167,37 @@ fn build_word(src_code: &mut VecDeque<char>) -> String {
     string_val
 }
 
-/// Builds a string representing a number
+/// Builds a string representing a number, such as 15, 0x0E or 0b1010_0001. The number is checked
+/// when it is parsed.
 fn build_num(src_code: &mut VecDeque<char>) -> String {
     let mut num_string: String = String::new();
 
-    while is_num(*src_code.front().unwrap()) {
+    while src_code
+        .front()
+        .is_some_and(|c| is_num(*c) || is_letter(*c))
+    {
         num_string.push(src_code.pop_front().unwrap());
     }
 
     num_string
 }
 
-/// Builds the value that is either a constant or address.
-fn build_value(src_code: &mut VecDeque<char>) -> String {
-    format!(
-        "{val:016b}",
-        val = build_num(src_code).parse::<i32>().unwrap()
-    )
-}
-
-/// Checks if register is found
-fn is_register(src_code: &VecDeque<char>) -> bool {
-    src_code.front().unwrap().is_numeric()
+/// Reads a char literal after its opening ', eg. A' or \n'.
+fn build_char(src_code: &mut VecDeque<char>) -> char {
+    let mut char_value: char = src_code.pop_front().expect("Unclosed char literal!");
+    if char_value == '\\' {
+        char_value = escaped_char(src_code.pop_front().expect("Unclosed char literal!"));
+    }
+    if src_code.pop_front() != Some('\'') {
+        panic!("Char literals can only hold a single char!");
+    }
+    char_value
 }
 
-/// Just checks for number like is_register
-fn is_amode(src_code: &VecDeque<char>) -> bool {
-    is_register(src_code)
+/// Returns the number in words like `r12` or `a01`, if the word starts with prefix.
+fn numbered(word: &str, prefix: char) -> Option<String> {
+    let num: &str = word.strip_prefix(prefix)?;
+    (!num.is_empty() && num.chars().all(is_num)).then(|| num.to_string())
 }
 
 /// Removes all comments from the ASS code
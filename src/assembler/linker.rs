@@ -0,0 +1,251 @@
+/*
+* Combines object files into a single program.
+*
+* The object containing main() is placed first, since execution starts at the first word, and the
+* rest follow in the order they were given. Every variable then keeps the address the compiler gave
+* it, unless an object compiled on its own put something else there. Those variables are moved to
+* the first free address, skipping reserved addresses and addresses used with move_to(). Like in the
+* compiler, variables only go in the globals regions of the hardware config. Variables with the same
+* id are the same variable, which is how parameters are shared between a function and the files
+* calling it.
+*/
+
+use super::object::{Object, RelocTarget};
+use crate::compiler::ass_gen::memory_manager::{is_var_addr, scratch_start};
+use crate::compiler::stdlib::is_std_function;
+use crate::utils::hardware_conf::{Hardware, MemRegion};
+use std::collections::{HashMap, HashSet};
+
+/// Links the objects into the words of the final program.
+pub fn link(mut objects: Vec<Object>, hardware_conf: &Hardware) -> Vec<u32> {
+    for object in objects.iter() {
+        if object.mem_addresses != hardware_conf.mem_addresses {
+            panic!(
+                "Object compiled for {} DM addresses, but linking for {}! Use the same hardware config for both.",
+                object.mem_addresses, hardware_conf.mem_addresses
+            );
+        }
+    }
+
+    // main() has to come first
+    match objects.iter().filter(|object| object.entry).count() {
+        0 => panic!("No main() found in any of the object files!"),
+        1 => {}
+        _ => panic!("main() found in more than one object file!"),
+    }
+    let entry: usize = objects.iter().position(|object| object.entry).unwrap();
+    let entry_object: Object = objects.remove(entry);
+    objects.insert(0, entry_object);
+
+    // Place the code of every object after the previous one
+    let mut bases: Vec<u16> = Vec::new();
+    let mut size: usize = 0;
+    for object in objects.iter() {
+        bases.push(size as u16);
+        size += object.words.len();
+    }
+    if size > u16::MAX as usize {
+        panic!("Program is {size} words, which is more than can be addressed!");
+    }
+
+    let mut routines: HashMap<&str, u16> = HashMap::new();
+    for (object, base) in objects.iter().zip(bases.iter()) {
+        for symbol in object.symbols.iter() {
+            // Every object using the standard library has its own copy of it, any of them will do
+            if is_std_function(&symbol.name) && routines.contains_key(symbol.name.as_str()) {
+                continue;
+            }
+            if routines
+                .insert(&symbol.name, base + symbol.offset)
+                .is_some()
+            {
+                panic!("{} is defined in more than one object file!", symbol.name);
+            }
+        }
+    }
+
+    let var_addrs: HashMap<u32, u16> = place_vars(&objects, hardware_conf);
+
+    let mut program: Vec<u32> = Vec::with_capacity(size);
+    for (object, base) in objects.iter().zip(bases.iter()) {
+        let mut words: Vec<u32> = object.words.clone();
+        for relocation in object.relocations.iter() {
+            let value: u16 = match &relocation.target {
+                RelocTarget::Local(offset) => base + offset,
+                RelocTarget::Routine(name) => *routines
+                    .get(name.as_str())
+                    .unwrap_or_else(|| panic!("Routine {name} is not defined in any object file!")),
+                RelocTarget::Var(var) => var_addrs[var],
+            };
+            words[relocation.word] = (words[relocation.word] & !0xFFFF) | value as u32;
+        }
+        program.append(&mut words);
+    }
+
+    program
+}
+
+/// Gives every variable used by the objects an address in DM. The address the compiler gave a
+/// variable is still in the operand, and is kept whenever it is free.
+fn place_vars(objects: &[Object], hardware_conf: &Hardware) -> HashMap<u32, u16> {
+    let pinned: HashSet<u16> = objects
+        .iter()
+        .flat_map(|object| object.pinned.iter().copied())
+        .collect();
+    let reserved: Vec<(u16, u16)> = objects
+        .iter()
+        .flat_map(|object| object.reserved.iter().copied())
+        .collect();
+    let regions: Vec<MemRegion> = hardware_conf.memory_layout();
+    let is_free = |addr: u16| -> bool {
+        is_var_addr(&regions, addr)
+            && !pinned.contains(&addr)
+            && !reserved
+                .iter()
+                .any(|(start, end)| *start <= addr && addr <= *end)
+    };
+
+    let limit: u16 = scratch_start(&regions);
+    let mut var_addrs: HashMap<u32, u16> = HashMap::new();
+    let mut taken: HashSet<u16> = HashSet::new();
+
+    // Variables are placed in the order they are first used, to keep the output the same between
+    // runs
+    let vars = objects.iter().flat_map(|object| {
+        object
+            .relocations
+            .iter()
+            .filter_map(|relocation| match relocation.target {
+                RelocTarget::Var(var) => Some((var, object.words[relocation.word] as u16)),
+                _ => None,
+            })
+    });
+    for (var, compiler_addr) in vars {
+        if var_addrs.contains_key(&var) {
+            continue;
+        }
+        let addr: u16 = if compiler_addr < limit
+            && is_free(compiler_addr)
+            && !taken.contains(&compiler_addr)
+        {
+            compiler_addr
+        } else {
+            (0..limit)
+                .find(|addr| is_free(*addr) && !taken.contains(addr))
+                .unwrap_or_else(|| {
+                    let available: usize = (0..limit).filter(|addr| is_free(*addr)).count();
+                    panic!(
+                        "Variables don't fit in the globals regions! Only {available} addresses are available."
+                    )
+                })
+        };
+        var_addrs.insert(var, addr);
+        taken.insert(addr);
+    }
+
+    var_addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::object::{Relocation, Symbol};
+
+    /// An object of the given words, compiled for the default hardware.
+    fn object(entry: bool, words: Vec<u32>) -> Object {
+        Object {
+            mem_addresses: Hardware::default().mem_addresses,
+            entry,
+            words,
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            pinned: Vec::new(),
+            reserved: Vec::new(),
+        }
+    }
+
+    fn relocation(word: usize, target: RelocTarget) -> Relocation {
+        Relocation { word, target }
+    }
+
+    #[test]
+    fn places_main_first_and_fills_in_code_addresses() {
+        let mut library = object(false, vec![0x100, 0x200]);
+        library.symbols.push(Symbol {
+            name: "lib.f".to_string(),
+            offset: 1,
+        });
+        library
+            .relocations
+            .push(relocation(0, RelocTarget::Local(1)));
+
+        let mut main = object(true, vec![0x300, 0x400]);
+        main.relocations
+            .push(relocation(1, RelocTarget::Routine("lib.f".to_string())));
+
+        let program: Vec<u32> = link(vec![library, main], &Hardware::default());
+        assert_eq!(program, [0x300, 0x003, 0x003, 0x200]);
+    }
+
+    #[test]
+    fn variables_keep_the_addresses_from_the_compiler() {
+        let mut main = object(true, vec![7, 9, 7]);
+        for (word, var) in [(0, 11), (1, 12), (2, 11)] {
+            main.relocations
+                .push(relocation(word, RelocTarget::Var(var)));
+        }
+
+        let program: Vec<u32> = link(vec![main], &Hardware::default());
+        assert_eq!(program, [7, 9, 7]);
+    }
+
+    #[test]
+    fn clashing_variables_skip_pinned_and_reserved_addresses() {
+        let mut main = object(true, vec![0]);
+        main.pinned.push(1);
+        main.reserved = vec![(2, 4)];
+        main.relocations.push(relocation(0, RelocTarget::Var(11)));
+
+        // Compiled on its own, so its variables start at 0 as well. 11 is shared with main.
+        let mut library = object(false, vec![0, 1]);
+        for (word, var) in [(0, 12), (1, 11)] {
+            library
+                .relocations
+                .push(relocation(word, RelocTarget::Var(var)));
+        }
+
+        let program: Vec<u32> = link(vec![main, library], &Hardware::default());
+        assert_eq!(program, [0, 5, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No main() found")]
+    fn needs_main() {
+        link(vec![object(false, Vec::new())], &Hardware::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "lib.f is defined in more than one object file!")]
+    fn routines_are_defined_once() {
+        let objects: Vec<Object> = [true, false]
+            .into_iter()
+            .map(|entry| {
+                let mut object = object(entry, vec![0]);
+                object.symbols.push(Symbol {
+                    name: "lib.f".to_string(),
+                    offset: 0,
+                });
+                object
+            })
+            .collect();
+        link(objects, &Hardware::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Use the same hardware config for both")]
+    fn objects_match_the_hardware() {
+        let mut main = object(true, Vec::new());
+        main.mem_addresses = 1024;
+        link(vec![main], &Hardware::default());
+    }
+}
@@ -1,4 +1,6 @@
 pub mod assemble;
 pub mod exporter;
 pub mod lexer;
+pub mod linker;
+pub mod object;
 pub mod parser;
@@ -0,0 +1,101 @@
+/*
+* Relocatable object files, produced by assembling a single file and combined into a program by
+* the linker.
+*
+* An object holds the encoded words of the file, along with everything the linker needs to place
+* it: the routines it defines, the operands that point at code or variables, and the DM addresses
+* that are not allowed to be moved around. Objects are stored as TOML.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Object {
+    pub mem_addresses: u16, // DM size the file was compiled for
+    pub entry: bool,        // Whether the code starts with main(), which has to be placed first
+    pub words: Vec<u32>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub pinned: Vec<u16>,          // Addresses used with move_to()
+    pub reserved: Vec<(u16, u16)>, // Ranges set aside with #RESERVE or the PREALLOC macros
+}
+
+/// A routine defined in the object.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u16, // Position of the first word, counted from the start of the object
+}
+
+/// An operand that can only be filled in once the object is placed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Relocation {
+    pub word: usize,
+    pub target: RelocTarget,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RelocTarget {
+    Local(u16),      // A label in the same object, given as an offset from its start
+    Routine(String), // A routine in any object
+    Var(u32),        // A variable, placed in DM by the linker
+}
+
+impl Object {
+    /// Reads an object file.
+    pub fn from(filename: &Path) -> Self {
+        let content: String = fs::read_to_string(filename)
+            .unwrap_or_else(|e| panic!("Failed to read {}! Err: {e}", filename.display()));
+        toml::from_str(&content).unwrap_or_else(|e| {
+            panic!(
+                "{} is not a valid object file! Err: {e}",
+                filename.display()
+            )
+        })
+    }
+
+    /// Writes the object to file.
+    pub fn write(&self, filename: &Path) {
+        let content: String = toml::to_string(self).expect("Failed to serialize object file!");
+        fs::write(filename, content)
+            .unwrap_or_else(|e| panic!("Failed to write {}! Err: {e}", filename.display()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_being_written_and_read() {
+        let object = Object {
+            mem_addresses: 255,
+            entry: true,
+            words: vec![1, 2, 3],
+            symbols: vec![Symbol {
+                name: "lib.f".to_string(),
+                offset: 2,
+            }],
+            relocations: vec![
+                Relocation {
+                    word: 0,
+                    target: RelocTarget::Local(2),
+                },
+                Relocation {
+                    word: 1,
+                    target: RelocTarget::Var(42),
+                },
+            ],
+            pinned: vec![250],
+            reserved: vec![(10, 20), (30, 31)],
+        };
+        let path = std::env::temp_dir().join(format!("nidc_object_{}.o", std::process::id()));
+        object.write(&path);
+        let read: Object = Object::from(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(format!("{read:?}"), format!("{object:?}"));
+    }
+}
@@ -1,58 +1,226 @@
 /*
 * This file is responsible for parsing tokens as a program.
+*
+* Every instruction is encoded into a single word, laid out as described in docs/binary.md.
+* Operands that refer to labels or routines can't be known until the linker has placed the program,
+* so they are left as 0 and recorded as relocations instead. Variables (`@4`) keep the address the
+* compiler gave them, and are recorded as relocations in case the linker has to move them.
 */
 
 use std::collections::{HashMap, VecDeque};
 
 use super::lexer::{Token, TokenType};
+use super::object::{Object, RelocTarget, Relocation, Symbol};
+use crate::utils::literals::parse_int_literal;
 
-/// Converts a Dequeu of Tokens into a Vec of strings, representing
-/// the binary code of the program.
-pub fn parse_tokens(tokens: &mut VecDeque<Token>) -> Vec<String> {
-    let mut program: Vec<String> = Vec::new();
-
-    let mut line: String = String::new();
-    for (i, token) in tokens.iter().enumerate() {
-        // Push the binary representation of the operation
-        if token.token_type == TokenType::Operation {
-            line.push_str(&op_to_bin(&token.value));
-
-            // A-mode always come after an op.
-            // If no a-mode is given, default to 00.
-            // tokens.len() is used for bounds checking in the case of
-            // instructions that are only an op, such as nop.
-            if tokens.len() > i + 1 && tokens.get(i + 1).unwrap().token_type != TokenType::Amode {
-                line.push_str("00")
+/// A line of ASS, split into its tokens.
+struct Line {
+    number: usize, // Line in the source file, counted from 0
+    tokens: Vec<Token>,
+}
+
+/// Converts a Dequeu of Tokens into an object. vars maps DM addresses to the variables the
+/// compiler placed there. Variables missing from it, eg. when only assembling, are never moved.
+pub fn parse_tokens(tokens: &mut VecDeque<Token>, vars: &HashMap<u16, u32>) -> Object {
+    let lines: Vec<Line> = split_lines(tokens);
+
+    // First pass finds where every label and routine ends up
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut symbols: Vec<Symbol> = Vec::new();
+    let mut offset: u16 = 0;
+    for line in lines.iter() {
+        match line.tokens[..] {
+            [Token {
+                token_type: TokenType::Label,
+                ref value,
+            }] => {
+                if labels.insert(value.clone(), offset).is_some() {
+                    panic!("Label {value} defined more than once!");
+                }
             }
+            [Token {
+                token_type: TokenType::RoutineName,
+                ref value,
+            }] => symbols.push(Symbol {
+                name: value.clone(),
+                offset,
+            }),
+            _ => offset += 1,
         }
+    }
 
-        if token.token_type == TokenType::Amode {
-            line.push_str(&token.value);
+    // Second pass encodes the instructions
+    let mut words: Vec<u32> = Vec::new();
+    let mut relocations: Vec<Relocation> = Vec::new();
+    for line in lines.iter() {
+        if line.tokens[0].token_type != TokenType::Operation {
+            continue;
         }
-
-        if token.token_type == TokenType::Register {
-            // Cursed conversion of string representation of decimal number to integer back to
-            // binary representation of said number
-            line.push_str(format!("{val:04b}", val = token.value.parse::<i32>().unwrap()).as_str());
+        let (word, target) = encode_line(line, &labels, vars);
+        if let Some(target) = target {
+            relocations.push(Relocation {
+                word: words.len(),
+                target,
+            });
         }
+        words.push(word);
+    }
 
-        if token.token_type == TokenType::Numeric {
-            line.push_str(&token.value);
-        }
+    // Execution starts at the first word, which is main() unless the file starts with a routine
+    let entry: bool = lines
+        .first()
+        .is_some_and(|line| line.tokens[0].token_type != TokenType::RoutineName);
 
-        // At end of line, push to the program and clear
-        // for next line of ASS.
+    Object {
+        mem_addresses: 0,
+        entry,
+        words,
+        symbols,
+        relocations,
+        pinned: Vec::new(),
+        reserved: Vec::new(),
+    }
+}
+
+/// Splits the tokens into lines, leaving out empty ones.
+fn split_lines(tokens: &mut VecDeque<Token>) -> Vec<Line> {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut line: Vec<Token> = Vec::new();
+    let mut number: usize = 0;
+
+    while let Some(token) = tokens.pop_front() {
         if token.token_type == TokenType::Eol {
-            program.push(line.clone());
-            line.clear();
+            if !line.is_empty() {
+                lines.push(Line {
+                    number,
+                    tokens: std::mem::take(&mut line),
+                });
+            }
+            number += 1;
+        } else {
+            line.push(token);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(Line {
+            number,
+            tokens: line,
+        });
+    }
+
+    lines
+}
+
+/// Encodes a single instruction. Returns the word along with what its operand refers to, if the
+/// linker has to fill it in.
+fn encode_line(
+    line: &Line,
+    labels: &HashMap<String, u16>,
+    vars: &HashMap<u16, u32>,
+) -> (u32, Option<RelocTarget>) {
+    let op: &str = &line.tokens[0].value;
+    if UNSUPPORTED_OPS.contains(&op) {
+        panic!(
+            "{op} on line {} is not supported by the assembler, the instruction set has no encoding for it!",
+            line.number + 1
+        );
+    }
+    let mut amode: u32 = 0;
+    let mut register: u32 = 0;
+    let mut value: u32 = 0;
+    let mut target: Option<RelocTarget> = None;
+
+    for token in line.tokens[1..].iter() {
+        match token.token_type {
+            // A-mode is written as binary, eg. a01
+            TokenType::Amode => {
+                amode = u32::from_str_radix(&token.value, 2)
+                    .ok()
+                    .filter(|mode| *mode < 4)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Invalid A-mode a{} on line {}!",
+                            token.value,
+                            line.number + 1
+                        )
+                    })
+            }
+            TokenType::Register => {
+                register = token.value.parse().unwrap();
+                if register > 15 {
+                    panic!(
+                        "Register r{register} doesn't fit in 4 bits! Line {}",
+                        line.number + 1
+                    );
+                }
+            }
+            TokenType::Numeric => value = numeric_value(op, &token.value, line.number),
+            TokenType::Variable => {
+                if !takes_address(op) {
+                    panic!(
+                        "Variable @{} used on line {}, but {op} doesn't take an address!",
+                        token.value,
+                        line.number + 1
+                    );
+                }
+                value = numeric_value(op, &token.value, line.number);
+                target = vars.get(&(value as u16)).map(|var| RelocTarget::Var(*var));
+            }
+            TokenType::Label => match labels.get(&token.value) {
+                Some(offset) => target = Some(RelocTarget::Local(*offset)),
+                None => panic!(
+                    "Label {} used on line {} doesn't exist!",
+                    token.value,
+                    line.number + 1
+                ),
+            },
+            TokenType::Identifier => target = Some(RelocTarget::Routine(token.value.clone())),
+            _ => panic!("Unexpected {} on line {}!", token.value, line.number + 1),
         }
     }
 
-    program
+    (
+        (op_to_bin(op) << 22) | (amode << 20) | (register << 16) | value,
+        target,
+    )
+}
+
+/// Encodes a number as the 16 bits of the value field. Constants may be negative, in which case
+/// they are stored in two's complement, while addresses may not.
+fn numeric_value(op: &str, num: &str, line_number: usize) -> u32 {
+    let min: i64 = if takes_address(op) {
+        0
+    } else {
+        i16::MIN as i64
+    };
+    let value: Option<i64> = match num.strip_prefix('-') {
+        Some(digits) => parse_int_literal(digits).map(|value| -value),
+        None => parse_int_literal(num),
+    };
+    match value {
+        Some(value) if (min..=u16::MAX as i64).contains(&value) => value as u32 & 0xFFFF,
+        Some(value) if value < 0 && min == 0 => panic!(
+            "Address {value} on line {} can't be negative!",
+            line_number + 1
+        ),
+        Some(_) => panic!("{num} on line {} doesn't fit in 16 bits!", line_number + 1),
+        None => panic!("Invalid number {num} on line {}!", line_number + 1),
+    }
+}
+
+/// Returns whether the operand of an operation is a DM address.
+fn takes_address(op: &str) -> bool {
+    matches!(
+        op,
+        "ld" | "st" | "add" | "sub" | "cmp" | "mul" | "div" | "and" | "or" | "not" | "xor"
+    )
 }
 
+/// Operations generated by the compiler that have no encoding in the instruction set yet.
+const UNSUPPORTED_OPS: &[&str] = &["lsl", "lsr", "kbd", "byk", "wait"];
+
 /// Converts operation name to binary
-fn op_to_bin(op_name: &str) -> String {
+fn op_to_bin(op_name: &str) -> u32 {
     let ops = HashMap::from([
         ("nop", "000000"),
         ("ld", "000001"),
@@ -89,5 +257,108 @@ fn op_to_bin(op_name: &str) -> String {
         ("blt", "100000"),
     ]);
 
-    String::from(*ops.get(op_name).unwrap())
+    u32::from_str_radix(ops[op_name], 2).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::lexer::tokenize;
+
+    /// Assembles the code with variable 7 placed at address 10 by the compiler.
+    fn assemble(code: &str) -> Object {
+        let vars: HashMap<u16, u32> = HashMap::from([(10, 7)]);
+        parse_tokens(&mut tokenize(code.to_string()), &vars)
+    }
+
+    /// Lists the relocations of an object as (word, target).
+    fn relocations(object: &Object) -> Vec<(usize, String)> {
+        object
+            .relocations
+            .iter()
+            .map(|relocation| (relocation.word, format!("{:?}", relocation.target)))
+            .collect()
+    }
+
+    #[test]
+    fn encodes_op_amode_register_and_value() {
+        let object = assemble("ldi r3, 5\nst a01, r2, 300\nsubi r1, 7\nret\n");
+        assert_eq!(
+            object.words,
+            [
+                (0b000010 << 22) | (3 << 16) | 5,
+                (0b000011 << 22) | (0b01 << 20) | (2 << 16) | 300,
+                (0b001001 << 22) | (1 << 16) | 7,
+                0b011000 << 22,
+            ]
+        );
+    }
+
+    #[test]
+    fn labels_and_routines_become_relocations() {
+        let code = "jmp #end\nhelper:\ncall helper\n#end\nret\n";
+        let object = assemble(code);
+        assert_eq!(object.words.len(), 3);
+        assert_eq!(
+            relocations(&object),
+            [
+                (0, "Local(2)".to_string()),
+                (1, "Routine(\"helper\")".to_string())
+            ]
+        );
+        assert_eq!(object.symbols[0].name, "helper");
+        assert_eq!(object.symbols[0].offset, 1);
+        assert!(object.entry);
+    }
+
+    #[test]
+    fn files_starting_with_a_routine_have_no_entry() {
+        assert!(!assemble("helper:\nret\n").entry);
+    }
+
+    #[test]
+    fn only_variable_operands_are_relocated() {
+        let object = assemble("ld r0, @10\nldi r0, 10\nld r0, 10\nld r0, @11\n");
+        assert_eq!(relocations(&object), [(0, "Var(7)".to_string())]);
+        // The compiler's address is kept until the linker says otherwise
+        assert_eq!(object.words[0] & 0xFFFF, 10);
+        assert_eq!(object.words[3] & 0xFFFF, 11);
+    }
+
+    #[test]
+    #[should_panic(expected = "Variable @10 used on line 1, but ldi doesn't take an address!")]
+    fn variables_are_only_used_as_addresses() {
+        assemble("ldi r0, @10\n");
+    }
+
+    #[test]
+    fn negative_constants_are_stored_in_twos_complement() {
+        let object = assemble("ldi r1, -1\nsubi r2, -32768\n");
+        assert_eq!(object.words[0] & 0xFFFF, 0xFFFF);
+        assert_eq!(object.words[1] & 0xFFFF, 0x8000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Address -3 on line 1 can't be negative!")]
+    fn addresses_can_not_be_negative() {
+        assemble("st a00, r1, -3\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "70000 on line 2 doesn't fit in 16 bits!")]
+    fn values_have_to_fit_in_16_bits() {
+        assemble("ldi r1, 1\nldi r1, 70000\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "wait on line 2 is not supported by the assembler")]
+    fn operations_without_an_encoding_are_rejected() {
+        assemble("ldi r0, 1\nwait 100\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Label #missing used on line 1 doesn't exist!")]
+    fn missing_labels_are_rejected() {
+        assemble("jmp #missing\n");
+    }
 }
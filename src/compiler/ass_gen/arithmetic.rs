@@ -5,227 +5,258 @@
 * Since these functions will be used by the compiler some sanity checks aren't performed since they
 * are assumed to not be able to happen.
 *
-* Some assumptions that are also made:
-* If only one register, addr or cosnt is used, it will use the first one. Therefore there are only
-* checks for the first of these whenever you check for a field.
+* All operations work in place on the register passed to them, with the second operand being read
+* from DM or passed as a constant, just like the ALU instructions themselves.
+*
+* Without mul or div instructions, the builder calls runtime functions for *, / and % unless the
+* second operand is a power of two, which is all that is left to be handled here with shifts and
+* masks.
+*
+* When the instructions do exist, they are used even for powers of two. The hardware only shifts a
+* single bit per instruction, so multiplying by 2^n takes n words instead of one. Dividing a signed
+* value also has to shift its magnitude, since the shifts are logical, which adds a branch and two
+* negations on top. Multiplying by 2 is the exception, where a single shift is as small as muli.
 */
 
-use super::memory_manager::{
-    decrement_stack_ptr, get_reg, get_var_id_from_addr, push_to_stack, read_from_dm,
-};
-
-/// Global variable to get the latest result register
-pub static mut LATEST_RESULT: u8 = 0;
-
-/// Performs addition on 2 operands. Only expects 2 parameters to be Some
-pub fn add(
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-    const2: Option<i16>,
-) -> Vec<String> {
-    if const1.is_some() && const2.is_some() {
-        let reg: u8 = get_reg(None);
-        unsafe { LATEST_RESULT = reg };
-        return vec![format!(
-            "ldi, {}, {}",
-            reg,
-            const1.unwrap() + const2.unwrap()
-        )];
-    }
-    if const1.is_some() {
-        return perform_op("addi", reg1, reg2, addr1, addr2, const1);
-    }
-    perform_op("add", reg1, reg2, addr1, addr2, const1)
+use super::memory_manager::VAR_PREFIX;
+use crate::compiler::ir::builder::random_branch_name;
+use crate::utils::hardware_conf::Hardware;
+
+/// Second operand of an ALU instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOperand {
+    Addr(u16),
+    Var(u16), // Address of a variable, which the linker may move
+    Const(i16),
 }
 
-/// Performs subtractions on 2 operands. Only expects 2 parameters to be Some
-pub fn sub(
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-    const2: Option<i16>,
-) -> Vec<String> {
-    if const1.is_some() && const2.is_some() {
-        let reg: u8 = get_reg(None);
-        unsafe { LATEST_RESULT = reg };
-        return vec![format!(
-            "ldi, {}, {}",
-            reg,
-            const1.unwrap() - const2.unwrap()
-        )];
-    }
-    if const1.is_some() {
-        return perform_op("subi", reg1, reg2, addr1, addr2, const1);
-    }
-    perform_op("sub", reg1, reg2, addr1, addr2, const1)
+/// Performs addition on register and operand.
+pub fn add(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("add", register, operand)
 }
 
-/// Performs multiplication on 2 operands. Only expects 2 parameters to be Some
-pub fn mul(
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-    const2: Option<i16>,
-) -> Vec<String> {
-    if const1.is_some() && const2.is_some() {
-        let reg: u8 = get_reg(None);
-        unsafe { LATEST_RESULT = reg };
-        return vec![format!(
-            "ldi, {}, {}",
-            reg,
-            const1.unwrap() * const2.unwrap()
-        )];
-    }
-    if let Some(val) = const1 {
-        if val == 2 {
-            return vec![lsl(reg1.unwrap())];
+/// Performs subtractions on register and operand.
+pub fn sub(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("sub", register, operand)
+}
+
+/// Performs multiplication on register and operand. Multiplying by a power of two is a shift
+/// left, which is used for 2 even with a mul instruction.
+pub fn mul(register: u8, operand: AluOperand, hardware_conf: &Hardware) -> Vec<String> {
+    match power_of_two(operand) {
+        Some(bits) if bits == 1 || !hardware_conf.has_mul => shl(register, AluOperand::Const(bits)),
+        _ if !hardware_conf.has_mul => {
+            panic!("Compiler error! Multiplying without mul calls math.mul().")
         }
-        return perform_op("muli", reg1, reg2, addr1, addr2, const1);
+        _ => perform_op("mul", register, operand),
     }
-    perform_op("mul", reg1, reg2, addr1, addr2, const1)
 }
 
-/// Performs division on 2 operands. Only expects 2 parameters to be Some
-pub fn div(
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-    const2: Option<i16>,
-) -> Vec<String> {
-    if const1.is_some() && const2.is_some() {
-        let reg: u8 = get_reg(None);
-        unsafe { LATEST_RESULT = reg };
-        return vec![format!(
-            "ldi, {}, {}",
-            reg,
-            const1.unwrap() / const2.unwrap()
-        )];
-    }
-    if let Some(val) = const1 {
-        if val == 2 {
-            return vec![lsr(reg1.unwrap())];
+/// Performs division on register and operand. Without a div instruction, dividing by a power of two
+/// shifts the magnitude right, which rounds towards zero just like div.
+pub fn div(register: u8, operand: AluOperand, hardware_conf: &Hardware) -> Vec<String> {
+    match power_of_two(operand) {
+        Some(bits) if !hardware_conf.has_div => {
+            on_magnitude(register, shr(register, AluOperand::Const(bits)))
         }
-        return perform_op("divi", reg1, reg2, addr1, addr2, const1);
+        _ if !hardware_conf.has_div => {
+            panic!("Compiler error! Dividing without div calls math.div().")
+        }
+        _ => perform_op("div", register, operand),
     }
-    perform_op("div", reg1, reg2, addr1, addr2, const1)
-}
-
-/// Performs comparison on 2 operands. Basically a subtraction below the hood, but does not return
-/// anything. Only affects the flags set by ALU. Only expects 2 parameters to be Some
-pub fn cmp(
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-    const2: Option<i16>,
+}
+
+/// Calculates the remainder of dividing register by operand, as register - register / operand *
+/// operand. The register is kept at scratch while dividing, which the operand can't be read from.
+/// Without mul or div, the remainder of a power of two is the low bits of the magnitude.
+pub fn rem(
+    register: u8,
+    operand: AluOperand,
+    scratch: u16,
+    hardware_conf: &Hardware,
 ) -> Vec<String> {
-    if const1.is_some() && const2.is_some() {
-        panic!("Compiler error! Two constants should not have entered the cmp() function!")
-    } else if const1.is_some() {
-        perform_op("cmpi", reg1, reg2, addr1, addr2, const1)
-    } else {
-        perform_op("cmp", reg1, reg2, addr1, addr2, const1)
+    if !hardware_conf.has_mul || !hardware_conf.has_div {
+        return match power_of_two(operand) {
+            Some(bits) => on_magnitude(register, and(register, AluOperand::Const((1 << bits) - 1))),
+            None => panic!("Compiler error! Remainders without mul and div call math.mod()."),
+        };
     }
+
+    let mut instructions: Vec<String> = vec![format!("st, r{register}, {scratch}")];
+    instructions.append(&mut div(register, operand, hardware_conf));
+    instructions.append(&mut mul(register, operand, hardware_conf));
+    // Leaves the remainder negated, which is flipped back with two's complement
+    instructions.append(&mut sub(register, AluOperand::Addr(scratch)));
+    instructions.append(&mut negate(register));
+    instructions
+}
+
+/// Performs bitwise and on register and operand.
+pub fn and(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("and", register, operand)
+}
+
+/// Performs bitwise or on register and operand.
+pub fn or(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("or", register, operand)
+}
+
+/// Performs bitwise xor on register and operand.
+pub fn xor(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("xor", register, operand)
+}
+
+/// Shifts register left by a constant amount, one bit at the time.
+pub fn shl(register: u8, operand: AluOperand) -> Vec<String> {
+    shift(register, operand, lsl)
+}
+
+/// Shifts register right by a constant amount, one bit at the time.
+pub fn shr(register: u8, operand: AluOperand) -> Vec<String> {
+    shift(register, operand, lsr)
+}
+
+/// Performs comparison on register and operand. Basically a subtraction below the hood, but does
+/// not return anything. Only affects the flags set by ALU.
+pub fn cmp(register: u8, operand: AluOperand) -> Vec<String> {
+    perform_op("cmp", register, operand)
 }
 
 /// Logical shift left
 pub fn lsl(register: u8) -> String {
-    unsafe { LATEST_RESULT = register };
     format!("lsl, r{register}")
 }
 
 /// Logical shift right
 pub fn lsr(register: u8) -> String {
-    unsafe { LATEST_RESULT = register };
     format!("lsr, r{register}")
 }
 
-/// Hepler function to avoid code duplication
-fn perform_op(
-    op: &str,
-    reg1: Option<u8>,
-    reg2: Option<u8>,
-    addr1: Option<u16>,
-    addr2: Option<u16>,
-    const1: Option<i16>,
-) -> Vec<String> {
-    let mut instructions: Vec<String> = Vec::new();
-    let mut work_reg: String = String::from(""); // Default working register
-    let mut const_val: Option<i16> = None;
-    let mut asm_addr: Option<u16> = None;
-    let mut stack_pushed: bool = false; // To know if stack needs to be popped after arithmetic
-                                        // operation.
-
-    /*
-     * Check what registers are used.
-     */
-    if let Some(reg) = reg1 {
-        work_reg = format!("r{reg}");
+/// Returns the exponent of a constant operand that is a power of two.
+fn power_of_two(operand: AluOperand) -> Option<i16> {
+    match operand {
+        AluOperand::Const(val) if val > 0 && (val as u16).is_power_of_two() => {
+            Some(val.trailing_zeros() as i16)
+        }
+        _ => None,
     }
+}
+
+/// Negates register with two's complement.
+fn negate(register: u8) -> Vec<String> {
+    let mut instructions: Vec<String> = xor(register, AluOperand::Const(-1));
+    instructions.append(&mut add(register, AluOperand::Const(1)));
+    instructions
+}
 
-    // If second register is used, it has to be pushed to the stack
-    if let Some(reg) = reg2 {
-        instructions.push(push_to_stack(reg));
-        stack_pushed = true;
+/// Applies ops to the magnitude of register and gives the result the sign of register back.
+fn on_magnitude(register: u8, ops: Vec<String>) -> Vec<String> {
+    if ops.is_empty() {
+        return ops;
     }
+    let negative: String = random_branch_name();
+    let done: String = random_branch_name();
+
+    let mut instructions: Vec<String> = cmp(register, AluOperand::Const(0));
+    instructions.push(format!("blt {negative}"));
+    instructions.extend(ops.iter().cloned());
+    instructions.push(format!("jmp {done}"));
+    instructions.push(negative);
+    instructions.append(&mut negate(register));
+    instructions.extend(ops);
+    instructions.append(&mut negate(register));
+    instructions.push(done);
+    instructions
+}
 
-    /*
-     * Add addresses to instrucitons
-     */
-    if reg1.is_none() && reg2.is_none() {
-        // If no register was set, use addr1 as first term
-        if let Some(addr) = addr1 {
-            instructions.push(read_from_dm(0, addr));
-
-            // Get a valid / optimal register to work on
-            let var_id: Option<u32> = get_var_id_from_addr(addr);
-            work_reg = format!("r{}", get_reg(var_id)); // Set register to work on.
-
-            if let Some(addr) = addr2 {
-                asm_addr = Some(addr);
-            }
+/// Unrolls a shift by a constant into single bit shifts. Shifting by 16 or more clears the register.
+fn shift(register: u8, operand: AluOperand, shift_once: fn(u8) -> String) -> Vec<String> {
+    match operand {
+        AluOperand::Const(amount) if amount <= 0 => Vec::new(),
+        AluOperand::Const(amount) if amount < 16 => vec![shift_once(register); amount as usize],
+        AluOperand::Const(_) => vec![format!("ldi, r{register}, 0")],
+        AluOperand::Addr(_) | AluOperand::Var(_) => {
+            panic!("Compiler error! Shifts by a variable are built as loops.")
         }
-    } else if reg2.is_none() {
-        asm_addr = addr1; // Set to addr1, if addr1 is none it will be replaced with the const
-                          // in the end of this function
     }
+}
 
-    // Add constants to instructions, if addresses werent added
-    if asm_addr.is_none() && const1.is_some() {
-        const_val = const1;
+/// Hepler function to avoid code duplication. Picks the immediate version of the instruction when
+/// the operand is a constant.
+fn perform_op(op: &str, register: u8, operand: AluOperand) -> Vec<String> {
+    match operand {
+        AluOperand::Addr(addr) => vec![format!("{op}, r{register}, {addr}")],
+        AluOperand::Var(addr) => vec![format!("{op}, r{register}, {VAR_PREFIX}{addr}")],
+        AluOperand::Const(val) => vec![format!("{op}i, r{register}, {val}")],
     }
+}
 
-    // Just sanity checks that compiler generated a correct instruction.
-    if work_reg.is_empty() {
-        panic!("No register set to work on!");
-    }
-    if asm_addr.is_none() && const_val.is_none() {
-        panic!("Second operand not set!"); // Panic if invalid instruction
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitwise_ops_pick_the_immediate_version_for_constants() {
+        assert_eq!(and(1, AluOperand::Const(15)), ["andi, r1, 15"]);
+        assert_eq!(or(2, AluOperand::Addr(40)), ["or, r2, 40"]);
+        assert_eq!(and(2, AluOperand::Var(4)), ["and, r2, @4"]);
+        assert_eq!(xor(3, AluOperand::Const(-1)), ["xori, r3, -1"]);
     }
 
-    if let Some(addr) = asm_addr {
-        instructions.push(format!("{op}, {work_reg}, {}", addr));
-    } else {
-        instructions.push(format!("{op}, {work_reg}, {}", const_val.unwrap()));
+    #[test]
+    fn constant_shifts_are_unrolled() {
+        assert_eq!(
+            shl(1, AluOperand::Const(3)),
+            ["lsl, r1", "lsl, r1", "lsl, r1"]
+        );
+        assert_eq!(shr(1, AluOperand::Const(0)), Vec::<String>::new());
+        assert_eq!(shr(1, AluOperand::Const(16)), ["ldi, r1, 0"]);
     }
 
-    if stack_pushed {
-        decrement_stack_ptr() // Just pop the stack_ptr
+    #[test]
+    fn remainder_subtracts_the_divided_value() {
+        assert_eq!(
+            rem(2, AluOperand::Const(3), 200, &Hardware::default()),
+            [
+                "st, r2, 200",
+                "divi, r2, 3",
+                "muli, r2, 3",
+                "sub, r2, 200",
+                "xori, r2, -1",
+                "addi, r2, 1"
+            ]
+        );
     }
 
-    // Set latest result register
-    work_reg.remove(0);
-    unsafe { LATEST_RESULT = work_reg.parse::<u8>().unwrap() };
+    #[test]
+    fn finds_powers_of_two() {
+        assert_eq!(power_of_two(AluOperand::Const(1)), Some(0));
+        assert_eq!(power_of_two(AluOperand::Const(16)), Some(4));
+        assert_eq!(power_of_two(AluOperand::Const(12)), None);
+        assert_eq!(power_of_two(AluOperand::Const(-4)), None);
+        assert_eq!(power_of_two(AluOperand::Addr(8)), None);
+    }
 
-    instructions
+    #[test]
+    fn negative_values_are_worked_on_as_their_magnitude() {
+        let ops: Vec<String> = vec![String::from("lsr, r1")];
+        let instructions: Vec<String> = on_magnitude(1, ops);
+        let negative: &str = instructions[1].strip_prefix("blt ").unwrap();
+        let done: &str = instructions[3].strip_prefix("jmp ").unwrap();
+        assert_eq!(instructions[0], "cmpi, r1, 0");
+        assert_eq!(instructions[2], "lsr, r1");
+        assert_eq!(
+            instructions[4..],
+            [
+                negative,
+                "xori, r1, -1",
+                "addi, r1, 1",
+                "lsr, r1",
+                "xori, r1, -1",
+                "addi, r1, 1",
+                done
+            ]
+        );
+        assert!(on_magnitude(1, Vec::new()).is_empty());
+    }
 }
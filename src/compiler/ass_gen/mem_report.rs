@@ -0,0 +1,372 @@
+/*
+* Static report of the memory and registers a program needs, printed with --mem-report.
+*
+* Every function records the variables, calls and registers it ended up with while its ASS is
+* generated. The call graph is then walked to find how deep the call stack can get, and how much
+* DM is in use at most while each function runs. As DM is never reused between functions, that is
+* the DM of the function itself plus the DM of everything it calls.
+*
+* The build fails if the deepest chain of calls doesn't fit in the stack region of the hardware
+* config, since the hardware would otherwise silently overwrite memory.
+*/
+
+use super::memory_manager::{get_max_regs, read_from_mem_map};
+use crate::compiler::ir::liveness::vreg_liveness;
+use crate::compiler::ir::{BranchCond, Inst, IrFunction, IrProgram, Operand, VReg, VarId};
+use crate::utils::hardware_conf::{Hardware, RegionKind};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// What a single function ended up using once its ASS was generated.
+struct FunctionUsage {
+    name: String,
+    vars: HashSet<VarId>, // Variables read or written, including spill slots
+    calls: Vec<String>,
+    max_live: usize, // Most values held in registers at the same time, before spilling
+    regs: HashSet<u8>,
+}
+
+lazy_static! {
+    static ref USAGE: Mutex<Vec<FunctionUsage>> = Mutex::new(Vec::new());
+}
+
+/// Records the usage of a function. max_live is measured before registers are allocated, as
+/// spilling hides the actual pressure.
+pub fn record_usage(func: &IrFunction, max_live: usize, regs: &HashMap<VReg, u8>) {
+    let mut vars: HashSet<VarId> = HashSet::new();
+    let mut calls: Vec<String> = Vec::new();
+    for inst in func.blocks.iter().flat_map(|block| block.insts.iter()) {
+        vars.extend(inst_vars(inst));
+        if let Inst::Call { name, .. } = inst {
+            calls.push(name.clone());
+        }
+    }
+
+    USAGE
+        .lock()
+        .expect("Failed to lock on USAGE")
+        .push(FunctionUsage {
+            name: func.name.clone(),
+            vars,
+            calls,
+            max_live,
+            regs: regs.values().copied().collect(),
+        });
+}
+
+/// Returns the most virtual registers live at the same time anywhere in a function.
+pub fn max_live(func: &IrFunction) -> usize {
+    let liveness = vreg_liveness(func);
+    let mut max_live: usize = 0;
+
+    for block in func.blocks.iter() {
+        let mut live: HashSet<VReg> = liveness.live_out[&block.id].clone();
+        live.extend(block.term.uses());
+        max_live = max_live.max(live.len());
+
+        for inst in block.insts.iter().rev() {
+            if let Some(def) = inst.def() {
+                live.remove(&def);
+            }
+            live.extend(inst.uses());
+            max_live = max_live.max(live.len());
+        }
+    }
+
+    max_live
+}
+
+/// Checks that the calls of the program fit on the call stack, and prints the report if asked to.
+pub fn check_memory(program: &IrProgram, hardware_conf: &Hardware, report: bool) {
+    let usage = USAGE.lock().expect("Failed to lock on USAGE");
+    let functions: HashMap<&str, &FunctionUsage> = usage
+        .iter()
+        .map(|func| (func.name.as_str(), func))
+        .collect();
+
+    // The params of a function are written by its callers, but belong to the function itself
+    let mut owners: HashMap<VarId, &str> = HashMap::new();
+    for func in program.functions.iter() {
+        for param in func.params.iter() {
+            owners.insert(*param, &func.name);
+        }
+    }
+    let own_addrs = |func: &FunctionUsage| -> HashSet<u16> {
+        func.vars
+            .iter()
+            .filter(|var| owners.get(var).is_none_or(|owner| *owner == func.name))
+            .chain(
+                program
+                    .functions
+                    .iter()
+                    .filter(|f| f.name == func.name)
+                    .flat_map(|f| f.params.iter()),
+            )
+            .filter_map(|var| read_from_mem_map(*var))
+            .collect()
+    };
+
+    let mut chains: HashMap<&str, Vec<&str>> = HashMap::new();
+    for func in usage.iter() {
+        deepest_chain(&func.name, &functions, &mut chains);
+    }
+
+    // Execution starts in main(), but every exported function may be called in an object file
+    let roots: Vec<&str> = match program.entry_point {
+        Some(entry) => vec![program.functions[entry].name.as_str()],
+        None => usage.iter().map(|func| func.name.as_str()).collect(),
+    };
+    let worst: Vec<&str> = roots
+        .iter()
+        .map(|root| chains[root].clone())
+        .max_by_key(|chain| chain.len())
+        .unwrap_or_default();
+    // Every call uses a slot, which is every function in the chain but main()
+    let depth: usize = match program.entry_point {
+        Some(_) => worst.len().saturating_sub(1),
+        None => worst.len(),
+    };
+
+    let stack_size: u16 = hardware_conf
+        .memory_layout()
+        .iter()
+        .find(|region| region.kind == RegionKind::Stack)
+        .map_or(0, |region| region.size());
+
+    if report {
+        println!("Memory report:");
+        println!(
+            "  {:<24} {:>4} {:>14} {:>6} {:>5} {:>6}",
+            "Function", "DM", "DM w/ callees", "Stack", "Live", "Regs"
+        );
+        for func in usage.iter() {
+            let callees: HashSet<&str> = reachable(&func.name, &functions);
+            let total: HashSet<u16> = callees
+                .iter()
+                .filter_map(|name| functions.get(name))
+                .flat_map(|callee| own_addrs(callee))
+                .collect();
+            println!(
+                "  {:<24} {:>4} {:>14} {:>6} {:>5} {:>6}",
+                func.name,
+                own_addrs(func).len(),
+                total.len(),
+                chains[func.name.as_str()].len() - 1,
+                func.max_live,
+                format!("{}/{}", func.regs.len(), get_max_regs())
+            );
+        }
+        println!(
+            "  Worst-case call stack: {depth} of {stack_size} ({})",
+            worst.join(" -> ")
+        );
+    }
+
+    if depth > stack_size as usize {
+        panic!(
+            "The call stack can get {depth} calls deep ({}), but the stack region only holds {stack_size}!",
+            worst.join(" -> ")
+        );
+    }
+}
+
+/// Finds the longest chain of calls starting in a function. Functions defined in other object files
+/// are counted as a single call, as their calls are unknown.
+fn deepest_chain<'a>(
+    name: &'a str,
+    functions: &HashMap<&'a str, &'a FunctionUsage>,
+    chains: &mut HashMap<&'a str, Vec<&'a str>>,
+) -> Vec<&'a str> {
+    if let Some(chain) = chains.get(name) {
+        return chain.clone();
+    }
+
+    // Recursion is rejected while building the IR, so this always ends
+    let mut longest: Vec<&str> = Vec::new();
+    if let Some(func) = functions.get(name) {
+        for callee in func.calls.iter() {
+            let chain: Vec<&str> = deepest_chain(callee, functions, chains);
+            if chain.len() > longest.len() {
+                longest = chain;
+            }
+        }
+    }
+
+    let mut chain: Vec<&str> = vec![name];
+    chain.append(&mut longest);
+    chains.insert(name, chain.clone());
+    chain
+}
+
+/// Returns a function along with every function it may end up calling.
+fn reachable<'a>(
+    name: &'a str,
+    functions: &HashMap<&'a str, &'a FunctionUsage>,
+) -> HashSet<&'a str> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut worklist: Vec<&str> = vec![name];
+
+    while let Some(name) = worklist.pop() {
+        if seen.insert(name) {
+            if let Some(func) = functions.get(name) {
+                worklist.extend(func.calls.iter().map(|callee| callee.as_str()));
+            }
+        }
+    }
+
+    seen
+}
+
+/// Returns the variables read or written by an instruction.
+fn inst_vars(inst: &Inst) -> Vec<VarId> {
+    match inst {
+        Inst::Load { var, .. } | Inst::Store { var, .. } | Inst::MoveTo { var, .. } => vec![*var],
+        Inst::BinOp {
+            rhs: Operand::Var(var),
+            ..
+        }
+        | Inst::LoadPtr {
+            addr: Operand::Var(var),
+            ..
+        }
+        | Inst::StorePtr {
+            addr: Operand::Var(var),
+            ..
+        } => vec![*var],
+        Inst::Builtin { args, .. } => args
+            .iter()
+            .filter_map(|arg| match arg {
+                Operand::Var(var) => Some(*var),
+                _ => None,
+            })
+            .collect(),
+        Inst::SetCond {
+            cond:
+                BranchCond::Cmp {
+                    rhs: Operand::Var(var),
+                    ..
+                },
+            ..
+        } => vec![*var],
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ir::{IrOp, Terminator};
+    use crate::utils::hardware_conf::MemRegion;
+
+    fn function(name: &str, calls: &[&str]) -> IrFunction {
+        let insts: Vec<Inst> = calls
+            .iter()
+            .map(|callee| Inst::Call {
+                name: callee.to_string(),
+                params: Vec::new(),
+                dst: None,
+            })
+            .collect();
+        IrFunction::from_blocks(name, vec![(insts, Terminator::Return(None))])
+    }
+
+    fn usage(name: &str, calls: &[&str]) -> FunctionUsage {
+        FunctionUsage {
+            name: name.to_string(),
+            vars: HashSet::new(),
+            calls: calls.iter().map(|callee| callee.to_string()).collect(),
+            max_live: 0,
+            regs: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn counts_values_live_at_the_same_time() {
+        let konst = |dst: u32, value: i16| Inst::LoadConst {
+            dst: VReg(dst),
+            value,
+        };
+        let add = |dst: u32, lhs: u32, rhs: u32| Inst::BinOp {
+            op: IrOp::Add,
+            dst: VReg(dst),
+            lhs: VReg(lhs),
+            rhs: Operand::Reg(VReg(rhs)),
+        };
+        let func = IrFunction::from_blocks(
+            "f",
+            vec![(
+                vec![
+                    konst(0, 1),
+                    konst(1, 2),
+                    konst(2, 3),
+                    add(3, 0, 1),
+                    add(4, 3, 2),
+                ],
+                Terminator::Return(Some(Operand::Reg(VReg(4)))),
+            )],
+        );
+        assert_eq!(max_live(&func), 3);
+    }
+
+    #[test]
+    fn finds_the_deepest_chain_of_calls() {
+        let usage: Vec<FunctionUsage> = vec![
+            usage("main", &["a", "b"]),
+            usage("a", &["lib.f"]),
+            usage("b", &["c"]),
+            usage("c", &[]),
+        ];
+        let functions: HashMap<&str, &FunctionUsage> = usage
+            .iter()
+            .map(|func| (func.name.as_str(), func))
+            .collect();
+        let mut chains: HashMap<&str, Vec<&str>> = HashMap::new();
+
+        assert_eq!(
+            deepest_chain("main", &functions, &mut chains),
+            ["main", "a", "lib.f"]
+        );
+        assert_eq!(chains["b"], ["b", "c"]);
+        assert_eq!(reachable("b", &functions), HashSet::from(["b", "c"]));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "The call stack can get 3 calls deep (deep_main -> deep_a -> deep_b -> deep_c), but the stack region only holds 2!"
+    )]
+    fn calls_have_to_fit_in_the_stack_region() {
+        let functions: Vec<IrFunction> = vec![
+            function("deep_main", &["deep_a"]),
+            function("deep_a", &["deep_b"]),
+            function("deep_b", &["deep_c"]),
+            function("deep_c", &[]),
+        ];
+        for func in functions.iter() {
+            record_usage(func, 0, &HashMap::new());
+        }
+        let program = IrProgram {
+            functions,
+            entry_point: Some(0),
+            exports: Vec::new(),
+            externs: Vec::new(),
+            prealloc_start: None,
+            prealloc_end: None,
+            reserved: Vec::new(),
+        };
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        let hardware_conf = Hardware {
+            regions: vec![
+                region("globals", RegionKind::Globals, 0, 99),
+                region("stack", RegionKind::Stack, 100, 101),
+            ],
+            ..Hardware::default()
+        };
+        check_memory(&program, &hardware_conf, false);
+    }
+}
@@ -8,33 +8,96 @@
 * not require the compiler to know where things are stored.
 */
 
+use crate::utils::hardware_conf::{MemRegion, RegionKind};
 use lazy_static::lazy_static;
-use std::collections::VecDeque;
 use std::sync::Mutex;
 
 /// Struct representing an item in memory, such as a varible or an array.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MemoryItem {
     pub var_id: u32,
-    pub reg: Option<u8>,
     pub addr: u16,
 }
 
 lazy_static! {
     static ref MEMORY_MAP: Mutex<Vec<MemoryItem>> = Mutex::new(Vec::new());
-    static ref REG_MAP: Mutex<VecDeque<MemoryItem>> = Mutex::new(VecDeque::new());
+    // Addresses variables have been moved to with move_to(). Something outside of the program,
+    // such as the screen, is expected to read these.
+    static ref PINNED_ADDRS: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+    // Every address handed out by allocate_var(), even if the variable was later moved. Used to
+    // tell the linker which addresses it is free to move around.
+    static ref ALLOCATED: Mutex<Vec<MemoryItem>> = Mutex::new(Vec::new());
+    // Layout of DM from the hardware config, sorted by address
+    static ref REGIONS: Mutex<Vec<MemRegion>> = Mutex::new(Vec::new());
+    // Ranges set aside by the user, that the compiler is not allowed to touch. Useful if something
+    // in asm {} or the hardware requires memory to not be overwritten by the compiler.
+    static ref RESERVED: Mutex<Vec<Reservation>> = Mutex::new(Vec::new());
+}
+
+/// A named range of DM reserved with #RESERVE or the PREALLOC macros, both ends included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
 }
 
 // Acts as a stack pointer to allow the compiler to use the more optimized st and ld instructions,
 // rather than psh or pop
 static mut STACK_PTR: u16 = 0;
-pub static mut MAX_ADDR: u16 = 0;
 
-// Set the maximum number of memory addresses available to compiler/CPU
-pub fn set_max_addr(max_addr: u16) {
+// Written in front of operands holding the address of a variable, eg. `ld, r0, @4`. Only these are
+// moved by the linker, every other address is left as is.
+pub const VAR_PREFIX: char = '@';
+
+// Number of addresses at the end of the last globals region used by the compiler as scratch space,
+// for things like moving values between registers.
+pub const SCRATCH_SIZE: u16 = 2;
+
+/// Sets the regions of DM available to the compiler/CPU.
+pub fn set_memory_layout(regions: Vec<MemRegion>) {
+    scratch_start(&regions); // Make sure there is room for the scratch space
     unsafe {
-        MAX_ADDR = max_addr;
+        STACK_PTR = regions
+            .iter()
+            .find(|region| region.kind == RegionKind::Globals)
+            .map_or(0, |region| region.start);
+    }
+    *REGIONS.lock().expect("Failed to lock on REGIONS") = regions;
+}
+
+/// Returns the region an address belongs to, if any.
+pub fn region_of(addr: u16) -> Option<MemRegion> {
+    REGIONS
+        .lock()
+        .expect("Failed to lock on REGIONS")
+        .iter()
+        .find(|region| region.contains(addr))
+        .cloned()
+}
+
+/// Returns the first scratch address, placed at the end of the last globals region.
+pub fn scratch_start(regions: &[MemRegion]) -> u16 {
+    let globals: &MemRegion = regions
+        .iter()
+        .rfind(|region| region.kind == RegionKind::Globals)
+        .expect("No globals region to place variables in!");
+    if globals.size() <= SCRATCH_SIZE {
+        panic!(
+            "Globals region {} is too small, it needs more than {SCRATCH_SIZE} addresses!",
+            globals.name
+        );
     }
+    globals.end + 1 - SCRATCH_SIZE
+}
+
+/// Returns whether variables may be placed at an address, which is anywhere in a globals region
+/// except for the scratch space.
+pub fn is_var_addr(regions: &[MemRegion], addr: u16) -> bool {
+    addr < scratch_start(regions)
+        && regions
+            .iter()
+            .any(|region| region.kind == RegionKind::Globals && region.contains(addr))
 }
 
 // Set the maximum number of registers available to compiler/CPU
@@ -46,55 +109,68 @@ pub fn set_max_regs(max_regs: u8) {
     }
 }
 
-// Address range that is allocated at compile time by the user that is not allowed to be touched by
-// the compiler. Useful if something in asm {} requires memory to not be overwritten by the
-// compuiler.
-pub static mut PREALLOC_START: u16 = u16::MAX;
-pub static mut PREALLOC_END: u16 = u16::MAX;
-/// Push variable to the next available position in the "DM stack"
-pub fn push_to_stack(register: u8) -> String {
+/// Returns the number of registers available to the compiler
+pub fn get_max_regs() -> u8 {
+    unsafe { MAX_REGS }
+}
+
+/// Allocates the next available position in the "DM stack" for a variable.
+pub fn allocate_var(var_id: u32) -> u16 {
+    let regions: Vec<MemRegion> = REGIONS.lock().expect("Failed to lock on REGIONS").clone();
+    let pinned: Vec<u16> = pinned_addrs();
+    let is_free = |addr: u16| -> bool {
+        is_var_addr(&regions, addr) && reserved_at(addr).is_none() && !pinned.contains(&addr)
+    };
+
     unsafe {
-        // Jump over preallocated range if one is set
-        if PREALLOC_START <= STACK_PTR && STACK_PTR <= PREALLOC_END && PREALLOC_END < MAX_ADDR {
-            STACK_PTR = PREALLOC_END + 1;
+        // Jump over reserved ranges, moved variables and anything outside of the globals regions
+        let scratch: u16 = scratch_start(&regions);
+        while STACK_PTR < scratch && !is_free(STACK_PTR) {
+            STACK_PTR += 1;
         }
-        if PREALLOC_START <= STACK_PTR && STACK_PTR <= PREALLOC_END {
-            panic!("Trying to allocated memory inside user defined range!")
+        if STACK_PTR >= scratch {
+            let available: usize = (0..scratch).filter(|addr| is_free(*addr)).count();
+            panic!("Variables don't fit in the globals regions! Only {available} addresses are available.")
         }
-        if STACK_PTR >= MAX_ADDR {
-            panic!("Trying to allocated outside of MAX_ADDR!")
-        }
-        let ass_output: String = format!("st, r{register}, {STACK_PTR}");
+        let addr: u16 = STACK_PTR;
         STACK_PTR += 1;
 
-        ass_output
+        push_to_mem_map(var_id, addr);
+        ALLOCATED
+            .lock()
+            .expect("Failed to lock on ALLOCATED")
+            .push(MemoryItem { var_id, addr });
+        addr
     }
 }
 
-// TODO: Find a use for the function below
-/// Pop variable from the last position in the "DM stack"
-pub fn _pop_from_stack(register: u8) -> String {
-    unsafe {
-        // Jump over preallocated range if one is set
-        if PREALLOC_START <= STACK_PTR && STACK_PTR <= PREALLOC_END && PREALLOC_START > 0 {
-            STACK_PTR = PREALLOC_START - 1;
-        }
-
-        let ass_output: String = format!("ld, r{register}, {STACK_PTR}");
+/// Returns the address of a variable in DM, allocating one if the variable has none yet.
+pub fn get_var_addr(var_id: u32) -> u16 {
+    read_from_mem_map(var_id).unwrap_or_else(|| allocate_var(var_id))
+}
 
-        STACK_PTR -= 1;
+/// Returns one of the scratch addresses reserved for the compiler.
+pub fn scratch_addr(index: u16) -> u16 {
+    if index >= SCRATCH_SIZE {
+        panic!("Compiler error! Scratch address {index} out of range!")
+    }
+    scratch_start(&REGIONS.lock().expect("Failed to lock on REGIONS")) + index
+}
 
-        ass_output
+/// Panics if the program is not allowed to write to addr.
+pub fn check_writable(addr: u16) {
+    match region_of(addr) {
+        Some(region) if region.kind == RegionKind::Stack => {
+            panic!("Trying to write to {addr}, which is in the call stack!")
+        }
+        Some(_) => {}
+        None => panic!("Trying to write to {addr}, which is outside of every memory region!"),
     }
 }
 
 /// Store data from regisster to addr in DM
 pub fn write_to_dm(register: u8, addr: u16) -> String {
-    unsafe {
-        if addr >= MAX_ADDR {
-            panic!("Trying to allocated outside of MAX_ADDR!")
-        }
-    }
+    check_writable(addr);
 
     format!("st, r{register}, {addr}")
 }
@@ -104,37 +180,50 @@ pub fn read_from_dm(register: u8, addr: u16) -> String {
     format!("ld, r{register}, {addr}")
 }
 
-/// Generates ldi instruction
-pub fn load_const(register: u8, const_val: i16) -> String {
-    format!("ldi, r{register}, {const_val}")
+/// Returns the operand addressing a variable, allocating it first if needed. Variables moved with
+/// move_to() have to stay where they were put, so they are addressed like any other address.
+pub fn var_operand(var_id: u32) -> String {
+    let addr: u16 = get_var_addr(var_id);
+    if is_compiler_addr(addr) {
+        format!("{VAR_PREFIX}{addr}")
+    } else {
+        addr.to_string()
+    }
 }
 
-/// Returns current memory address of stack pointer
-pub fn get_stack_ptr() -> u16 {
-    unsafe { STACK_PTR }
+/// Store data from register to a variable
+pub fn write_to_var(register: u8, var_id: u32) -> String {
+    format!("st, r{register}, {}", var_operand(var_id))
 }
 
-/// Decrements the stack ptr to symbolize stack being popped.
-pub fn decrement_stack_ptr() {
-    unsafe {
-        STACK_PTR -= 1;
-    }
+/// Load data from a variable to register
+pub fn read_from_var(register: u8, var_id: u32) -> String {
+    format!("ld, r{register}, {}", var_operand(var_id))
+}
+
+/// Reads the address of an operand, whether it addresses a variable or not.
+pub fn parse_addr(operand: &str) -> Option<u16> {
+    operand
+        .strip_prefix(VAR_PREFIX)
+        .unwrap_or(operand)
+        .parse()
+        .ok()
+}
+
+/// Generates ldi instruction
+pub fn load_const(register: u8, const_val: i16) -> String {
+    format!("ldi, r{register}, {const_val}")
 }
 
 /// Push new variable to memory map
 pub fn push_to_mem_map(var_id: u32, address: u16) {
-    unsafe {
-        if address >= MAX_ADDR {
-            panic!("Trying to allocate outside of MAX_ADDR!")
-        }
-    }
+    check_writable(address);
 
     MEMORY_MAP
         .lock()
         .expect("Failed to lock on MEMORY_MAP")
         .push(MemoryItem {
             var_id,
-            reg: None,
             addr: address,
         })
 }
@@ -168,98 +257,297 @@ pub fn remove_from_mem_map(var_id: u32) {
     }
 }
 
+/// Marks an address as being read by something outside of the program.
+pub fn pin_addr(addr: u16) {
+    PINNED_ADDRS
+        .lock()
+        .expect("Failed to lock on PINNED_ADDRS")
+        .push(addr);
+}
+
+/// Returns every address allocated for a variable, except for the ones moved with move_to().
+pub fn allocated_vars() -> Vec<MemoryItem> {
+    ALLOCATED
+        .lock()
+        .expect("Failed to lock on ALLOCATED")
+        .iter()
+        .filter(|item| is_compiler_addr(item.addr))
+        .cloned()
+        .collect()
+}
+
+/// Returns the addresses variables have been moved to with move_to().
+pub fn pinned_addrs() -> Vec<u16> {
+    PINNED_ADDRS
+        .lock()
+        .expect("Failed to lock on PINNED_ADDRS")
+        .clone()
+}
+
+/// Returns the variable currently placed at an address, if any.
+pub fn var_at(addr: u16) -> Option<u32> {
+    MEMORY_MAP
+        .lock()
+        .expect("Failed to lock on MEMORY_MAP")
+        .iter()
+        .find(|item| item.addr == addr)
+        .map(|item| item.var_id)
+}
+
+/// Returns whether an address is part of the scratch space.
+pub fn is_scratch_addr(addr: u16) -> bool {
+    (0..SCRATCH_SIZE).any(|index| scratch_addr(index) == addr)
+}
+
+/// Returns the reserved range an address is in, if any.
+pub fn reserved_at(addr: u16) -> Option<Reservation> {
+    RESERVED
+        .lock()
+        .expect("Failed to lock on RESERVED")
+        .iter()
+        .find(|reservation| reservation.start <= addr && addr <= reservation.end)
+        .cloned()
+}
+
+/// Returns every reserved range.
+pub fn reserved_ranges() -> Vec<(u16, u16)> {
+    RESERVED
+        .lock()
+        .expect("Failed to lock on RESERVED")
+        .iter()
+        .map(|reservation| (reservation.start, reservation.end))
+        .collect()
+}
+
+/// Returns whether an address is only ever touched by the compiled program. Anything else might be
+/// read or written behind the back of the compiler, eg. by inline assembly or the hardware.
+pub fn is_compiler_addr(addr: u16) -> bool {
+    if region_of(addr).is_none_or(|region| region.kind != RegionKind::Globals) {
+        return false;
+    }
+    if reserved_at(addr).is_some() {
+        return false;
+    }
+    !PINNED_ADDRS
+        .lock()
+        .expect("Failed to lock on PINNED_ADDRS")
+        .contains(&addr)
+}
+
 /// Pre-allocate memory space that is not allowed to be touched by the compiler
 pub fn remove_mem_from_compiler(start: Option<u16>, end: Option<u16>) {
     if let Some(start_addr) = start {
-        unsafe {
-            let mut end_addr = MAX_ADDR;
+        let regions: Vec<MemRegion> = REGIONS.lock().expect("Failed to lock on REGIONS").clone();
+        let end_addr: u16 = end.unwrap_or(scratch_start(&regions) - 1);
 
-            if let Some(addr) = end {
-                end_addr = addr;
-            }
+        if start_addr > end_addr {
+            panic!("Invalid memory range set with PREALLOC macro!")
+        }
+        reserve_mem("PREALLOC", start_addr, end_addr);
+    }
+}
 
-            if start_addr > end_addr {
-                panic!("Invalid memory range set with PREALLOC macro!")
+/// Reserves a named range of memory that is not allowed to be touched by the compiler.
+pub fn reserve_mem(name: &str, start: u16, end: u16) {
+    if start > end {
+        panic!("Invalid range {start}..{end} reserved as {name}!");
+    }
+    for addr in start..=end {
+        match region_of(addr) {
+            Some(region) if region.kind == RegionKind::Stack => {
+                panic!("Reserved range {name} ({start}..{end}) overlaps the call stack!")
             }
+            Some(_) if is_scratch_addr(addr) => panic!(
+                "Reserved range {name} ({start}..{end}) overlaps the scratch space of the compiler!"
+            ),
+            Some(_) => {}
+            None => {
+                panic!("Reserved range {name} ({start}..{end}) is outside of every memory region!")
+            }
+        }
+    }
 
-            PREALLOC_START = start_addr;
-            PREALLOC_END = end_addr;
+    let reserved: Vec<Reservation> = RESERVED.lock().expect("Failed to lock on RESERVED").clone();
+    for other in reserved.iter() {
+        if other.name == name {
+            panic!("{name} is reserved more than once!");
+        }
+        if other.start <= end && start <= other.end {
+            panic!(
+                "Reserved range {name} ({start}..{end}) overlaps {} ({}..{})!",
+                other.name, other.start, other.end
+            );
         }
     }
+    RESERVED
+        .lock()
+        .expect("Failed to lock on RESERVED")
+        .push(Reservation {
+            name: name.to_string(),
+            start,
+            end,
+        });
 }
 
-/// Registers a usage of a register in the REG_MAP
-pub fn use_reg(item: &MemoryItem) {
-    let mut reg_map = REG_MAP.lock().expect("Failed to lock on REG_MAP!");
-
-    // Make sure compiler uses a register when adding it to reg_map
-    if item.reg.is_none() {
-        panic!("Trying to use new item item in REG_MAP without a set reg field!")
-    }
+/// Returns a summary of how every region of memory is used, once the program has been generated.
+pub fn memory_summary() -> Vec<String> {
+    let regions: Vec<MemRegion> = REGIONS.lock().expect("Failed to lock on REGIONS").clone();
+    let reserved: Vec<Reservation> = RESERVED.lock().expect("Failed to lock on RESERVED").clone();
+    let pinned: Vec<u16> = pinned_addrs();
+    let used: Vec<u16> = ALLOCATED
+        .lock()
+        .expect("Failed to lock on ALLOCATED")
+        .iter()
+        .map(|item| item.addr)
+        .chain(pinned.iter().copied())
+        .collect();
+
+    let mut summary: Vec<String> = vec![String::from("Memory:")];
+    for region in regions.iter() {
+        let name: String = format!("{} ({}..{})", region.name, region.start, region.end);
+        if region.kind == RegionKind::Stack {
+            summary.push(format!(
+                "  {name:<24} call stack, {} addresses",
+                region.size()
+            ));
+            continue;
+        }
 
-    // Check if item exists in map already
-    let mut map_index: usize = usize::MAX;
-    for (index, iter_item) in reg_map.iter().enumerate() {
-        if item == iter_item {
-            map_index = index;
+        let (mut used_count, mut reserved_count, mut scratch_count) = (0, 0, 0);
+        for addr in region.start..=region.end {
+            if is_scratch_addr(addr) {
+                scratch_count += 1;
+            } else if reserved_at(addr).is_some() {
+                reserved_count += 1;
+            } else if used.contains(&addr) {
+                used_count += 1;
+            }
+        }
+        let free: usize = region.size() as usize - used_count - reserved_count - scratch_count;
+        let mut line: String =
+            format!("  {name:<24} {used_count} used, {reserved_count} reserved, {free} free");
+        if scratch_count > 0 {
+            line.push_str(&format!(", {scratch_count} scratch"));
         }
+        summary.push(line);
     }
-    if map_index != usize::MAX {
-        reg_map.remove(map_index);
+
+    for reservation in reserved.iter() {
+        let moved: usize = pinned
+            .iter()
+            .filter(|addr| reservation.start <= **addr && **addr <= reservation.end)
+            .count();
+        summary.push(format!(
+            "  reserved {:<15} {}..{}, {} addresses, {moved} written with move_to()",
+            reservation.name,
+            reservation.start,
+            reservation.end,
+            reservation.end - reservation.start + 1
+        ));
     }
 
-    reg_map.push_back(item.clone());
+    summary
 }
 
-/// Gets the optimal register to use.
-pub fn get_reg(var_id: Option<u32>) -> u8 {
-    let mut reg_map = REG_MAP.lock().expect("Failed to lock on REG_MAP!");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Vec<MemRegion> {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: String::from(name),
+            kind,
+            start,
+            end,
+        };
+        vec![
+            region("low", RegionKind::Globals, 0, 9),
+            region("screen", RegionKind::Vram, 10, 19),
+            region("high", RegionKind::Globals, 20, 29),
+            region("stack", RegionKind::Stack, 30, 49),
+        ]
+    }
 
-    // Return the currently used register of the variable, if one exists
-    if let Some(var) = var_id {
-        for item in reg_map.iter() {
-            if item.var_id == var {
-                return item.reg.unwrap();
-            }
-        }
+    #[test]
+    fn scratch_space_ends_the_last_globals_region() {
+        assert_eq!(scratch_start(&layout()), 28);
     }
 
-    // Else pop the least recently used item and return it's register
-    unsafe {
-        if reg_map.len() == MAX_REGS as usize {
-            return reg_map
-                .pop_front()
-                .expect("Failed to perform pop_front() on REG_MAP!")
-                .reg
-                .unwrap(); // use_reg() requires that a register is set on each item added to REG_MAP
-        }
+    #[test]
+    fn variables_only_go_in_globals_regions() {
+        let regions: Vec<MemRegion> = layout();
+        let var_addrs: Vec<u16> = (0..50)
+            .filter(|addr| is_var_addr(&regions, *addr))
+            .collect();
+        assert_eq!(
+            var_addrs,
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 20, 21, 22, 23, 24, 25, 26, 27]
+        );
     }
-    reg_map.len() as u8 // Return the next available position, which should be the current
-                        // length of the reg_map
-}
 
-/// Returns the variable stored at the specified address
-pub fn get_var_id_from_addr(addr: u16) -> Option<u32> {
-    let mem_map = MEMORY_MAP.lock().expect("Failed to lock MEMORY_MAP!");
+    #[test]
+    #[should_panic(expected = "Globals region high is too small")]
+    fn globals_need_room_for_the_scratch_space() {
+        let mut regions: Vec<MemRegion> = layout();
+        regions[2].end = 21;
+        scratch_start(&regions);
+    }
 
-    for item in mem_map.iter() {
-        if item.addr == addr {
-            return Some(item.var_id);
-        }
+    /// Sets the same layout as the optimization tests, since they share the memory manager.
+    fn set_test_layout() {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: String::from(name),
+            kind,
+            start,
+            end,
+        };
+        set_memory_layout(vec![
+            region("globals", RegionKind::Globals, 0, 199),
+            region("io", RegionKind::Mmio, 200, 255),
+        ]);
     }
 
-    None
-}
+    #[test]
+    #[should_panic(expected = "Reserved range pad (241..243) overlaps keys (240..241)!")]
+    fn reserved_ranges_may_not_overlap() {
+        set_test_layout();
+        reserve_mem("keys", 240, 241);
+        reserve_mem("pad", 241, 243);
+    }
 
-/// Returns the register if a variable is already loaded, else None
-pub fn already_in_reg(var_id: u32) -> Option<u8> {
-    let reg_map = REG_MAP.lock().expect("Failed to lock REG_MAP!");
+    #[test]
+    #[should_panic(expected = "sound is reserved more than once!")]
+    fn names_are_only_reserved_once() {
+        set_test_layout();
+        reserve_mem("sound", 230, 230);
+        reserve_mem("sound", 231, 231);
+    }
 
-    for item in reg_map.iter() {
-        if item.var_id == var_id {
-            return item.reg;
-        }
+    #[test]
+    #[should_panic(expected = "overlaps the scratch space of the compiler!")]
+    fn scratch_space_can_not_be_reserved() {
+        set_test_layout();
+        reserve_mem("tmp", 197, 198);
     }
 
-    None
+    #[test]
+    #[should_panic(expected = "Reserved range far (250..300) is outside of every memory region!")]
+    fn reserved_ranges_have_to_be_in_a_region() {
+        set_test_layout();
+        reserve_mem("far", 250, 300);
+    }
+
+    #[test]
+    fn summary_lists_regions_and_reservations() {
+        set_test_layout();
+        reserve_mem("screen", 210, 219);
+        let summary: Vec<String> = memory_summary();
+        assert_eq!(summary[0], "Memory:");
+        assert!(summary
+            .iter()
+            .any(|line| line.starts_with("  io (200..255) ")));
+        assert!(summary.contains(&String::from(
+            "  reserved screen          210..219, 10 addresses, 0 written with move_to()"
+        )));
+    }
 }
@@ -1,5 +1,7 @@
 pub mod arithmetic;
 pub mod instruction_parser;
+pub mod mem_report;
 pub mod memory_manager;
+pub mod pointers;
 pub mod program_generator;
 pub mod registers;
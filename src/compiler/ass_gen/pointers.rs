@@ -0,0 +1,305 @@
+/*
+* Lowering of pointers only known at runtime.
+*
+* ld and st only take an address written in the instruction itself, so the CPU can't follow a
+* pointer held in a register or variable. Instead, such reads and writes call a routine that
+* searches for the address with a tree of comparisons, ending in an ld or st of that constant
+* address. The routines are generated from the memory layout and only added when they are used.
+*/
+
+use super::memory_manager::{scratch_start, SCRATCH_SIZE};
+use crate::compiler::ir::{
+    builder::random_branch_name, BasicBlock, BlockId, BranchCond, CmpOp, InlineHint, Inst,
+    IrFunction, IrProgram, Operand, Terminator, VReg, VarId,
+};
+use crate::compiler::parser::variable_hasher;
+use crate::utils::hardware_conf::{MemRegion, RegionKind};
+
+/// Routine reading the word a pointer points to.
+pub const LOAD_ROUTINE: &str = "mem.load";
+/// Routine writing a value to the word a pointer points to.
+pub const STORE_ROUTINE: &str = "mem.store";
+
+/// Replaces the loads and stores through pointers only known at runtime with calls to the pointer
+/// routines, which are added to the program if needed.
+pub fn lower_pointers(program: &mut IrProgram, regions: &[MemRegion]) {
+    let addr: VarId = variable_hasher("addr", "pointers");
+    let value: VarId = variable_hasher("value", "pointers");
+    let mut loads: bool = false;
+    let mut stores: bool = false;
+
+    for func in program.functions.iter_mut() {
+        for index in 0..func.blocks.len() {
+            let insts: Vec<Inst> = std::mem::take(&mut func.blocks[index].insts);
+            let mut lowered: Vec<Inst> = Vec::with_capacity(insts.len());
+
+            for inst in insts {
+                match inst {
+                    Inst::LoadPtr { dst, addr: ptr } if !matches!(ptr, Operand::Const(_)) => {
+                        let src: VReg = pointer_reg(ptr, func, &mut lowered);
+                        lowered.push(Inst::Store { var: addr, src });
+                        lowered.push(Inst::Call {
+                            name: LOAD_ROUTINE.to_string(),
+                            params: vec![addr],
+                            dst: Some(dst),
+                        });
+                        loads = true;
+                    }
+                    Inst::StorePtr { addr: ptr, src } if !matches!(ptr, Operand::Const(_)) => {
+                        let ptr: VReg = pointer_reg(ptr, func, &mut lowered);
+                        lowered.push(Inst::Store {
+                            var: addr,
+                            src: ptr,
+                        });
+                        lowered.push(Inst::Store { var: value, src });
+                        lowered.push(Inst::Call {
+                            name: STORE_ROUTINE.to_string(),
+                            params: vec![addr, value],
+                            dst: None,
+                        });
+                        stores = true;
+                    }
+                    inst => lowered.push(inst),
+                }
+            }
+
+            func.blocks[index].insts = lowered;
+        }
+    }
+
+    if loads {
+        program
+            .functions
+            .push(pointer_routine(LOAD_ROUTINE, addr, None, regions));
+    }
+    if stores {
+        program
+            .functions
+            .push(pointer_routine(STORE_ROUTINE, addr, Some(value), regions));
+    }
+}
+
+/// Returns the register holding a pointer, loading it from its variable if needed.
+fn pointer_reg(ptr: Operand, func: &mut IrFunction, insts: &mut Vec<Inst>) -> VReg {
+    match ptr {
+        Operand::Reg(reg) => reg,
+        Operand::Var(var) => {
+            let dst = VReg(func.next_vreg);
+            func.next_vreg += 1;
+            insts.push(Inst::Load { dst, var });
+            dst
+        }
+        Operand::Const(_) => panic!("Compiler error! Constant addresses don't need a pointer."),
+    }
+}
+
+/// Addresses a pointer can reach. The call stack and the scratch space belong to the hardware and
+/// the compiler. They are sorted as signed numbers, since that is how cmp compares them.
+fn pointer_targets(regions: &[MemRegion]) -> Vec<u16> {
+    let scratch: u16 = scratch_start(regions);
+    let mut targets: Vec<u16> = regions
+        .iter()
+        .filter(|region| region.kind != RegionKind::Stack)
+        .flat_map(|region| region.start..=region.end)
+        .filter(|addr| !(scratch..scratch + SCRATCH_SIZE).contains(addr))
+        .collect();
+    targets.sort_by_key(|addr| *addr as i16);
+    targets
+}
+
+/// Generates a pointer routine. The pointer is read from addr and compared until a single address
+/// is left, which is then read, or written with the value when there is one. Pointers outside of
+/// every region end up at the closest address that is in one.
+fn pointer_routine(
+    name: &str,
+    addr: VarId,
+    value: Option<VarId>,
+    regions: &[MemRegion],
+) -> IrFunction {
+    let targets: Vec<u16> = pointer_targets(regions);
+    if targets.is_empty() {
+        panic!("Pointers are used, but there is no memory for them to point to!");
+    }
+
+    let mut func = IrFunction {
+        name: name.to_string(),
+        params: [Some(addr), value].into_iter().flatten().collect(),
+        inline: InlineHint::Never,
+        blocks: Vec::new(),
+        entry: BlockId(0),
+        next_vreg: 2,
+    };
+    let ptr = VReg(0);
+    let src = VReg(1);
+
+    let entry: BlockId = new_block(&mut func);
+    func.blocks[entry.0].insts.push(Inst::Load {
+        dst: ptr,
+        var: addr,
+    });
+    if let Some(value) = value {
+        func.blocks[entry.0].insts.push(Inst::Load {
+            dst: src,
+            var: value,
+        });
+    }
+    search(&mut func, entry, &targets, ptr, value.map(|_| src));
+    func
+}
+
+/// Fills block with the search among targets. The upper half is laid out right after the block,
+/// so the branch falls through to it.
+fn search(func: &mut IrFunction, block: BlockId, targets: &[u16], ptr: VReg, src: Option<VReg>) {
+    if let [target] = targets {
+        let target = Operand::Const(*target as i16);
+        let (inst, value) = match src {
+            Some(src) => (Inst::StorePtr { addr: target, src }, None),
+            None => {
+                let dst = VReg(func.next_vreg);
+                func.next_vreg += 1;
+                (Inst::LoadPtr { dst, addr: target }, Some(Operand::Reg(dst)))
+            }
+        };
+        func.blocks[block.0].insts.push(inst);
+        func.blocks[block.0].term = Terminator::Return(value);
+        return;
+    }
+
+    let (lower, upper) = targets.split_at(targets.len() / 2);
+    let upper_block: BlockId = new_block(func);
+    search(func, upper_block, upper, ptr, src);
+    let lower_block: BlockId = new_block(func);
+    search(func, lower_block, lower, ptr, src);
+
+    func.blocks[block.0].term = Terminator::Branch {
+        cond: BranchCond::Cmp {
+            op: CmpOp::LessThan,
+            lhs: ptr,
+            rhs: Operand::Const(upper[0] as i16),
+        },
+        then_block: lower_block,
+        else_block: upper_block,
+    };
+}
+
+/// Adds an empty block to the end of the function.
+fn new_block(func: &mut IrFunction) -> BlockId {
+    let id = BlockId(func.blocks.len());
+    func.blocks.push(BasicBlock {
+        id,
+        label: random_branch_name(),
+        insts: Vec::new(),
+        term: Terminator::Return(None),
+    });
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(kind: RegionKind, start: u16, end: u16) -> MemRegion {
+        MemRegion {
+            name: format!("{kind:?}"),
+            kind,
+            start,
+            end,
+        }
+    }
+
+    fn program(insts: Vec<Inst>) -> IrProgram {
+        IrProgram {
+            functions: vec![IrFunction::from_blocks(
+                "main",
+                vec![(insts, Terminator::Return(None))],
+            )],
+            entry_point: Some(0),
+            exports: Vec::new(),
+            externs: Vec::new(),
+            prealloc_start: None,
+            prealloc_end: None,
+            reserved: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn runtime_pointers_call_the_routines() {
+        let regions = vec![
+            region(RegionKind::Globals, 0, 9),
+            region(RegionKind::Stack, 10, 19),
+        ];
+        let mut program = program(vec![
+            Inst::LoadPtr {
+                dst: VReg(0),
+                addr: Operand::Var(1),
+            },
+            Inst::StorePtr {
+                addr: Operand::Reg(VReg(0)),
+                src: VReg(0),
+            },
+            Inst::StorePtr {
+                addr: Operand::Const(3),
+                src: VReg(0),
+            },
+        ]);
+        lower_pointers(&mut program, &regions);
+
+        let insts: &[Inst] = &program.functions[0].blocks[0].insts;
+        assert!(matches!(
+            insts[2],
+            Inst::Call { ref name, dst: Some(VReg(0)), .. } if name == LOAD_ROUTINE
+        ));
+        assert!(matches!(
+            insts[5],
+            Inst::Call { ref name, dst: None, .. } if name == STORE_ROUTINE
+        ));
+        assert!(matches!(
+            insts[6],
+            Inst::StorePtr {
+                addr: Operand::Const(3),
+                ..
+            }
+        ));
+        let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main", LOAD_ROUTINE, STORE_ROUTINE]);
+    }
+
+    #[test]
+    fn routines_reach_every_address_outside_of_the_stack() {
+        let regions = vec![
+            region(RegionKind::Globals, 0, 9),
+            region(RegionKind::Stack, 10, 19),
+            region(RegionKind::Mmio, 20, 22),
+        ];
+        let routine = pointer_routine(STORE_ROUTINE, 1, Some(2), &regions);
+        let mut targets: Vec<i16> = routine
+            .blocks
+            .iter()
+            .flat_map(|block| block.insts.iter())
+            .filter_map(|inst| match inst {
+                Inst::StorePtr {
+                    addr: Operand::Const(addr),
+                    ..
+                } => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        targets.sort();
+
+        // 8 and 9 are the scratch space at the end of the globals
+        assert_eq!(targets, [0, 1, 2, 3, 4, 5, 6, 7, 20, 21, 22]);
+        assert!(routine.blocks.iter().all(|block| block.insts.len() <= 2));
+    }
+
+    #[test]
+    fn addresses_are_searched_in_signed_order() {
+        let regions = vec![
+            region(RegionKind::Globals, 0, 99),
+            region(RegionKind::Vram, 0x8000, 0x8003),
+        ];
+        assert_eq!(
+            &pointer_targets(&regions)[..5],
+            [0x8000, 0x8001, 0x8002, 0x8003, 0]
+        );
+    }
+}
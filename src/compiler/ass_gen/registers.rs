@@ -0,0 +1,449 @@
+/*
+* Maps the virtual registers used by the IR onto the registers available in hardware.
+*
+* This is a graph colouring allocator. Two virtual registers interfere if one of them is written
+* while the other one is still live, in which case they can't share a hardware register. The
+* interference graph is then coloured with as many colours as there are registers in the hardware
+* config.
+*
+* When the graph can't be coloured, some virtual registers are spilled to DM. Every write to a
+* spilled register is followed by a store to its spill slot, and every read is preceded by a load
+* from it (or reads the slot directly when the ALU allows it). The allocation is then retried on
+* the rewritten function until it succeeds.
+*
+* Called functions are free to use every register, so anything live across a call is spilled
+* before colouring even starts.
+*/
+
+use super::memory_manager::get_max_regs;
+use crate::compiler::ir::liveness::vreg_liveness;
+use crate::compiler::ir::{Inst, IrFunction, Operand, Terminator, VReg, VarId};
+use crate::compiler::parser::variable_hasher;
+use std::collections::{HashMap, HashSet};
+
+/// Undirected graph of virtual registers that can't share a hardware register.
+struct InterferenceGraph {
+    nodes: HashSet<VReg>,
+    edges: HashMap<VReg, HashSet<VReg>>,
+    // Registers that would like to share hardware register, to avoid copying between them
+    hints: HashMap<VReg, VReg>,
+    // Registers that would like a specific hardware register, like return values in r0
+    fixed_hints: HashMap<VReg, u8>,
+}
+
+/// Assigns a hardware register to every virtual register used in a function. Inserts spill code
+/// into the function if there are not enough registers.
+pub fn allocate_registers(func: &mut IrFunction) -> HashMap<VReg, u8> {
+    let max_regs: u8 = get_max_regs();
+
+    // Registers created by spill code live for a single instruction. Spilling them would not help.
+    let mut unspillable: HashSet<VReg> = HashSet::new();
+
+    let mut across_calls: Vec<VReg> = live_across_calls(func).into_iter().collect();
+    across_calls.sort(); // Keeps the output the same between runs
+    for reg in across_calls {
+        unspillable.extend(spill_reg(func, reg));
+    }
+
+    loop {
+        let graph = build_interference_graph(func);
+
+        match color_graph(&graph, max_regs, &unspillable) {
+            Ok(colors) => return colors,
+            Err(spilled) => {
+                for reg in spilled {
+                    unspillable.extend(spill_reg(func, reg));
+                }
+            }
+        }
+    }
+}
+
+/// Finds the registers holding a value that is still needed after a function call.
+fn live_across_calls(func: &IrFunction) -> HashSet<VReg> {
+    let liveness = vreg_liveness(func);
+    let mut across_calls: HashSet<VReg> = HashSet::new();
+
+    for block in func.blocks.iter() {
+        let mut live: HashSet<VReg> = liveness.live_out[&block.id].clone();
+        live.extend(block.term.uses());
+
+        for inst in block.insts.iter().rev() {
+            if let Some(def) = inst.def() {
+                live.remove(&def);
+            }
+            if let Inst::Call { .. } = inst {
+                across_calls.extend(live.iter().copied());
+            }
+            live.extend(inst.uses());
+        }
+    }
+
+    across_calls
+}
+
+/// Builds the interference graph by walking every block backwards from the registers live at
+/// the end of it.
+fn build_interference_graph(func: &IrFunction) -> InterferenceGraph {
+    let liveness = vreg_liveness(func);
+    let mut graph = InterferenceGraph {
+        nodes: HashSet::new(),
+        edges: HashMap::new(),
+        hints: HashMap::new(),
+        fixed_hints: HashMap::new(),
+    };
+
+    for block in func.blocks.iter() {
+        let mut live: HashSet<VReg> = liveness.live_out[&block.id].clone();
+        for reg in block.term.uses() {
+            graph.nodes.insert(reg);
+            live.insert(reg);
+        }
+
+        // Values are returned in r0
+        if let Terminator::Return(Some(Operand::Reg(reg))) = block.term {
+            graph.fixed_hints.insert(reg, 0);
+        }
+
+        for inst in block.insts.iter().rev() {
+            if let Some(def) = inst.def() {
+                graph.nodes.insert(def);
+                for other in live.iter() {
+                    if *other != def {
+                        graph.add_edge(def, *other);
+                    }
+                }
+                live.remove(&def);
+
+                match inst {
+                    // Binary operations work in place on the left operand
+                    Inst::BinOp { lhs, .. } => {
+                        graph.hints.insert(def, *lhs);
+                    }
+                    // Called functions return their value in r0
+                    Inst::Call { .. } => {
+                        graph.fixed_hints.insert(def, 0);
+                    }
+                    _ => {}
+                }
+            }
+            for reg in inst.uses() {
+                graph.nodes.insert(reg);
+                live.insert(reg);
+            }
+        }
+    }
+
+    graph
+}
+
+impl InterferenceGraph {
+    fn add_edge(&mut self, a: VReg, b: VReg) {
+        self.edges.entry(a).or_default().insert(b);
+        self.edges.entry(b).or_default().insert(a);
+    }
+
+    fn neighbours(&self, reg: VReg) -> impl Iterator<Item = &VReg> {
+        self.edges.get(&reg).into_iter().flatten()
+    }
+}
+
+/// Colours the graph with max_regs colours. Nodes with fewer neighbours than there are colours are
+/// removed first, since they can always be coloured. When there are none left, the node with the
+/// most neighbours is removed optimistically, hoping that some of its neighbours share a colour.
+/// Returns the registers that had to be spilled if no colouring was found.
+fn color_graph(
+    graph: &InterferenceGraph,
+    max_regs: u8,
+    unspillable: &HashSet<VReg>,
+) -> Result<HashMap<VReg, u8>, Vec<VReg>> {
+    let mut degree: HashMap<VReg, usize> = graph
+        .nodes
+        .iter()
+        .map(|reg| (*reg, graph.neighbours(*reg).count()))
+        .collect();
+    let mut remaining: Vec<VReg> = graph.nodes.iter().copied().collect();
+    remaining.sort(); // Keeps the output the same between runs
+    let mut stack: Vec<VReg> = Vec::new();
+
+    while !remaining.is_empty() {
+        let index: usize = remaining
+            .iter()
+            .position(|reg| degree[reg] < max_regs as usize)
+            .unwrap_or_else(|| {
+                // Spill candidate. Prefer the most constrained register that spilling helps.
+                (0..remaining.len())
+                    .max_by_key(|i| {
+                        (
+                            !unspillable.contains(&remaining[*i]),
+                            degree[&remaining[*i]],
+                        )
+                    })
+                    .unwrap()
+            });
+
+        let reg: VReg = remaining.remove(index);
+        for other in graph.neighbours(reg) {
+            if let Some(deg) = degree.get_mut(other) {
+                *deg = deg.saturating_sub(1);
+            }
+        }
+        stack.push(reg);
+    }
+
+    let mut colors: HashMap<VReg, u8> = HashMap::new();
+    let mut spilled: Vec<VReg> = Vec::new();
+
+    while let Some(reg) = stack.pop() {
+        let taken: HashSet<u8> = graph
+            .neighbours(reg)
+            .filter_map(|other| colors.get(other))
+            .copied()
+            .collect();
+
+        let hinted: Option<u8> = graph
+            .hints
+            .get(&reg)
+            .and_then(|hint| colors.get(hint))
+            .or_else(|| graph.fixed_hints.get(&reg))
+            .filter(|color| !taken.contains(color))
+            .copied();
+
+        match hinted.or_else(|| (0..max_regs).find(|color| !taken.contains(color))) {
+            Some(color) => {
+                colors.insert(reg, color);
+            }
+            None => spilled.push(reg),
+        }
+    }
+
+    if spilled.is_empty() {
+        return Ok(colors);
+    }
+
+    if let Some(reg) = spilled.iter().find(|reg| unspillable.contains(reg)) {
+        panic!(
+            "Not enough registers to compile! {reg} can't be placed in any of the {max_regs} registers."
+        );
+    }
+    Err(spilled)
+}
+
+/// Moves a virtual register to DM. Returns the new short lived registers used to load and store
+/// the spilled value.
+fn spill_reg(func: &mut IrFunction, spilled: VReg) -> Vec<VReg> {
+    let slot: VarId = variable_hasher(&spilled.to_string(), &format!("spill{}", func.name));
+    let mut new_regs: Vec<VReg> = Vec::new();
+    let mut next_vreg: u32 = func.next_vreg;
+    let mut new_reg = || {
+        let reg = VReg(next_vreg);
+        next_vreg += 1;
+        reg
+    };
+
+    for block in func.blocks.iter_mut() {
+        let mut insts: Vec<Inst> = Vec::new();
+
+        for mut inst in block.insts.drain(..) {
+            if inst.uses().contains(&spilled) {
+                match &mut inst {
+                    // The ALU can read the second operand straight from DM
+                    Inst::BinOp { lhs, rhs, .. }
+                        if *rhs == Operand::Reg(spilled) && *lhs != spilled =>
+                    {
+                        *rhs = Operand::Var(slot);
+                    }
+                    // Pointers are read from DM by indirect loads and stores anyway
+                    Inst::LoadPtr { addr, .. } if *addr == Operand::Reg(spilled) => {
+                        *addr = Operand::Var(slot);
+                    }
+                    Inst::StorePtr { addr, src }
+                        if *addr == Operand::Reg(spilled) && *src != spilled =>
+                    {
+                        *addr = Operand::Var(slot);
+                    }
+                    _ => {
+                        let reload = new_reg();
+                        insts.push(Inst::Load {
+                            dst: reload,
+                            var: slot,
+                        });
+                        inst.replace_use(spilled, reload);
+                        new_regs.push(reload);
+                    }
+                }
+            }
+
+            if inst.def() == Some(spilled) {
+                let result = new_reg();
+                inst.replace_def(result);
+                insts.push(inst);
+                insts.push(Inst::Store {
+                    var: slot,
+                    src: result,
+                });
+                new_regs.push(result);
+            } else {
+                insts.push(inst);
+            }
+        }
+
+        if block.term.uses().contains(&spilled) {
+            match &mut block.term {
+                Terminator::Return(Some(val)) => *val = Operand::Var(slot),
+                term => {
+                    let reload = new_reg();
+                    insts.push(Inst::Load {
+                        dst: reload,
+                        var: slot,
+                    });
+                    term.replace_use(spilled, reload);
+                    new_regs.push(reload);
+                }
+            }
+        }
+
+        block.insts = insts;
+    }
+
+    func.next_vreg = next_vreg;
+    new_regs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ass_gen::memory_manager::set_max_regs;
+    use crate::compiler::ir::IrOp;
+
+    /// Asserts that no two interfering registers of the allocated function share a colour.
+    fn assert_valid_coloring(func: &IrFunction, colors: &HashMap<VReg, u8>, max_regs: u8) {
+        let graph = build_interference_graph(func);
+        for reg in graph.nodes.iter() {
+            let color: u8 = *colors
+                .get(reg)
+                .unwrap_or_else(|| panic!("{reg} has no register"));
+            assert!(color < max_regs, "{reg} got r{color} out of {max_regs}");
+            for other in graph.neighbours(*reg) {
+                assert_ne!(
+                    Some(&color),
+                    colors.get(other),
+                    "{reg} and {other} share r{color}"
+                );
+            }
+        }
+    }
+
+    fn add(dst: u32, lhs: u32, rhs: u32) -> Inst {
+        Inst::BinOp {
+            op: IrOp::Add,
+            dst: VReg(dst),
+            lhs: VReg(lhs),
+            rhs: Operand::Reg(VReg(rhs)),
+        }
+    }
+
+    #[test]
+    fn spills_when_more_values_are_live_than_registers() {
+        set_max_regs(3);
+
+        // Six constants are all live at once before being summed up
+        let mut insts: Vec<Inst> = (0..6)
+            .map(|i| Inst::LoadConst {
+                dst: VReg(i),
+                value: i as i16,
+            })
+            .collect();
+        insts.push(add(6, 0, 1));
+        insts.push(add(7, 6, 2));
+        insts.push(add(8, 7, 3));
+        insts.push(add(9, 8, 4));
+        insts.push(add(10, 9, 5));
+        let mut func = IrFunction::from_blocks(
+            "sum",
+            vec![(insts, Terminator::Return(Some(Operand::Reg(VReg(10)))))],
+        );
+
+        let colors = allocate_registers(&mut func);
+        assert_valid_coloring(&func, &colors, 3);
+        assert!(func.blocks[0]
+            .insts
+            .iter()
+            .any(|inst| matches!(inst, Inst::Store { .. })));
+    }
+
+    #[test]
+    fn reuses_registers_of_dead_values() {
+        set_max_regs(3);
+
+        // Every sum only lives until the next one is calculated, so two registers are enough
+        let mut insts: Vec<Inst> = vec![
+            Inst::LoadConst {
+                dst: VReg(0),
+                value: 1,
+            },
+            Inst::LoadConst {
+                dst: VReg(1),
+                value: 2,
+            },
+        ];
+        insts.push(add(2, 0, 1));
+        insts.push(add(3, 2, 2));
+        insts.push(add(4, 3, 3));
+        let mut func = IrFunction::from_blocks(
+            "double",
+            vec![(insts, Terminator::Return(Some(Operand::Reg(VReg(4)))))],
+        );
+
+        let colors = allocate_registers(&mut func);
+        assert_valid_coloring(&func, &colors, 3);
+        assert!(!func.blocks[0]
+            .insts
+            .iter()
+            .any(|inst| matches!(inst, Inst::Store { .. })));
+    }
+
+    #[test]
+    fn spills_values_live_across_calls() {
+        set_max_regs(3);
+
+        let mut func = IrFunction::from_blocks(
+            "caller",
+            vec![(
+                vec![
+                    Inst::LoadConst {
+                        dst: VReg(0),
+                        value: 5,
+                    },
+                    Inst::Call {
+                        name: "callee".to_string(),
+                        params: Vec::new(),
+                        dst: Some(VReg(1)),
+                    },
+                    add(2, 0, 1),
+                ],
+                Terminator::Return(Some(Operand::Reg(VReg(2)))),
+            )],
+        );
+
+        let colors = allocate_registers(&mut func);
+        assert_valid_coloring(&func, &colors, 3);
+        assert!(live_across_calls(&func).is_empty());
+
+        // v0 is stored to its slot before the call and read back from it after
+        let slot: VarId = variable_hasher("v0", "spillcaller");
+        let insts: &Vec<Inst> = &func.blocks[0].insts;
+        let call: usize = insts
+            .iter()
+            .position(|inst| matches!(inst, Inst::Call { .. }))
+            .unwrap();
+        assert!(insts[..call]
+            .iter()
+            .any(|inst| matches!(inst, Inst::Store { var, .. } if *var == slot)));
+        assert!(insts[call..].iter().any(|inst| match inst {
+            Inst::Load { var, .. } => *var == slot,
+            Inst::BinOp { rhs, .. } => *rhs == Operand::Var(slot),
+            _ => false,
+        }));
+    }
+}
@@ -20,17 +20,43 @@ pub enum ValueEnum {
     Void,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BinaryOperator {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl BinaryOperator {
+    /// The operator as written in NID.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::Add => "+",
+            BinaryOperator::Sub => "-",
+            BinaryOperator::Mul => "*",
+            BinaryOperator::Div => "/",
+            BinaryOperator::Mod => "%",
+            BinaryOperator::And => "&",
+            BinaryOperator::Or => "|",
+            BinaryOperator::Xor => "^",
+            BinaryOperator::Shl => "<<",
+            BinaryOperator::Shr => ">>",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ConditionalOperator {
     Not,
+    And, // Both sides are conditions
+    Or,  // -- || --
     NotEq,
     Eq,
     GreatThan,
@@ -43,6 +69,9 @@ pub enum ConditionalOperator {
 pub enum MacroType {
     PreAllocStart,
     PreAllocEnd,
+    Inline,                                         // Always inline the next function
+    NoInline,                                       // Never inline the next function
+    Reserve { name: String, start: u16, end: u16 }, // Named range the compiler may not touch
 }
 
 /// Enum for easier identification of Node type
@@ -54,45 +83,31 @@ pub enum AstType {
     Block,
     Branch,
     Condition,
+    Deref,
     Function,
+    FunctionCall,
     Loop,
+    LoopJump,
     Return,
     Type,
     Variable,
     Value,
     Macro,
-    Debug,
     Builtin,
 }
 
 #[derive(Debug)]
 pub struct Ast<T: Node + ?Sized> {
-    pub entry_point: usize, // Entry point index
+    pub entry_point: Option<usize>, // Entry point index, None for files without main()
     pub body: Vec<Box<T>>,
 }
 
 impl Ast<dyn Node> {
     /// Finds the entry point of a program (main())
     pub fn new(body: Vec<Box<dyn Node>>) -> Self {
-        let mut index: usize = 0;
-        loop {
-            if index >= body.len() {
-                panic!("main() not found!");
-            }
+        let entry_point: Option<usize> = body.iter().position(|node| node.get_name() == "main");
 
-            // TODO: Remove the allow()
-            #[allow(clippy::borrowed_box)]
-            let node: &Box<dyn Node> = body.get(index).unwrap();
-            if node.get_name() == "main" {
-                break; // Only break if it's the main decleration
-            }
-            index += 1;
-        }
-
-        Self {
-            body,
-            entry_point: index,
-        }
+        Self { body, entry_point }
     }
 }
 
@@ -166,8 +181,13 @@ pub struct Builtin {
 /// Condition, used by branches and loops
 pub struct Condition {
     pub operator: ConditionalOperator,
-    pub left: Option<Box<dyn Node>>, // Variable or value
-    pub right: Box<dyn Node>,        // Variable or value
+    pub left: Option<Box<dyn Node>>, // Variable or value, or a condition for && and ||
+    pub right: Box<dyn Node>,        // Variable or value, or a condition for &&, || and !
+}
+
+/// Read or write of the memory a pointer points to, eg. `*p` or `*(p + 1)`
+pub struct Deref {
+    pub address: Box<dyn Node>, // Expression giving the address
 }
 
 pub struct Function {
@@ -176,12 +196,39 @@ pub struct Function {
     pub body: Block,
 }
 
-/// Loops, currently ony while is supported
+/// Call to a function defined in NID code
+pub struct FunctionCall {
+    pub identifier: String,
+    pub args: Vec<Box<dyn Node>>, // Expressions passed as arguments
+}
+
+/// Loops. for loops are while loops with an init and a step, which are empty for the others.
 pub struct Loop {
+    pub kind: LoopKind,
+    pub init: Block, // Runs once before the loop
     pub condition: Box<Condition>,
+    pub step: Block, // Runs after every round, also when it is cut short by continue
     pub body: Block,
 }
 
+/// When the condition of a loop is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKind {
+    While,   // Before every round, for loops included
+    DoWhile, // After every round, so the body runs at least once
+}
+
+/// break or continue, which leave the innermost loop or skip to its next round.
+pub struct LoopJump {
+    pub kind: LoopJumpKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopJumpKind {
+    Break,
+    Continue,
+}
+
 /// Macros, used for special stuff like telling the compiler what memory it cannot touch
 pub struct Macro {
     pub macro_type: MacroType,
@@ -208,9 +255,6 @@ pub struct Value {
     pub value: ValueEnum,
 }
 
-/// Debug trait. TODO: Remove this
-pub struct DebugNode;
-
 /*
 * Impl the Node trait on all Nodes
 */
@@ -334,13 +378,7 @@ impl Node for BinaryExpression {
         tree.begin_child(self.display());
         self.left.traverse_leaves(tree);
 
-        let op: &str = match self.op {
-            BinaryOperator::Add => "+",
-            BinaryOperator::Sub => "-",
-            BinaryOperator::Mul => "*",
-            BinaryOperator::Div => "/",
-        };
-        tree.add_empty_child(op.to_string());
+        tree.add_empty_child(self.op.symbol().to_string());
         self.right.traverse_leaves(tree);
         tree.end_child();
     }
@@ -497,6 +535,37 @@ impl Function {
         })
     }
 }
+impl Node for Deref {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("*({})", self.address.display())
+    }
+
+    fn get_type(&self) -> AstType {
+        AstType::Deref
+    }
+
+    fn get_name(&self) -> String {
+        self.display()
+    }
+
+    fn has_leaves(&self) -> bool {
+        true
+    }
+
+    fn traverse_leaves(&self, tree: &mut ptree::TreeBuilder) {
+        tree.begin_child(String::from("Deref"));
+        self.address.traverse_leaves(tree);
+        tree.end_child();
+    }
+}
 impl Node for Function {
     fn as_any(&self) -> &dyn Any {
         self
@@ -531,6 +600,41 @@ impl Node for Function {
         tree.end_child();
     }
 }
+impl Node for FunctionCall {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("{}()", self.identifier)
+    }
+
+    fn get_type(&self) -> AstType {
+        AstType::FunctionCall
+    }
+
+    fn get_name(&self) -> String {
+        self.identifier.clone()
+    }
+
+    fn has_leaves(&self) -> bool {
+        true
+    }
+
+    fn traverse_leaves(&self, tree: &mut ptree::TreeBuilder) {
+        tree.begin_child(format!("Call {}", self.display()));
+
+        for arg in &self.args {
+            arg.traverse_leaves(tree);
+        }
+
+        tree.end_child();
+    }
+}
 impl Node for Loop {
     fn as_any(&self) -> &dyn Any {
         self
@@ -541,7 +645,7 @@ impl Node for Loop {
     }
 
     fn display(&self) -> String {
-        String::from("Loop")
+        format!("Loop {:?}", self.kind)
     }
 
     fn get_type(&self) -> AstType {
@@ -555,12 +659,39 @@ impl Node for Loop {
     fn traverse_leaves(&self, tree: &mut ptree::TreeBuilder) {
         tree.begin_child(self.display());
 
+        self.init.traverse_leaves(tree);
         self.condition.traverse_leaves(tree);
+        self.step.traverse_leaves(tree);
         self.body.traverse_leaves(tree);
 
         tree.end_child();
     }
 }
+impl Node for LoopJump {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.kind)
+    }
+
+    fn get_type(&self) -> AstType {
+        AstType::LoopJump
+    }
+
+    fn has_leaves(&self) -> bool {
+        false
+    }
+
+    fn traverse_leaves(&self, tree: &mut ptree::TreeBuilder) {
+        tree.add_empty_child(self.display());
+    }
+}
 impl Node for Macro {
     fn as_any(&self) -> &dyn Any {
         self
@@ -661,6 +792,9 @@ impl Node for Variable {
     }
 
     fn display(&self) -> String {
+        if let Some(var_type) = &self.var_type {
+            return format!("Variable: {} ({:?})", self.identifier, var_type);
+        }
         format!("Variable: {}", self.identifier)
     }
 
@@ -680,12 +814,10 @@ impl Value {
     pub fn value_as_i16(&self) -> i16 {
         match self.value {
             ValueEnum::Int(val) => val,
-            ValueEnum::Bool(val) => {
-                if val {
-                    return 1;
-                }
-                0
-            }
+            ValueEnum::Bool(val) => val as i16, // Stored as 1 for true and 0 for false
+            ValueEnum::Char(val) => u16::try_from(val as u32)
+                .unwrap_or_else(|_| panic!("Char {val} doesn't fit in 16 bits!"))
+                as i16,
             _ => panic!("Types other than 16-bit integer not currently supported!"),
         }
     }
@@ -715,32 +847,6 @@ impl Node for Value {
         tree.add_empty_child(self.display());
     }
 }
-impl Node for DebugNode {
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-
-    fn as_any_mut(&mut self) -> &mut dyn Any {
-        self
-    }
-
-    fn display(&self) -> String {
-        String::from("Debugging Node")
-    }
-
-    fn get_type(&self) -> AstType {
-        AstType::Debug
-    }
-
-    fn has_leaves(&self) -> bool {
-        true
-    }
-
-    fn traverse_leaves(&self, tree: &mut ptree::TreeBuilder) {
-        tree.add_empty_child("DEBUGGING NODE!".to_string());
-    }
-}
-
 /// Debugging function. Prints all nodes in AST to terminal.
 pub fn export_ast(ast: &Ast<dyn Node>) {
     println!("AST:");
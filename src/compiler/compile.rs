@@ -3,44 +3,120 @@
 * human-readable NID code into ASS code.
 */
 
-use std::{path::PathBuf, process::exit};
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+};
 
 use crate::{
     compiler::{
         ast::{Ast, Node},
-        lexer::{export_tokens, tokenize},
+        ir::{builder::generate_ir, IrProgram},
+        lexer::export_tokens,
+        modules::{load_program, module_namespace},
+        optimizations::{
+            inliner::DEFAULT_INLINE_THRESHOLD,
+            pass_manager::{OptLevel, PassConfig, PassManager},
+        },
         parser::generate_ast,
     },
     utils::{
-        command_line::Args,
+        command_line::{Args, Emit},
         hardware_conf::Hardware,
-        nid_fs::{read_file, write_to_file},
+        nid_fs::write_to_file,
     },
 };
 
-use super::{ass_gen::program_generator::generate_ass, ast::export_ast, lexer::remove_comments};
+use super::{
+    ass_gen::{
+        mem_report::check_memory, memory_manager::memory_summary, pointers::lower_pointers,
+        program_generator::generate_ass,
+    },
+    ast::export_ast,
+};
 
 /// The main compile function. Takes care of the overall logic of compilation while handing out the
-/// details to helper functions.
-pub fn compile(args: &Args, hardware_conf: &Hardware) -> PathBuf {
-    let output_name: PathBuf = PathBuf::from(args.filename.to_string().replace(".nid", ".ass"));
-    let source_code = read_file(&PathBuf::from(&args.filename));
-    let source_code_no_comments = remove_comments(&source_code);
-
-    // Generate Tokens from the source code.
-    let mut tokens = tokenize(source_code_no_comments);
-    if args.verbose {
+/// details to helper functions. Returns the path of the generated ASS file, or None if --emit
+/// stopped compilation before any ASS was generated.
+pub fn compile(args: &Args, hardware_conf: &Hardware) -> Option<PathBuf> {
+    let output_name: PathBuf = match (&args.output, args.emit) {
+        (Some(output), Emit::Ass) => output.clone(),
+        _ => Path::new(&args.filename).with_extension("ass"),
+    };
+
+    // Generate Tokens from the source code, along with every file it imports.
+    // Object files only hold the functions of their own file, which are namespaced just like when
+    // the file is imported.
+    let namespace: Option<String> =
+        (args.emit == Emit::Obj).then(|| module_namespace(Path::new(&args.filename)));
+    let mut tokens = load_program(
+        Path::new(&args.filename),
+        &args.include_paths,
+        namespace.as_deref(),
+        hardware_conf,
+    );
+    if args.verbose || args.emit == Emit::Tokens {
         export_tokens(&tokens);
     }
+    if args.emit == Emit::Tokens {
+        return None;
+    }
 
     // Use the Tokens to create an AST of the NID program.
     let ast: Ast<dyn Node> = generate_ast(&mut tokens);
-    if args.verbose {
+    if args.verbose || args.emit == Emit::Ast {
         export_ast(&ast);
     }
+    if args.emit == Emit::Ast {
+        return None;
+    }
+
+    // Break the AST down into the intermediate representation.
+    let mut ir_program: IrProgram = generate_ir(&ast, hardware_conf);
+    match &namespace {
+        Some(namespace) => ir_program.keep_namespace(namespace),
+        None if ir_program.entry_point.is_none() => panic!("main() not found!"),
+        None => {}
+    }
+
+    // Optimize the IR before lowering it.
+    let inline_threshold: isize = args
+        .inline_threshold
+        .or(hardware_conf.inline_threshold)
+        .unwrap_or(match args.opt_level {
+            OptLevel::Os => 0, // Only inline when it doesn't make the program bigger
+            _ => DEFAULT_INLINE_THRESHOLD,
+        });
+    let passes: PassManager = PassManager::new(
+        PassConfig {
+            level: args.opt_level,
+            inline_threshold,
+            verbose: args.verbose,
+            regions: hardware_conf.memory_layout(),
+        },
+        &args.pass_flags,
+    );
+    passes.run_ir(&mut ir_program);
+
+    // The CPU can't follow pointers by itself, so the ones only known at runtime call a routine.
+    lower_pointers(&mut ir_program, &hardware_conf.memory_layout());
+    if args.verbose || args.emit == Emit::Ir {
+        println!("IR:");
+        print!("{ir_program}");
+    }
+    if args.emit == Emit::Ir {
+        return None;
+    }
+
+    // Lower the IR into ASS code.
+    let mut ass_program: Vec<String> = generate_ass(&ir_program, hardware_conf);
+    for line in memory_summary() {
+        println!("{line}");
+    }
+    check_memory(&ir_program, hardware_conf, args.mem_report);
 
-    // Convert the AST into ASS code.
-    let ass_program: Vec<String> = generate_ass(&ast.body, ast.entry_point, hardware_conf);
+    // Optimize the generated ASS code.
+    passes.run_ass(&mut ass_program);
     if args.verbose {
         println!("Generated ASS code:");
         for (line, inst) in ass_program.iter().enumerate() {
@@ -57,5 +133,5 @@ pub fn compile(args: &Args, hardware_conf: &Hardware) -> PathBuf {
         exit(1);
     }
 
-    output_name
+    Some(output_name)
 }
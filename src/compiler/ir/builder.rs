@@ -0,0 +1,1728 @@
+/*
+* Builds the IR from the AST. Every function in the AST is turned into a control flow graph of
+* basic blocks, while expressions are broken down into three-address instructions.
+*/
+
+use super::{
+    BasicBlock, BlockId, BranchCond, CmpOp, InlineHint, Inst, IrFunction, IrOp, IrProgram, Operand,
+    Terminator, VReg, VarId,
+};
+use crate::compiler::ast::{self, Node};
+use crate::compiler::parser::variable_hasher;
+use crate::compiler::stdlib::builtins::{builtin, BuiltinDef, Codegen, ParamType, ReturnType};
+use crate::compiler::stdlib::{runtime_function, HEAP_INIT};
+use crate::utils::hardware_conf::{Hardware, MemRegion};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+
+/// Result of building a single comparison. Comparisons between two constants are resolved at
+/// compile time.
+enum Comparison {
+    Const(bool),
+    Runtime(BranchCond),
+}
+
+/// A block whose terminator still has to be pointed at where a condition leads.
+#[derive(Clone, Copy)]
+enum Exit {
+    Then(BlockId), // then_block of the branch ending the block
+    Else(BlockId), // else_block of -- || --
+    Jump(BlockId),
+}
+
+/// Result of building a condition. Runtime conditions list the blocks they leave from when true
+/// and when false, which are patched once the blocks they lead to exist.
+enum ConditionResult {
+    Const(bool),
+    Runtime {
+        on_true: Vec<Exit>,
+        on_false: Vec<Exit>,
+    },
+}
+
+/// Blocks leaving the loop being built through break and continue, which are patched once the
+/// blocks they lead to exist.
+#[derive(Default)]
+struct LoopExits {
+    breaks: Vec<Exit>,
+    continues: Vec<Exit>,
+}
+
+/// Keeps track of the function currently being built and which block new instructions go into.
+struct FunctionBuilder<'a> {
+    func: IrFunction,
+    current: BlockId,
+    loops: Vec<LoopExits>, // Loops around the current block, innermost last
+    signatures: &'a HashMap<String, Vec<VarId>>, // Params of every function, used by calls
+    regions: &'a [MemRegion], // Memory layout, for intrinsics like heap_start()
+    hardware_conf: &'a Hardware, // Decides which operators need runtime functions
+}
+
+/// Entry point for building the IR. Takes the AST and converts every function in it.
+pub fn generate_ir(ast: &ast::Ast<dyn Node>, hardware_conf: &Hardware) -> IrProgram {
+    let mut program = IrProgram {
+        functions: Vec::new(),
+        entry_point: None,
+        exports: Vec::new(),
+        externs: Vec::new(),
+        prealloc_start: None,
+        prealloc_end: None,
+        reserved: Vec::new(),
+    };
+
+    // Functions can be called before they are defined, so all params are needed up front
+    let signatures: HashMap<String, Vec<VarId>> = ast
+        .body
+        .iter()
+        .filter_map(|node| node.as_any().downcast_ref::<ast::Function>())
+        .map(|func| (func.identifier.clone(), function_params(func)))
+        .collect();
+
+    let regions: Vec<MemRegion> = hardware_conf.memory_layout();
+
+    // Set by #INLINE and #NOINLINE, applies to the next function
+    let mut inline_hint: InlineHint = InlineHint::Default;
+
+    for (index, node) in ast.body.iter().enumerate() {
+        if let Some(func) = node.as_any().downcast_ref::<ast::Function>() {
+            if Some(index) == ast.entry_point {
+                program.entry_point = Some(program.functions.len());
+            }
+            let mut ir_func = build_function(func, &signatures, &regions, hardware_conf);
+            ir_func.inline = inline_hint;
+            inline_hint = InlineHint::Default;
+            program.functions.push(ir_func);
+        } else if let Some(nid_macro) = node.as_any().downcast_ref::<ast::Macro>() {
+            match &nid_macro.macro_type {
+                ast::MacroType::PreAllocStart => {
+                    program.prealloc_start = Some(nid_macro.macro_value)
+                }
+                ast::MacroType::PreAllocEnd => program.prealloc_end = Some(nid_macro.macro_value),
+                ast::MacroType::Inline => inline_hint = InlineHint::Always,
+                ast::MacroType::NoInline => inline_hint = InlineHint::Never,
+                ast::MacroType::Reserve { name, start, end } => {
+                    program.reserved.push((name.clone(), *start, *end))
+                }
+            }
+        }
+    }
+
+    // The heap has to be set up before main() can allocate anything
+    let uses_heap: bool = program.functions.iter().any(|func| func.name == HEAP_INIT);
+    if let (Some(entry), true) = (program.entry_point, uses_heap) {
+        let main: &mut IrFunction = &mut program.functions[entry];
+        let entry_block: BlockId = main.entry;
+        main.blocks
+            .iter_mut()
+            .find(|block| block.id == entry_block)
+            .unwrap()
+            .insts
+            .insert(
+                0,
+                Inst::Call {
+                    name: HEAP_INIT.to_string(),
+                    params: Vec::new(),
+                    dst: None,
+                },
+            );
+    }
+
+    check_recursion(&program);
+    program
+}
+
+/// Returns the hashed params of a function.
+fn function_params(func: &ast::Function) -> Vec<VarId> {
+    func.params
+        .iter()
+        .filter_map(|param| param.as_any().downcast_ref::<ast::Variable>())
+        .map(var_id)
+        .collect()
+}
+
+/// Variables and params are placed at fixed addresses in DM, so a function can't be running more
+/// than once at a time. Panics if any function can end up calling itself.
+fn check_recursion(program: &IrProgram) {
+    let callees: HashMap<&str, Vec<&str>> = program
+        .functions
+        .iter()
+        .map(|func| {
+            let called: Vec<&str> = func
+                .blocks
+                .iter()
+                .flat_map(|block| block.insts.iter())
+                .filter_map(|inst| match inst {
+                    Inst::Call { name, .. } => Some(name.as_str()),
+                    _ => None,
+                })
+                .collect();
+            (func.name.as_str(), called)
+        })
+        .collect();
+
+    for func in program.functions.iter() {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut worklist: Vec<&str> = callees[func.name.as_str()].clone();
+
+        while let Some(name) = worklist.pop() {
+            if name == func.name {
+                panic!(
+                    "Recursion is not supported! {}() ends up calling itself.",
+                    func.name
+                );
+            }
+            if seen.insert(name) {
+                worklist.extend(callees[name].iter());
+            }
+        }
+    }
+}
+
+/// Builds the control flow graph of a single function.
+fn build_function(
+    func: &ast::Function,
+    signatures: &HashMap<String, Vec<VarId>>,
+    regions: &[MemRegion],
+    hardware_conf: &Hardware,
+) -> IrFunction {
+    let mut builder = FunctionBuilder::new(
+        &func.identifier,
+        function_params(func),
+        signatures,
+        regions,
+        hardware_conf,
+    );
+    builder.build_body(func.get_body());
+    builder.func
+}
+
+impl<'a> FunctionBuilder<'a> {
+    fn new(
+        name: &str,
+        params: Vec<VarId>,
+        signatures: &'a HashMap<String, Vec<VarId>>,
+        regions: &'a [MemRegion],
+        hardware_conf: &'a Hardware,
+    ) -> Self {
+        let mut builder = Self {
+            func: IrFunction {
+                name: name.to_string(),
+                params,
+                inline: InlineHint::Default,
+                blocks: Vec::new(),
+                entry: BlockId(0),
+                next_vreg: 0,
+            },
+            current: BlockId(0),
+            loops: Vec::new(),
+            signatures,
+            regions,
+            hardware_conf,
+        };
+        builder.func.entry = builder.new_block();
+        builder
+    }
+
+    /// Creates a new empty block. Blocks are laid out in the order they are created.
+    fn new_block(&mut self) -> BlockId {
+        let id = BlockId(self.func.blocks.len());
+        self.func.blocks.push(BasicBlock {
+            id,
+            label: random_branch_name(),
+            insts: Vec::new(),
+            term: Terminator::Return(None),
+        });
+        id
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.current = block;
+    }
+
+    fn new_vreg(&mut self) -> VReg {
+        let reg = VReg(self.func.next_vreg);
+        self.func.next_vreg += 1;
+        reg
+    }
+
+    /// Adds an instruction to the end of the current block.
+    fn emit(&mut self, inst: Inst) {
+        self.func.blocks[self.current.0].insts.push(inst);
+    }
+
+    /// Sets the terminator of any block.
+    fn set_term(&mut self, block: BlockId, term: Terminator) {
+        self.func.blocks[block.0].term = term;
+    }
+
+    /// Parses a body of NID AST nodes into the current block. Can be used for recursive parsing.
+    fn build_body(&mut self, body: &[Box<dyn Node>]) {
+        for node in body.iter() {
+            match node.get_type() {
+                ast::AstType::Asm => {
+                    let asm = node
+                        .as_any()
+                        .downcast_ref::<ast::Asm>()
+                        .expect("Downcasting to Asm failed!");
+                    self.emit(Inst::Asm {
+                        code: asm.code.iter().map(|line| line.value.clone()).collect(),
+                    });
+                }
+                ast::AstType::Assignment => self.parse_assignment(
+                    node.as_any()
+                        .downcast_ref::<ast::Assignment>()
+                        .expect("Downcasting to Assignment failed!"),
+                ),
+                ast::AstType::Block => self.build_body(node.get_body()),
+                ast::AstType::Branch => self.parse_branch_statement(
+                    node.as_any()
+                        .downcast_ref::<ast::Branch>()
+                        .expect("Downcasting to Branch failed!"),
+                ),
+                ast::AstType::Builtin => self.parse_builtin_functions(
+                    node.as_any()
+                        .downcast_ref::<ast::Builtin>()
+                        .expect("Downcasting to Builtin failed!"),
+                ),
+                ast::AstType::FunctionCall => {
+                    // Return value is thrown away
+                    self.build_call(
+                        node.as_any()
+                            .downcast_ref::<ast::FunctionCall>()
+                            .expect("Downcasting to FunctionCall failed!"),
+                        false,
+                    );
+                }
+                ast::AstType::Loop => self.parse_loop_statement(
+                    node.as_any()
+                        .downcast_ref::<ast::Loop>()
+                        .expect("Downcasting to Loop failed!"),
+                ),
+                ast::AstType::LoopJump => {
+                    let jump = node
+                        .as_any()
+                        .downcast_ref::<ast::LoopJump>()
+                        .expect("Downcasting to LoopJump failed!");
+                    let exits: &mut LoopExits = self.loops.last_mut().unwrap_or_else(|| {
+                        panic!("{:?} can only be used inside of a loop!", jump.kind)
+                    });
+                    match jump.kind {
+                        ast::LoopJumpKind::Break => exits.breaks.push(Exit::Jump(self.current)),
+                        ast::LoopJumpKind::Continue => {
+                            exits.continues.push(Exit::Jump(self.current))
+                        }
+                    }
+
+                    // Anything after break or continue ends up in an unreachable block
+                    let dead_block = self.new_block();
+                    self.switch_to(dead_block);
+                }
+                ast::AstType::Return => {
+                    let nid_return = node
+                        .as_any()
+                        .downcast_ref::<ast::Return>()
+                        .expect("Downcasting to Return failed!");
+                    let value: Option<Operand> = nid_return
+                        .return_value
+                        .as_ref()
+                        .map(|val| self.build_operand(val.as_ref()));
+                    self.set_term(self.current, Terminator::Return(value));
+
+                    // Anything after the return statement ends up in an unreachable block
+                    let dead_block = self.new_block();
+                    self.switch_to(dead_block);
+                }
+                _ => {
+                    panic!(
+                        "Unhandled Node: {:?} of type: {:?}",
+                        node.get_name(),
+                        node.get_type()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Converts an assignment into a store of the evaluated expression.
+    fn parse_assignment(&mut self, assign: &ast::Assignment) {
+        // Writes through a pointer, eg. *p = x
+        if let Some(deref) = assign.var.as_any().downcast_ref::<ast::Deref>() {
+            let addr: Operand = self.build_operand(deref.address.as_ref());
+            let src: VReg = self.build_expression(assign.expression.as_ref());
+            self.emit(Inst::StorePtr { addr, src });
+            return;
+        }
+
+        let assigned_var = assign
+            .var
+            .as_any()
+            .downcast_ref::<ast::Variable>()
+            .expect("No variable to assign!");
+
+        let src: VReg = self.build_expression(assign.expression.as_ref());
+        self.emit(Inst::Store {
+            var: var_id(assigned_var),
+            src,
+        });
+    }
+
+    /// Splits an if-statement into a true block, an optional false block and a block which both
+    /// of them continue into.
+    fn parse_branch_statement(&mut self, branch: &ast::Branch) {
+        let (on_true, on_false) = match self.build_condition(&branch.condition) {
+            ConditionResult::Runtime { on_true, on_false } => (on_true, on_false),
+            ConditionResult::Const(true) => {
+                self.build_body(branch.true_body.get_body());
+                return;
+            }
+            ConditionResult::Const(false) => {
+                if let Some(false_body) = &branch.false_body {
+                    self.build_body(false_body.get_body());
+                }
+                return;
+            }
+        };
+
+        let true_branch = self.new_block();
+        self.switch_to(true_branch);
+        self.build_body(branch.true_body.get_body());
+        let true_end = self.current;
+
+        let false_branch: Option<(BlockId, BlockId)> = branch.false_body.as_ref().map(|body| {
+            let false_branch = self.new_block();
+            self.switch_to(false_branch);
+            self.build_body(body.get_body());
+            (false_branch, self.current)
+        });
+
+        let skip_branch = self.new_block();
+        self.set_term(true_end, Terminator::Jump(skip_branch));
+
+        let else_block = if let Some((false_start, false_end)) = false_branch {
+            self.set_term(false_end, Terminator::Jump(skip_branch));
+            false_start
+        } else {
+            skip_branch
+        };
+
+        self.patch_exits(&on_true, true_branch);
+        self.patch_exits(&on_false, else_block);
+        self.switch_to(skip_branch);
+    }
+
+    /// Splits a loop into a block checking the condition, the loop body and the block that runs
+    /// once the loop is done. for loops run their step in a block of its own after the body, while
+    /// do-while loops check the condition after the body instead of before it. break leads to
+    /// loop_done, and continue to wherever the next round starts.
+    fn parse_loop_statement(&mut self, nid_loop: &ast::Loop) {
+        self.build_body(nid_loop.init.get_body());
+        if nid_loop.kind == ast::LoopKind::DoWhile {
+            return self.parse_do_while(nid_loop);
+        }
+
+        let loop_branch = self.new_block();
+        self.set_term(self.current, Terminator::Jump(loop_branch));
+        self.switch_to(loop_branch);
+
+        let condition = self.build_condition(&nid_loop.condition);
+        if let ConditionResult::Const(false) = condition {
+            return; // Loop will never be run
+        }
+
+        let while_body = self.new_block();
+        self.switch_to(while_body);
+        let exits: LoopExits = self.build_loop_body(&nid_loop.body);
+
+        let loop_step = match nid_loop.step.get_body() {
+            [] => loop_branch,
+            step => {
+                let loop_step = self.new_block();
+                self.set_term(self.current, Terminator::Jump(loop_step));
+                self.switch_to(loop_step);
+                self.build_body(step);
+                loop_step
+            }
+        };
+        self.set_term(self.current, Terminator::Jump(loop_branch));
+
+        let loop_done = self.new_block();
+        match condition {
+            ConditionResult::Runtime { on_true, on_false } => {
+                self.patch_exits(&on_true, while_body);
+                self.patch_exits(&on_false, loop_done);
+            }
+            _ => self.set_term(loop_branch, Terminator::Jump(while_body)),
+        }
+        self.patch_exits(&exits.continues, loop_step);
+        self.patch_exits(&exits.breaks, loop_done);
+        self.switch_to(loop_done);
+    }
+
+    /// Builds a do-while loop, where the condition is checked at the end of every round.
+    fn parse_do_while(&mut self, nid_loop: &ast::Loop) {
+        let while_body = self.new_block();
+        self.set_term(self.current, Terminator::Jump(while_body));
+        self.switch_to(while_body);
+        let exits: LoopExits = self.build_loop_body(&nid_loop.body);
+
+        let loop_branch = self.new_block();
+        self.set_term(self.current, Terminator::Jump(loop_branch));
+        self.switch_to(loop_branch);
+        let condition = self.build_condition(&nid_loop.condition);
+
+        let loop_done = self.new_block();
+        match condition {
+            ConditionResult::Runtime { on_true, on_false } => {
+                self.patch_exits(&on_true, while_body);
+                self.patch_exits(&on_false, loop_done);
+            }
+            ConditionResult::Const(true) => {
+                self.set_term(loop_branch, Terminator::Jump(while_body))
+            }
+            ConditionResult::Const(false) => {
+                self.set_term(loop_branch, Terminator::Jump(loop_done))
+            }
+        }
+        self.patch_exits(&exits.continues, loop_branch);
+        self.patch_exits(&exits.breaks, loop_done);
+        self.switch_to(loop_done);
+    }
+
+    /// Builds the body of a loop. Returns the blocks leaving it through break and continue.
+    fn build_loop_body(&mut self, body: &ast::Block) -> LoopExits {
+        self.loops.push(LoopExits::default());
+        self.build_body(body.get_body());
+        self.loops.pop().unwrap()
+    }
+
+    /// Builds a builtin used as a statement.
+    fn parse_builtin_functions(&mut self, call: &ast::Builtin) {
+        let def: &BuiltinDef = builtin(&call.identifier);
+        if def.returns != ReturnType::Void {
+            panic!("The value of {}() is never used!", def.name);
+        }
+
+        let args: Vec<Operand> = self.builtin_args(call, def);
+        match (def.codegen, args.as_slice()) {
+            (Codegen::Inst(_), _) => self.emit(Inst::Builtin {
+                name: call.identifier.clone(),
+                args,
+            }),
+            (Codegen::MoveTo, [Operand::Var(var), addr]) => {
+                let src = self.new_vreg();
+                self.emit(Inst::Load {
+                    dst: src,
+                    var: *var,
+                });
+                match addr {
+                    Operand::Const(addr) => self.emit(Inst::MoveTo {
+                        var: *var,
+                        src,
+                        addr: *addr as u16,
+                    }),
+                    // The variable can't move to an address only known at runtime
+                    _ => self.emit(Inst::StorePtr { addr: *addr, src }),
+                }
+            }
+            _ => panic!("{}() can't be used as a statement!", def.name),
+        }
+    }
+
+    /// Checks the arguments of a builtin against its parameters, and turns them into operands.
+    /// Expressions are evaluated into registers, as builtins can't read their arguments from DM.
+    fn builtin_args(&mut self, call: &ast::Builtin, def: &BuiltinDef) -> Vec<Operand> {
+        if call.params.len() != def.params.len() {
+            panic!(
+                "Wrong number of arguments supplied to {}()! Expected {}, got {}.",
+                def.name,
+                def.params.len(),
+                call.params.len()
+            );
+        }
+
+        call.params
+            .iter()
+            .zip(def.params.iter())
+            .enumerate()
+            .map(|(index, (arg, param))| match param {
+                ParamType::Int => match self.build_operand(arg.as_ref()) {
+                    Operand::Var(var) => {
+                        let dst = self.new_vreg();
+                        self.emit(Inst::Load { dst, var });
+                        Operand::Reg(dst)
+                    }
+                    operand => operand,
+                },
+                ParamType::Const => match self.build_operand(arg.as_ref()) {
+                    Operand::Const(value) => Operand::Const(value),
+                    _ => panic!(
+                        "Argument {} of {}() has to be known at compile time!",
+                        index + 1,
+                        def.name
+                    ),
+                },
+                ParamType::Variable => Operand::Var(var_id(
+                    arg.as_any()
+                        .downcast_ref::<ast::Variable>()
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "Argument {} of {}() has to be a variable!",
+                                index + 1,
+                                def.name
+                            )
+                        }),
+                )),
+            })
+            .collect()
+    }
+
+    /// Stores the arguments in the params of the called function and calls it. Returns the
+    /// register holding the return value if it is used.
+    fn build_call(&mut self, call: &ast::FunctionCall, use_result: bool) -> Option<VReg> {
+        let params: Vec<VarId> = self
+            .signatures
+            .get(&call.identifier)
+            .unwrap_or_else(|| panic!("Function {}() doesn't exist!", call.identifier))
+            .clone();
+        if params.len() != call.args.len() {
+            panic!(
+                "Wrong number of arguments supplied to {}()! Expected {}, got {}.",
+                call.identifier,
+                params.len(),
+                call.args.len()
+            );
+        }
+
+        // All arguments are evaluated before any is stored, in case they call the same function
+        let args: Vec<VReg> = call
+            .args
+            .iter()
+            .map(|arg| self.build_expression(arg.as_ref()))
+            .collect();
+        for (param, src) in params.iter().zip(args) {
+            self.emit(Inst::Store { var: *param, src });
+        }
+
+        let dst: Option<VReg> = use_result.then(|| self.new_vreg());
+        self.emit(Inst::Call {
+            name: call.identifier.clone(),
+            params,
+            dst,
+        });
+        dst
+    }
+
+    /// Evaluates an expression into a virtual register.
+    fn build_expression(&mut self, node: &dyn Node) -> VReg {
+        let operand: Operand = self.build_operand(node);
+        self.operand_to_reg(operand)
+    }
+
+    /// Places an operand in a virtual register, loading constants and variables into a new one.
+    fn operand_to_reg(&mut self, operand: Operand) -> VReg {
+        match operand {
+            Operand::Reg(reg) => reg,
+            Operand::Const(value) => {
+                let dst = self.new_vreg();
+                self.emit(Inst::LoadConst { dst, value });
+                dst
+            }
+            Operand::Var(var) => {
+                let dst = self.new_vreg();
+                self.emit(Inst::Load { dst, var });
+                dst
+            }
+        }
+    }
+
+    /// Evaluates an expression into an operand. Values and variables are used directly, while
+    /// binary expressions are calculated into a new register.
+    fn build_operand(&mut self, node: &dyn Node) -> Operand {
+        if let Some(val) = node.as_any().downcast_ref::<ast::Value>() {
+            Operand::Const(val.value_as_i16())
+        } else if let Some(var) = node.as_any().downcast_ref::<ast::Variable>() {
+            Operand::Var(var_id(var))
+        } else if let Some(bin_exp) = node.as_any().downcast_ref::<ast::BinaryExpression>() {
+            let op = match bin_exp.op {
+                ast::BinaryOperator::Add => IrOp::Add,
+                ast::BinaryOperator::Sub => IrOp::Sub,
+                ast::BinaryOperator::Mul => IrOp::Mul,
+                ast::BinaryOperator::Div => IrOp::Div,
+                ast::BinaryOperator::Mod => IrOp::Mod,
+                ast::BinaryOperator::And => IrOp::And,
+                ast::BinaryOperator::Or => IrOp::Or,
+                ast::BinaryOperator::Xor => IrOp::Xor,
+                ast::BinaryOperator::Shl => IrOp::Shl,
+                ast::BinaryOperator::Shr => IrOp::Shr,
+            };
+
+            // Two constants can be calculated right away
+            if let (Some(l_const), Some(r_const)) = (
+                bin_exp.left.as_any().downcast_ref::<ast::Value>(),
+                bin_exp.right.as_any().downcast_ref::<ast::Value>(),
+            ) {
+                if let Some(value) = fold_op(op, l_const.value_as_i16(), r_const.value_as_i16()) {
+                    return Operand::Const(value);
+                }
+            }
+
+            // Without an instruction for the operator a runtime function is called instead, unless
+            // the other operand is a power of two, which is left to shifts and masks. A power of
+            // two on the left of * is moved to the right for that.
+            let runtime: Option<&str> = runtime_function(bin_exp.op.symbol(), self.hardware_conf);
+            let (left, right) = match runtime {
+                Some(_) if op == IrOp::Mul && is_power_of_two(bin_exp.left.as_ref()) => {
+                    (&bin_exp.right, &bin_exp.left)
+                }
+                _ => (&bin_exp.left, &bin_exp.right),
+            };
+
+            let lhs = self.build_expression(left.as_ref());
+            let rhs = self.build_operand(right.as_ref());
+            if matches!(op, IrOp::Shl | IrOp::Shr) && !matches!(rhs, Operand::Const(_)) {
+                return self.build_shift_loop(op, lhs, rhs);
+            }
+            if let (Some(function), false) = (runtime, is_power_of_two(right.as_ref())) {
+                return Operand::Reg(self.build_runtime_call(function, lhs, rhs));
+            }
+
+            let dst = self.new_vreg();
+            self.emit(Inst::BinOp { op, dst, lhs, rhs });
+            Operand::Reg(dst)
+        } else if let Some(call) = node.as_any().downcast_ref::<ast::FunctionCall>() {
+            Operand::Reg(self.build_call(call, true).unwrap())
+        } else if let Some(call) = node.as_any().downcast_ref::<ast::Builtin>() {
+            self.build_builtin_value(call)
+        } else if let Some(deref) = node.as_any().downcast_ref::<ast::Deref>() {
+            let addr = self.build_operand(deref.address.as_ref());
+            let dst = self.new_vreg();
+            self.emit(Inst::LoadPtr { dst, addr });
+            Operand::Reg(dst)
+        } else if let Some(condition) = node.as_any().downcast_ref::<ast::Condition>() {
+            self.build_condition_value(condition)
+        } else {
+            panic!(
+                "Trying to evaluate something that is niether a value, variable, function call, pointer or binary expression! Node: {}",
+                node.display()
+            );
+        }
+    }
+
+    /// Calls a runtime function of the standard library in place of an instruction, with lhs and
+    /// rhs as its arguments. Returns the register holding the result.
+    fn build_runtime_call(&mut self, function: &str, lhs: VReg, rhs: Operand) -> VReg {
+        let params: Vec<VarId> = self
+            .signatures
+            .get(function)
+            .unwrap_or_else(|| {
+                panic!("Compiler error! Runtime function {function}() isn't loaded.")
+            })
+            .clone();
+
+        let rhs: VReg = self.operand_to_reg(rhs);
+        for (param, src) in params.iter().zip([lhs, rhs]) {
+            self.emit(Inst::Store { var: *param, src });
+        }
+
+        let dst = self.new_vreg();
+        self.emit(Inst::Call {
+            name: function.to_string(),
+            params,
+            dst: Some(dst),
+        });
+        dst
+    }
+
+    /// Shifts value by an amount only known at runtime. The hardware only shifts by constants, so
+    /// the value is shifted by one bit until the amount counts down to 0. Both are kept in
+    /// variables of the compiler while looping.
+    fn build_shift_loop(&mut self, op: IrOp, value: VReg, amount: Operand) -> Operand {
+        let check_block = self.new_block();
+        let shift_block = self.new_block();
+        let done_block = self.new_block();
+        let result: VarId = variable_hasher(
+            &check_block.to_string(),
+            &format!("shift{}", self.func.name),
+        );
+        let counter: VarId = variable_hasher(
+            &check_block.to_string(),
+            &format!("count{}", self.func.name),
+        );
+
+        let amount: VReg = self.operand_to_reg(amount);
+        self.emit(Inst::Store {
+            var: result,
+            src: value,
+        });
+        self.emit(Inst::Store {
+            var: counter,
+            src: amount,
+        });
+        self.set_term(self.current, Terminator::Jump(check_block));
+
+        self.switch_to(check_block);
+        let count = self.new_vreg();
+        self.emit(Inst::Load {
+            dst: count,
+            var: counter,
+        });
+        self.set_term(
+            check_block,
+            Terminator::Branch {
+                cond: BranchCond::Cmp {
+                    op: CmpOp::GreatThan,
+                    lhs: count,
+                    rhs: Operand::Const(0),
+                },
+                then_block: shift_block,
+                else_block: done_block,
+            },
+        );
+
+        self.switch_to(shift_block);
+        for (var, op) in [(result, op), (counter, IrOp::Sub)] {
+            let lhs = self.new_vreg();
+            let dst = self.new_vreg();
+            self.emit(Inst::Load { dst: lhs, var });
+            self.emit(Inst::BinOp {
+                op,
+                dst,
+                lhs,
+                rhs: Operand::Const(1),
+            });
+            self.emit(Inst::Store { var, src: dst });
+        }
+        self.set_term(shift_block, Terminator::Jump(check_block));
+
+        self.switch_to(done_block);
+        Operand::Var(result)
+    }
+
+    /// Evaluates a builtin used in an expression. Values of the hardware config are known at
+    /// compile time, while conditions such as is_pressed() are turned into 0 or 1.
+    fn build_builtin_value(&mut self, call: &ast::Builtin) -> Operand {
+        let def: &BuiltinDef = builtin(&call.identifier);
+        if def.returns == ReturnType::Void {
+            panic!("{}() doesn't return a value!", def.name);
+        }
+        if !def.in_expression {
+            panic!("{}() can't be used in expressions!", def.name);
+        }
+
+        let args: Vec<Operand> = self.builtin_args(call, def);
+        match def.codegen {
+            Codegen::Value(value) => Operand::Const(value(self.regions)),
+            Codegen::Branch(_) => {
+                let dst = self.new_vreg();
+                self.emit(Inst::SetCond {
+                    dst,
+                    cond: BranchCond::Builtin {
+                        name: call.identifier.clone(),
+                        args,
+                    },
+                });
+                Operand::Reg(dst)
+            }
+            _ => panic!("{}() can't be used in expressions!", def.name),
+        }
+    }
+
+    /// Evaluates a condition used as a value, eg. `bool alive = hp > 0;`, into 1 or 0. Single
+    /// comparisons are set straight into a register. && and || still only check their right side
+    /// when needed, so they branch to blocks storing the result in a variable of the compiler.
+    fn build_condition_value(&mut self, condition: &ast::Condition) -> Operand {
+        if !is_logic(condition) {
+            return match self.build_comparison(condition) {
+                Comparison::Const(value) => Operand::Const(value as i16),
+                Comparison::Runtime(cond) => {
+                    let dst = self.new_vreg();
+                    self.emit(Inst::SetCond { dst, cond });
+                    Operand::Reg(dst)
+                }
+            };
+        }
+
+        let (on_true, on_false) = match self.build_condition(condition) {
+            ConditionResult::Const(value) => return Operand::Const(value as i16),
+            ConditionResult::Runtime { on_true, on_false } => (on_true, on_false),
+        };
+
+        let true_block = self.new_block();
+        let false_block = self.new_block();
+        let done_block = self.new_block();
+        let result: VarId =
+            variable_hasher(&true_block.to_string(), &format!("cond{}", self.func.name));
+
+        for (exits, block, value) in [(on_true, true_block, 1), (on_false, false_block, 0)] {
+            self.patch_exits(&exits, block);
+            self.switch_to(block);
+            let src = self.new_vreg();
+            self.emit(Inst::LoadConst { dst: src, value });
+            self.emit(Inst::Store { var: result, src });
+            self.set_term(block, Terminator::Jump(done_block));
+        }
+
+        self.switch_to(done_block);
+        Operand::Var(result)
+    }
+
+    /// Builds the condition of if statements and loops. && and || only check their right side
+    /// when the left side doesn't decide the outcome already, so every side gets its own block.
+    fn build_condition(&mut self, condition: &ast::Condition) -> ConditionResult {
+        match (&condition.operator, &condition.left) {
+            (ast::ConditionalOperator::And, Some(left))
+            | (ast::ConditionalOperator::Or, Some(left)) => {
+                let is_and: bool = matches!(condition.operator, ast::ConditionalOperator::And);
+                let (left, right) = nested_condition(left.as_ref())
+                    .zip(nested_condition(condition.right.as_ref()))
+                    .expect("&& and || need a condition on both sides!");
+                self.build_logic(is_and, left, right)
+            }
+            (ast::ConditionalOperator::Not, None)
+                if nested_condition(condition.right.as_ref()).is_some() =>
+            {
+                match self.build_condition(nested_condition(condition.right.as_ref()).unwrap()) {
+                    ConditionResult::Const(value) => ConditionResult::Const(!value),
+                    ConditionResult::Runtime { on_true, on_false } => ConditionResult::Runtime {
+                        on_true: on_false,
+                        on_false: on_true,
+                    },
+                }
+            }
+            _ => match self.build_comparison(condition) {
+                Comparison::Const(value) => ConditionResult::Const(value),
+                Comparison::Runtime(cond) => {
+                    // The targets are filled in by patch_exits()
+                    let block: BlockId = self.current;
+                    self.set_term(
+                        block,
+                        Terminator::Branch {
+                            cond,
+                            then_block: block,
+                            else_block: block,
+                        },
+                    );
+                    ConditionResult::Runtime {
+                        on_true: vec![Exit::Then(block)],
+                        on_false: vec![Exit::Else(block)],
+                    }
+                }
+            },
+        }
+    }
+
+    /// Builds left && right, or left || right.
+    fn build_logic(
+        &mut self,
+        is_and: bool,
+        left: &ast::Condition,
+        right: &ast::Condition,
+    ) -> ConditionResult {
+        // The right side is checked when the left is true for &&, and when it is false for ||
+        let (to_right, decided): (Vec<Exit>, Vec<Exit>) = match self.build_condition(left) {
+            ConditionResult::Const(value) if value == is_and => return self.build_condition(right),
+            ConditionResult::Const(value) => return ConditionResult::Const(value),
+            ConditionResult::Runtime { on_true, on_false } if is_and => (on_true, on_false),
+            ConditionResult::Runtime { on_true, on_false } => (on_false, on_true),
+        };
+
+        let right_block = self.new_block();
+        self.patch_exits(&to_right, right_block);
+        self.switch_to(right_block);
+
+        let (mut on_true, mut on_false): (Vec<Exit>, Vec<Exit>) = match self.build_condition(right)
+        {
+            ConditionResult::Runtime { on_true, on_false } => (on_true, on_false),
+            ConditionResult::Const(value) => {
+                let block: BlockId = self.current;
+                self.set_term(block, Terminator::Jump(block));
+                match value {
+                    true => (vec![Exit::Jump(block)], Vec::new()),
+                    false => (Vec::new(), vec![Exit::Jump(block)]),
+                }
+            }
+        };
+
+        match is_and {
+            true => on_false.extend(decided),
+            false => on_true.extend(decided),
+        }
+        ConditionResult::Runtime { on_true, on_false }
+    }
+
+    /// Points the terminators of the blocks a condition leaves from at target.
+    fn patch_exits(&mut self, exits: &[Exit], target: BlockId) {
+        for exit in exits.iter() {
+            match *exit {
+                Exit::Then(block) | Exit::Else(block) => {
+                    if let Terminator::Branch {
+                        then_block,
+                        else_block,
+                        ..
+                    } = &mut self.func.blocks[block.0].term
+                    {
+                        match exit {
+                            Exit::Then(_) => *then_block = target,
+                            _ => *else_block = target,
+                        }
+                    }
+                }
+                Exit::Jump(block) => self.set_term(block, Terminator::Jump(target)),
+            }
+        }
+    }
+
+    /// Builds a single comparison, or a builtin checked on its own such as is_pressed().
+    /// NOTE: ! (not) is handled as comparing the operand with 0.
+    fn build_comparison(&mut self, condition: &ast::Condition) -> Comparison {
+        let is_single: bool =
+            condition.left.is_none() && matches!(condition.operator, ast::ConditionalOperator::Eq);
+        if let Some(call) = condition
+            .right
+            .as_any()
+            .downcast_ref::<ast::Builtin>()
+            .filter(|_| is_single)
+        {
+            let def: &BuiltinDef = builtin(&call.identifier);
+            if !def.in_condition {
+                panic!("{}() can't be used as a condition!", def.name);
+            }
+            return Comparison::Runtime(BranchCond::Builtin {
+                name: call.identifier.clone(),
+                args: self.builtin_args(call, def),
+            });
+        }
+
+        let (op, left, right): (CmpOp, &dyn Node, Operand) = match &condition.left {
+            Some(left) => {
+                let op = match condition.operator {
+                    ast::ConditionalOperator::NotEq => CmpOp::NotEq,
+                    ast::ConditionalOperator::Eq => CmpOp::Eq,
+                    ast::ConditionalOperator::LessThan => CmpOp::LessThan,
+                    ast::ConditionalOperator::LessEq => CmpOp::LessEq,
+                    ast::ConditionalOperator::GreatThan => CmpOp::GreatThan,
+                    ast::ConditionalOperator::GreatEq => CmpOp::GreatEq,
+                    ast::ConditionalOperator::Not => panic!("! used with two operands!"),
+                    ast::ConditionalOperator::And | ast::ConditionalOperator::Or => {
+                        panic!("&& and || need a condition on both sides!")
+                    }
+                };
+                (
+                    op,
+                    left.as_ref(),
+                    self.build_operand(condition.right.as_ref()),
+                )
+            }
+            None => (CmpOp::Eq, condition.right.as_ref(), Operand::Const(0)),
+        };
+
+        // Conditions between two constants are known at compile time
+        if let (Some(l_const), Operand::Const(r_const)) =
+            (left.as_any().downcast_ref::<ast::Value>(), right)
+        {
+            return Comparison::Const(compare(op, l_const.value_as_i16(), r_const));
+        }
+
+        let lhs = self.build_expression(left);
+        Comparison::Runtime(BranchCond::Cmp {
+            op,
+            lhs,
+            rhs: right,
+        })
+    }
+}
+
+/// Returns whether a condition joins or negates other conditions, rather than comparing values.
+fn is_logic(condition: &ast::Condition) -> bool {
+    match (&condition.operator, &condition.left) {
+        (ast::ConditionalOperator::And, _) | (ast::ConditionalOperator::Or, _) => true,
+        (ast::ConditionalOperator::Not, None) => {
+            nested_condition(condition.right.as_ref()).is_some()
+        }
+        _ => false,
+    }
+}
+
+/// Returns the condition joined by && or || or negated by !, if node is one.
+fn nested_condition(node: &dyn Node) -> Option<&ast::Condition> {
+    node.as_any().downcast_ref::<ast::Condition>()
+}
+
+/// Returns whether node is a constant power of two, which * / and % can work on without calling a
+/// runtime function.
+fn is_power_of_two(node: &dyn Node) -> bool {
+    node.as_any()
+        .downcast_ref::<ast::Value>()
+        .is_some_and(|val| val.value_as_i16() > 0 && (val.value_as_i16() as u16).is_power_of_two())
+}
+
+/// Calculates the result of a binary operation between two constants. Division by zero has no
+/// result, and is left for the hardware to deal with.
+pub fn fold_op(op: IrOp, left: i16, right: i16) -> Option<i16> {
+    let value: i16 = match op {
+        IrOp::Add => left.wrapping_add(right),
+        IrOp::Sub => left.wrapping_sub(right),
+        IrOp::Mul => left.wrapping_mul(right),
+        IrOp::Div | IrOp::Mod if right == 0 => return None,
+        IrOp::Div => left.wrapping_div(right),
+        IrOp::Mod => left.wrapping_rem(right),
+        IrOp::And => left & right,
+        IrOp::Or => left | right,
+        IrOp::Xor => left ^ right,
+        // Shifting by 16 or more clears every bit, negative amounts don't shift at all
+        IrOp::Shl => match right {
+            ..=0 => left,
+            1..=15 => ((left as u16) << right) as i16,
+            _ => 0,
+        },
+        IrOp::Shr => match right {
+            ..=0 => left,
+            1..=15 => ((left as u16) >> right) as i16,
+            _ => 0,
+        },
+    };
+    Some(value)
+}
+
+/// Calculates the result of a comparison between two constants.
+pub fn compare(op: CmpOp, left: i16, right: i16) -> bool {
+    match op {
+        CmpOp::Eq => left == right,
+        CmpOp::NotEq => left != right,
+        CmpOp::LessThan => left < right,
+        CmpOp::LessEq => left <= right,
+        CmpOp::GreatThan => left > right,
+        CmpOp::GreatEq => left >= right,
+    }
+}
+
+/// Returns the hashed id of a variable.
+fn var_id(var: &ast::Variable) -> VarId {
+    var.identifier
+        .parse::<VarId>()
+        .expect("Variable has not been hashed!")
+}
+
+/// Geenrates a random name for a branch to be used in jumps
+pub fn random_branch_name() -> String {
+    // Create a thread-local RNG (random number generator)
+    let rng = rand::thread_rng();
+
+    // Generate a random u16 number
+    let random_string: String = rng
+        .sample_iter(&Alphanumeric)
+        .take(16) // Hard coded length of 10 for now
+        .map(|mut ch| {
+            if !ch.is_ascii_alphabetic() {
+                ch = b'a'
+            }
+            char::from(ch)
+        })
+        .collect();
+
+    format!("#{}", random_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::lexer::tokenize;
+    use crate::compiler::parser::generate_ast;
+    use crate::utils::hardware_conf::RegionKind;
+
+    /// Builds the IR of a NID program.
+    fn build(source: &str) -> IrProgram {
+        let mut tokens = tokenize(source.to_string());
+        generate_ir(&generate_ast(&mut tokens), &Hardware::default())
+    }
+
+    #[test]
+    fn pointers_are_read_and_written_through() {
+        let program = build("int main() {\nint *p = 100;\n*p = 3;\nint x = *(p + 1);\n}");
+        let insts: &[Inst] = &program.functions[0].blocks[0].insts;
+        assert!(insts
+            .iter()
+            .any(|inst| matches!(inst, Inst::StorePtr { .. })));
+        assert!(insts.iter().any(|inst| matches!(
+            inst,
+            Inst::LoadPtr {
+                addr: Operand::Reg(_),
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn heap_bounds_come_from_the_hardware_config() {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        let hardware_conf = Hardware {
+            regions: vec![
+                region("globals", RegionKind::Globals, 0, 99),
+                region("heap", RegionKind::Heap, 100, 199),
+                region("stack", RegionKind::Stack, 235, 254),
+            ],
+            ..Hardware::default()
+        };
+        let mut tokens = tokenize(String::from(
+            "int main() {\nint x = heap_start();\nint y = heap_end();\n}",
+        ));
+        let program = generate_ir(&generate_ast(&mut tokens), &hardware_conf);
+        let values: Vec<i16> = program.functions[0].blocks[0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::LoadConst { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, [100, 199]);
+    }
+
+    #[test]
+    #[should_panic(expected = "heap_start() needs a heap region in the hardware config!")]
+    fn heap_bounds_need_a_heap_region() {
+        build("int main() {\nint x = heap_start();\n}");
+    }
+
+    #[test]
+    fn main_sets_up_the_heap_first() {
+        // Names are only qualified when loading modules, which is skipped here
+        let mut tokens = tokenize(String::from(
+            "void init() {\n}\nint main() {\nint x = 1;\n}",
+        ));
+        for token in tokens.iter_mut().filter(|token| token.value == "init") {
+            token.value = HEAP_INIT.to_string();
+        }
+        let program = generate_ir(&generate_ast(&mut tokens), &Hardware::default());
+        let main: &IrFunction = &program.functions[program.entry_point.unwrap()];
+        assert!(matches!(
+            &main.blocks[0].insts[0],
+            Inst::Call { name, .. } if name == HEAP_INIT
+        ));
+    }
+
+    #[test]
+    fn is_pressed_branches_on_the_builtin() {
+        let program = build("int main() {\nint x = 0;\nif (is_pressed(4)) {\nx = 1;\n}\n}");
+        match &program.functions[0].blocks[0].term {
+            Terminator::Branch {
+                cond: BranchCond::Builtin { name, args },
+                ..
+            } => {
+                assert_eq!(name, "is_pressed");
+                assert_eq!(args, &[Operand::Const(4)]);
+            }
+            term => panic!("Expected a branch on is_pressed(), got {term:?}"),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrong number of arguments supplied to sleep()! Expected 1, got 2.")]
+    fn builtin_argument_count_is_checked() {
+        build("int main() {\nsleep(1, 2);\n}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Argument 1 of move_to() has to be a variable!")]
+    fn builtin_argument_types_are_checked() {
+        build("int main() {\nmove_to(3, 100);\n}");
+    }
+
+    #[test]
+    fn runtime_builtin_arguments_are_loaded_into_registers() {
+        let program = build("int main() {\nint ms = 20;\nsleep(ms * 2);\nsleep(ms);\nsleep(5);\n}");
+        let args: Vec<&[Operand]> = program.functions[0].blocks[0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Builtin { args, .. } => Some(args.as_slice()),
+                _ => None,
+            })
+            .collect();
+        assert!(matches!(args[0], [Operand::Reg(_)]));
+        assert!(matches!(args[1], [Operand::Reg(_)]));
+        assert_eq!(args[2], [Operand::Const(5)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Argument 1 of is_pressed() has to be known at compile time!")]
+    fn scancodes_have_to_be_known_at_compile_time() {
+        build("int main() {\nint key = 4;\nif (is_pressed(key)) {\nkey = 5;\n}\n}");
+    }
+
+    #[test]
+    fn move_to_a_runtime_address_writes_through_it() {
+        let program = build("int main() {\nint x = 1;\nint addr = 100;\nmove_to(x, addr + 1);\n}");
+        let insts: &[Inst] = &program.functions[0].blocks[0].insts;
+        assert!(matches!(insts.last(), Some(Inst::StorePtr { .. })));
+        assert!(!insts.iter().any(|inst| matches!(inst, Inst::MoveTo { .. })));
+    }
+
+    #[test]
+    #[should_panic(expected = "sleep() doesn't return a value!")]
+    fn void_builtins_have_no_value() {
+        build("int main() {\nint x = sleep(1);\n}");
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let program = build("int main() {\nint x = 6 * 7;\n}");
+        let insts: &[Inst] = &program.functions[0].blocks[0].insts;
+        assert!(matches!(insts[0], Inst::LoadConst { value: 42, .. }));
+        assert!(matches!(insts[1], Inst::Store { .. }));
+        assert_eq!(insts.len(), 2);
+    }
+
+    #[test]
+    fn while_loop_gets_condition_body_and_exit_blocks() {
+        let program = build("int main() {\nint x = 0;\nwhile (x < 3) {\nx = x + 1;\n}\n}");
+        let func: &IrFunction = &program.functions[0];
+        assert_eq!(func.blocks.len(), 4);
+        assert_eq!(func.blocks[0].term, Terminator::Jump(BlockId(1)));
+        match &func.blocks[1].term {
+            Terminator::Branch {
+                cond: BranchCond::Cmp { op, rhs, .. },
+                then_block,
+                else_block,
+            } => {
+                assert_eq!(*op, CmpOp::LessThan);
+                assert_eq!(*rhs, Operand::Const(3));
+                assert_eq!((*then_block, *else_block), (BlockId(2), BlockId(3)));
+            }
+            term => panic!("Expected a branch, got {term}"),
+        }
+        assert_eq!(func.blocks[2].term, Terminator::Jump(BlockId(1)));
+    }
+
+    #[test]
+    fn constant_conditions_are_resolved() {
+        let program =
+            build("int main() {\nint x = 0;\nif (1 < 2) {\nx = 1;\n} else {\nx = 2;\n}\n}");
+        let func: &IrFunction = &program.functions[0];
+        assert_eq!(func.blocks.len(), 1);
+        assert!(matches!(
+            func.blocks[0].insts[2],
+            Inst::LoadConst { value: 1, .. }
+        ));
+        assert_eq!(func.blocks[0].insts.len(), 4);
+    }
+
+    #[test]
+    fn code_after_return_lands_in_a_new_block() {
+        let program = build("int main() {\nint x = 1;\nreturn x;\nx = 2;\n}");
+        let func: &IrFunction = &program.functions[0];
+        assert!(matches!(
+            func.blocks[0].term,
+            Terminator::Return(Some(Operand::Var(_)))
+        ));
+        assert_eq!(func.blocks[1].insts.len(), 2);
+    }
+
+    /// Returns where the branch ending a block leads.
+    fn targets(func: &IrFunction, block: usize) -> (BlockId, BlockId) {
+        match func.blocks[block].term {
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => (then_block, else_block),
+            ref term => panic!("Expected block {block} to branch, got {term:?}"),
+        }
+    }
+
+    #[test]
+    fn and_only_checks_the_right_side_when_the_left_is_true() {
+        let program =
+            build("int main() {\nint x = 0;\nint y = 0;\nif (x < 1 && y > 2) {\nx = 1;\n}\n}");
+        let func: &IrFunction = &program.functions[0];
+        let (left_true, left_false) = targets(func, 0);
+        let (right_true, right_false) = targets(func, 1);
+        assert_eq!(left_true, BlockId(1));
+        assert_eq!(right_true, BlockId(2));
+        assert_eq!(left_false, right_false);
+    }
+
+    #[test]
+    fn or_only_checks_the_right_side_when_the_left_is_false() {
+        let program =
+            build("int main() {\nint x = 0;\nint y = 0;\nif (x < 1 || y > 2) {\nx = 1;\n}\n}");
+        let func: &IrFunction = &program.functions[0];
+        let (left_true, left_false) = targets(func, 0);
+        let (right_true, _) = targets(func, 1);
+        assert_eq!(left_false, BlockId(1));
+        assert_eq!(left_true, BlockId(2));
+        assert_eq!(right_true, BlockId(2));
+    }
+
+    #[test]
+    fn not_swaps_where_a_condition_leads() {
+        let program = build("int main() {\nint x = 0;\nif (!(x < 1)) {\nx = 1;\n}\n}");
+        assert_eq!(targets(&program.functions[0], 0), (BlockId(2), BlockId(1)));
+    }
+
+    #[test]
+    fn constant_sides_of_logic_are_dropped() {
+        let program = build("int main() {\nint x = 0;\nif (1 < 2 && x > 0) {\nx = 1;\n}\n}");
+        let func: &IrFunction = &program.functions[0];
+        assert_eq!(func.blocks.len(), 3);
+        assert_eq!(targets(func, 0), (BlockId(1), BlockId(2)));
+
+        let program = build("int main() {\nint x = 0;\nif (2 < 1 && x > 0) {\nx = 1;\n}\n}");
+        assert_eq!(program.functions[0].blocks.len(), 1);
+    }
+
+    #[test]
+    fn is_pressed_can_be_stored() {
+        let program = build("int main() {\nint k = is_pressed(4);\n}");
+        assert!(matches!(
+            &program.functions[0].blocks[0].insts[0],
+            Inst::SetCond { cond: BranchCond::Builtin { name, .. }, .. } if name == "is_pressed"
+        ));
+    }
+
+    #[test]
+    fn comparisons_are_set_into_registers() {
+        let program = build("int main() {\nint x = 3;\nbool big = x > 2;\n}");
+        let func: &IrFunction = &program.functions[0];
+        assert_eq!(func.blocks.len(), 1);
+        assert!(func.blocks[0].insts.iter().any(|inst| matches!(
+            inst,
+            Inst::SetCond {
+                cond: BranchCond::Cmp {
+                    op: CmpOp::GreatThan,
+                    ..
+                },
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn constant_comparisons_become_constants() {
+        let program = build("int main() {\nbool yes = 1 < 2;\nbool no = !(1 < 2);\n}");
+        let values: Vec<i16> = program.functions[0].blocks[0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::LoadConst { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(values, [1, 0]);
+    }
+
+    #[test]
+    fn logic_values_are_stored_on_both_sides() {
+        let program = build("int main() {\nint x = 3;\nbool ok = x > 0 && x < 5;\n}");
+        let func: &IrFunction = &program.functions[0];
+        // Left side, right side, true, false and the block continuing after them
+        assert_eq!(func.blocks.len(), 5);
+        let stored: Vec<i16> = func.blocks[2..4]
+            .iter()
+            .map(
+                |block| match (&block.insts[0], &block.insts[1], &block.term) {
+                    (
+                        Inst::LoadConst { value, .. },
+                        Inst::Store { .. },
+                        Terminator::Jump(BlockId(4)),
+                    ) => *value,
+                    other => panic!("Expected a stored bool, got {other:?}"),
+                },
+            )
+            .collect();
+        assert_eq!(stored, [1, 0]);
+        assert!(matches!(func.blocks[4].insts[0], Inst::Load { .. }));
+    }
+
+    #[test]
+    fn folds_bitwise_and_shift_ops() {
+        assert_eq!(fold_op(IrOp::Mod, -7, 3), Some(-1));
+        assert_eq!(fold_op(IrOp::And, 0b1100, 0b1010), Some(0b1000));
+        assert_eq!(fold_op(IrOp::Or, 0b1100, 0b1010), Some(0b1110));
+        assert_eq!(fold_op(IrOp::Xor, 0b1100, 0b1010), Some(0b0110));
+        assert_eq!(fold_op(IrOp::Shl, 1, 15), Some(i16::MIN));
+        assert_eq!(fold_op(IrOp::Shr, -1, 12), Some(0xF));
+        assert_eq!(fold_op(IrOp::Shl, 1, 16), Some(0));
+        assert_eq!(fold_op(IrOp::Shr, 8, -1), Some(8));
+    }
+
+    #[test]
+    fn division_by_zero_is_left_for_the_hardware() {
+        assert_eq!(fold_op(IrOp::Div, 5, 0), None);
+        assert_eq!(fold_op(IrOp::Mod, 5, 0), None);
+
+        let program = build("int main() {\nint x = 7 / 0;\nint y = 7 % 0;\n}");
+        let ops: Vec<IrOp> = program.functions[0].blocks[0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::BinOp {
+                    op,
+                    rhs: Operand::Const(0),
+                    ..
+                } => Some(*op),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ops, [IrOp::Div, IrOp::Mod]);
+    }
+
+    #[test]
+    fn shifts_by_variables_become_loops() {
+        let program = build("int main() {\nint x = 1;\nint n = 3;\nint y = x << n;\n}");
+        let func: &IrFunction = &program.functions[0];
+        // Setup, check, shift once and the block continuing after the loop
+        assert_eq!(func.blocks.len(), 4);
+        assert_eq!(func.blocks[0].term, Terminator::Jump(BlockId(1)));
+        assert_eq!(targets(func, 1), (BlockId(2), BlockId(3)));
+        assert_eq!(func.blocks[2].term, Terminator::Jump(BlockId(1)));
+        assert!(func.blocks[2].insts.iter().any(|inst| matches!(
+            inst,
+            Inst::BinOp {
+                op: IrOp::Shl,
+                rhs: Operand::Const(1),
+                ..
+            }
+        )));
+    }
+
+    /// Builds source on hardware without mul, with a stand in for math.mul().
+    fn build_without_mul(source: &str) -> IrFunction {
+        let hardware_conf = Hardware {
+            has_mul: false,
+            ..Hardware::default()
+        };
+        // Names are only qualified when loading modules, which is skipped here
+        let mut tokens = tokenize(format!("int mul(int a, int b) {{\nreturn a;\n}}\n{source}"));
+        for token in tokens.iter_mut().filter(|token| token.value == "mul") {
+            token.value = String::from("math.mul");
+        }
+        let program = generate_ir(&generate_ast(&mut tokens), &hardware_conf);
+        program.functions[program.entry_point.unwrap()].clone()
+    }
+
+    #[test]
+    fn multiplying_without_mul_calls_the_runtime() {
+        let main = build_without_mul("int main() {\nint x = 3;\nint y = x * 5;\n}");
+        let calls: Vec<&str> = main.blocks[0]
+            .insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Call { name, .. } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, ["math.mul"]);
+    }
+
+    #[test]
+    fn powers_of_two_are_left_to_shifts() {
+        for source in ["int y = x * 4;", "int y = 4 * x;"] {
+            let main = build_without_mul(&format!("int main() {{\nint x = 3;\n{source}\n}}"));
+            let insts: &[Inst] = &main.blocks[0].insts;
+            assert!(
+                !insts.iter().any(|inst| matches!(inst, Inst::Call { .. })),
+                "{source}"
+            );
+            assert!(
+                insts.iter().any(|inst| matches!(
+                    inst,
+                    Inst::BinOp {
+                        op: IrOp::Mul,
+                        rhs: Operand::Const(4),
+                        ..
+                    }
+                )),
+                "{source}"
+            );
+        }
+    }
+
+    /// Runs a function taking no arguments and returns what it returns. Only what the loops below
+    /// need is supported, and running for too long counts as a loop that never ends.
+    fn run(func: &IrFunction) -> i16 {
+        let mut regs: HashMap<VReg, i16> = HashMap::new();
+        let mut vars: HashMap<VarId, i16> = HashMap::new();
+        let mut block: &BasicBlock = func.block(func.entry);
+
+        for _ in 0..10_000 {
+            let value =
+                |operand: &Operand, regs: &HashMap<VReg, i16>, vars: &HashMap<VarId, i16>| {
+                    match operand {
+                        Operand::Reg(reg) => regs[reg],
+                        Operand::Const(value) => *value,
+                        Operand::Var(var) => vars.get(var).copied().unwrap_or(0),
+                    }
+                };
+            let holds =
+                |cond: &BranchCond, regs: &HashMap<VReg, i16>, vars: &HashMap<VarId, i16>| {
+                    match cond {
+                        BranchCond::Cmp { op, lhs, rhs } => {
+                            compare(*op, regs[lhs], value(rhs, regs, vars))
+                        }
+                        BranchCond::Builtin { .. } => panic!("Builtins are not supported"),
+                    }
+                };
+
+            for inst in block.insts.iter() {
+                match inst {
+                    Inst::LoadConst { dst, value } => {
+                        regs.insert(*dst, *value);
+                    }
+                    Inst::Load { dst, var } => {
+                        regs.insert(*dst, vars.get(var).copied().unwrap_or(0));
+                    }
+                    Inst::Store { var, src } => {
+                        vars.insert(*var, regs[src]);
+                    }
+                    Inst::BinOp { op, dst, lhs, rhs } => {
+                        let result: i16 = fold_op(*op, regs[lhs], value(rhs, &regs, &vars))
+                            .expect("Division by zero");
+                        regs.insert(*dst, result);
+                    }
+                    Inst::SetCond { dst, cond } => {
+                        let result: i16 = holds(cond, &regs, &vars) as i16;
+                        regs.insert(*dst, result);
+                    }
+                    _ => panic!("Unsupported instruction {inst}"),
+                }
+            }
+
+            let next: BlockId = match &block.term {
+                Terminator::Jump(target) => *target,
+                Terminator::Branch {
+                    cond,
+                    then_block,
+                    else_block,
+                } => match holds(cond, &regs, &vars) {
+                    true => *then_block,
+                    false => *else_block,
+                },
+                Terminator::Return(value_returned) => {
+                    return value(&value_returned.expect("Nothing returned"), &regs, &vars)
+                }
+            };
+            block = func.block(next);
+        }
+        panic!("{} never returned", func.name);
+    }
+
+    /// Builds and runs a function called f, which holds the given body.
+    fn run_body(body: &str) -> i16 {
+        let program = build(&format!("int f() {{\n{body}\n}}"));
+        run(&program.functions[0])
+    }
+
+    #[test]
+    fn for_loop_counts() {
+        let body = "
+            int sum = 0;
+            for (int i = 0; i < 5; i = i + 1) {
+                sum = sum + i;
+            }
+            return sum;";
+        assert_eq!(run_body(body), 10);
+    }
+
+    #[test]
+    fn continue_in_for_runs_the_step() {
+        // Jumping straight to the condition would skip i = i + 1 and loop forever
+        let body = "
+            int sum = 0;
+            for (int i = 0; i < 10; i = i + 1) {
+                if (i == 3) {
+                    continue;
+                }
+                sum = sum + i;
+            }
+            return sum;";
+        assert_eq!(run_body(body), 42);
+    }
+
+    #[test]
+    fn break_leaves_only_the_inner_loop() {
+        let body = "
+            int count = 0;
+            for (int x = 0; x < 4; x = x + 1) {
+                int y = 0;
+                while (true) {
+                    if (y == x) {
+                        break;
+                    }
+                    count = count + 1;
+                    y = y + 1;
+                }
+                count = count + 100;
+            }
+            return count;";
+        assert_eq!(run_body(body), 406);
+    }
+
+    #[test]
+    fn continue_in_nested_loop_continues_the_inner_loop() {
+        let body = "
+            int count = 0;
+            for (int x = 0; x < 3; x = x + 1) {
+                for (int y = 0; y < 3; y = y + 1) {
+                    if (y == 1) {
+                        continue;
+                    }
+                    count = count + 1;
+                }
+                count = count + 10;
+            }
+            return count;";
+        assert_eq!(run_body(body), 36);
+    }
+
+    #[test]
+    fn for_without_parts_loops_until_break() {
+        let body = "
+            int i = 0;
+            for (;;) {
+                i = i + 1;
+                if (i >= 7) {
+                    break;
+                }
+            }
+            return i;";
+        assert_eq!(run_body(body), 7);
+    }
+
+    #[test]
+    fn do_while_runs_body_before_condition() {
+        let body = "
+            int n = 0;
+            do {
+                n = n + 1;
+            } while (false);
+            do {
+                n = n + 1;
+                if (n == 2) {
+                    continue;
+                }
+                n = n + 10;
+            } while (n < 2);
+            return n;";
+        // continue goes to the condition, which ends the loop before n gets another 10
+        assert_eq!(run_body(body), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Break can only be used inside of a loop!")]
+    fn break_outside_of_loop() {
+        build("void main() {\n    break;\n}");
+    }
+
+    #[test]
+    #[should_panic(expected = "Continue can only be used inside of a loop!")]
+    fn continue_outside_of_loop() {
+        build("void main() {\n    if (true) {\n        continue;\n    }\n}");
+    }
+}
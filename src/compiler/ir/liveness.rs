@@ -0,0 +1,77 @@
+/*
+* Liveness analysis of virtual registers. A register is live at a point in the program if its
+* current value might still be read later on.
+*
+* Solved as a backwards dataflow problem over the control flow graph, iterating until none of the
+* live sets change anymore.
+*/
+
+use super::{BlockId, IrFunction, VReg};
+use std::collections::{HashMap, HashSet};
+
+/// Registers that are live when entering and leaving every block of a function.
+pub struct Liveness {
+    pub live_in: HashMap<BlockId, HashSet<VReg>>,
+    pub live_out: HashMap<BlockId, HashSet<VReg>>,
+}
+
+/// Calculates which virtual registers are live at the start and end of every block.
+pub fn vreg_liveness(func: &IrFunction) -> Liveness {
+    // Registers read before being written in a block (gen) and registers written in it (kill)
+    let mut gen: HashMap<BlockId, HashSet<VReg>> = HashMap::new();
+    let mut kill: HashMap<BlockId, HashSet<VReg>> = HashMap::new();
+
+    for block in func.blocks.iter() {
+        let mut block_gen: HashSet<VReg> = HashSet::new();
+        let mut block_kill: HashSet<VReg> = HashSet::new();
+
+        for inst in block.insts.iter() {
+            for reg in inst.uses() {
+                if !block_kill.contains(&reg) {
+                    block_gen.insert(reg);
+                }
+            }
+            if let Some(reg) = inst.def() {
+                block_kill.insert(reg);
+            }
+        }
+        for reg in block.term.uses() {
+            if !block_kill.contains(&reg) {
+                block_gen.insert(reg);
+            }
+        }
+
+        gen.insert(block.id, block_gen);
+        kill.insert(block.id, block_kill);
+    }
+
+    let mut liveness = Liveness {
+        live_in: func.blocks.iter().map(|b| (b.id, HashSet::new())).collect(),
+        live_out: func.blocks.iter().map(|b| (b.id, HashSet::new())).collect(),
+    };
+
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+
+        // Going through the blocks backwards makes the sets converge faster
+        for block in func.blocks.iter().rev() {
+            let mut live_out: HashSet<VReg> = HashSet::new();
+            for succ in block.term.successors() {
+                live_out.extend(liveness.live_in[&succ].iter().copied());
+            }
+
+            let mut live_in: HashSet<VReg> =
+                live_out.difference(&kill[&block.id]).copied().collect();
+            live_in.extend(gen[&block.id].iter().copied());
+
+            if live_in != liveness.live_in[&block.id] || live_out != liveness.live_out[&block.id] {
+                changed = true;
+                liveness.live_in.insert(block.id, live_in);
+                liveness.live_out.insert(block.id, live_out);
+            }
+        }
+    }
+
+    liveness
+}
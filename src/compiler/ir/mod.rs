@@ -0,0 +1,645 @@
+/*
+* The intermediate representation (IR) that sits between the AST and the generated ASS code.
+*
+* A NID program is turned into a list of functions, where every function is a control flow graph
+* of basic blocks. Each basic block is a straight list of three-address instructions working on
+* virtual registers, ending in exactly one terminator (jump, branch or return).
+*
+* Variables live in DM, so they are only touched through Load/Store or as a memory operand. This
+* mirrors the ASS instructions, which always take their second operand from DM or as a constant.
+*/
+
+pub mod builder;
+pub mod liveness;
+
+use crate::compiler::stdlib::is_std_function;
+use std::fmt::{self, Display};
+
+/// Variables are identified by the hash given to them by the parser.
+pub type VarId = u32;
+
+/// A virtual register. There is an unlimited amount of these, mapping them to actual hardware
+/// registers is done when lowering the IR to ASS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(pub u32);
+
+/// Identifies a basic block inside of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+/// Second operand of an instruction. Can be read from a register, a constant or straight from the
+/// DM address of a variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(VReg),
+    Const(i16),
+    Var(VarId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl, // Logical shifts. Shifting by a register is turned into a loop by the builder.
+    Shr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    NotEq,
+    LessThan,
+    LessEq,
+    GreatThan,
+    GreatEq,
+}
+
+/// A single three-address instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    LoadConst {
+        dst: VReg,
+        value: i16,
+    },
+    Load {
+        dst: VReg,
+        var: VarId,
+    },
+    Store {
+        var: VarId,
+        src: VReg,
+    },
+    BinOp {
+        op: IrOp,
+        dst: VReg,
+        lhs: VReg,
+        rhs: Operand,
+    },
+    /// Builtin function, such as sleep(). The name is used to find the correct codegen.
+    Builtin {
+        name: String,
+        args: Vec<Operand>,
+    },
+    /// Places 1 in dst if the condition holds and 0 otherwise, eg. `bool alive = hp > 0;`
+    SetCond {
+        dst: VReg,
+        cond: BranchCond,
+    },
+    /// Moves a variable to a fixed address in DM, see stdlib::mem::move_to()
+    MoveTo {
+        var: VarId,
+        src: VReg,
+        addr: u16,
+    },
+    /// Reads the word at the address held in addr, eg. *p
+    LoadPtr {
+        dst: VReg,
+        addr: Operand,
+    },
+    /// Writes src to the address held in addr, eg. *p = x
+    StorePtr {
+        addr: Operand,
+        src: VReg,
+    },
+    /// Inline assembly, passed through untouched.
+    Asm {
+        code: Vec<String>,
+    },
+    /// Calls a function. Arguments are stored to the params of the callee before the call, which
+    /// are listed here so the stores aren't seen as dead. The return value ends up in dst.
+    Call {
+        name: String,
+        params: Vec<VarId>,
+        dst: Option<VReg>,
+    },
+}
+
+/// Condition checked by a branch terminator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BranchCond {
+    Cmp {
+        op: CmpOp,
+        lhs: VReg,
+        rhs: Operand,
+    },
+    /// Builtin checked as a condition, such as is_pressed()
+    Builtin {
+        name: String,
+        args: Vec<Operand>,
+    },
+}
+
+/// The last instruction of every basic block. Decides which block runs next.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        cond: BranchCond,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    Return(Option<Operand>),
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub id: BlockId,
+    pub label: String,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+/// Whether the user asked for a function to be inlined, using #INLINE or #NOINLINE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InlineHint {
+    Default,
+    Always,
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct IrFunction {
+    pub name: String,
+    pub params: Vec<VarId>,
+    pub inline: InlineHint,
+    pub blocks: Vec<BasicBlock>, // Blocks in the order they will be laid out in ASS
+    pub entry: BlockId,
+    pub next_vreg: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct IrProgram {
+    pub functions: Vec<IrFunction>,
+    pub entry_point: Option<usize>, // Index of main() in functions, None when compiling a library
+    pub exports: Vec<String>,       // Functions other object files may call, kept even if unused
+    pub externs: Vec<String>,       // Functions defined in other object files
+    pub prealloc_start: Option<u16>,
+    pub prealloc_end: Option<u16>,
+    pub reserved: Vec<(String, u16, u16)>, // Ranges set aside with #RESERVE name start end
+}
+
+impl IrProgram {
+    /// Prepares the program for being compiled on its own into an object file. Functions from
+    /// namespace, along with main(), are kept and exported. Everything else comes from imported
+    /// files, which are compiled into their own object files. The standard library has no object
+    /// file of its own, so every object keeps its own copy of it without exporting it.
+    pub fn keep_namespace(&mut self, namespace: &str) {
+        let prefix: String = format!("{namespace}.");
+        let main_name: Option<String> = self
+            .entry_point
+            .map(|index| self.functions[index].name.clone());
+
+        let (own, imported): (Vec<IrFunction>, Vec<IrFunction>) =
+            self.functions.drain(..).partition(|func| {
+                func.name.starts_with(&prefix)
+                    || Some(&func.name) == main_name.as_ref()
+                    || is_std_function(&func.name)
+            });
+
+        self.functions = own;
+        self.exports = self
+            .functions
+            .iter()
+            .filter(|func| !is_std_function(&func.name))
+            .map(|func| func.name.clone())
+            .collect();
+        self.externs = imported.into_iter().map(|func| func.name).collect();
+        self.entry_point = main_name.and_then(|main_name| {
+            self.functions
+                .iter()
+                .position(|func| func.name == main_name)
+        });
+    }
+}
+
+impl Inst {
+    /// Returns the virtual register written by this instruction, if any.
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::LoadConst { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::BinOp { dst, .. }
+            | Inst::LoadPtr { dst, .. }
+            | Inst::SetCond { dst, .. } => Some(*dst),
+            Inst::Call { dst, .. } => *dst,
+            _ => None,
+        }
+    }
+
+    /// Returns the virtual registers read by this instruction.
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Inst::Store { src, .. } | Inst::MoveTo { src, .. } => vec![*src],
+            Inst::LoadPtr {
+                addr: Operand::Reg(reg),
+                ..
+            } => vec![*reg],
+            Inst::StorePtr {
+                addr: Operand::Reg(reg),
+                src,
+            } => vec![*reg, *src],
+            Inst::StorePtr { src, .. } => vec![*src],
+            Inst::BinOp { lhs, rhs, .. } => {
+                let mut regs = vec![*lhs];
+                if let Operand::Reg(reg) = rhs {
+                    regs.push(*reg);
+                }
+                regs
+            }
+            Inst::Builtin { args, .. } => reg_args(args),
+            Inst::SetCond { cond, .. } => cond.uses(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replaces every read of one virtual register with another.
+    pub fn replace_use(&mut self, from: VReg, to: VReg) {
+        match self {
+            Inst::Store { src, .. } | Inst::MoveTo { src, .. } => replace_reg(src, from, to),
+            Inst::LoadPtr { addr, .. } => replace_operand(addr, from, to),
+            Inst::StorePtr { addr, src } => {
+                replace_operand(addr, from, to);
+                replace_reg(src, from, to);
+            }
+            Inst::BinOp { lhs, rhs, .. } => {
+                replace_reg(lhs, from, to);
+                replace_operand(rhs, from, to);
+            }
+            Inst::Builtin { args, .. } => {
+                for arg in args.iter_mut() {
+                    replace_operand(arg, from, to);
+                }
+            }
+            Inst::SetCond { cond, .. } => cond.replace_use(from, to),
+            _ => {}
+        }
+    }
+
+    /// Changes the virtual register written by this instruction.
+    pub fn replace_def(&mut self, to: VReg) {
+        match self {
+            Inst::LoadConst { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::BinOp { dst, .. }
+            | Inst::LoadPtr { dst, .. }
+            | Inst::SetCond { dst, .. } => *dst = to,
+            Inst::Call { dst: Some(dst), .. } => *dst = to,
+            _ => panic!("Trying to replace the result of an instruction without one!"),
+        }
+    }
+}
+
+impl Terminator {
+    /// Returns the blocks that can run after this terminator.
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![*then_block, *else_block],
+            Terminator::Return(_) => Vec::new(),
+        }
+    }
+
+    /// Returns the virtual registers read by this terminator.
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            Terminator::Branch { cond, .. } => cond.uses(),
+            Terminator::Return(Some(Operand::Reg(reg))) => vec![*reg],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Replaces every read of one virtual register with another.
+    pub fn replace_use(&mut self, from: VReg, to: VReg) {
+        match self {
+            Terminator::Branch { cond, .. } => cond.replace_use(from, to),
+            Terminator::Return(Some(val)) => replace_operand(val, from, to),
+            _ => {}
+        }
+    }
+}
+
+impl BranchCond {
+    /// Returns the virtual registers read by this condition.
+    pub fn uses(&self) -> Vec<VReg> {
+        match self {
+            BranchCond::Cmp { lhs, rhs, .. } => {
+                let mut regs = vec![*lhs];
+                if let Operand::Reg(reg) = rhs {
+                    regs.push(*reg);
+                }
+                regs
+            }
+            BranchCond::Builtin { args, .. } => reg_args(args),
+        }
+    }
+
+    /// Replaces every read of one virtual register with another.
+    pub fn replace_use(&mut self, from: VReg, to: VReg) {
+        match self {
+            BranchCond::Cmp { lhs, rhs, .. } => {
+                replace_reg(lhs, from, to);
+                replace_operand(rhs, from, to);
+            }
+            BranchCond::Builtin { args, .. } => {
+                for arg in args.iter_mut() {
+                    replace_operand(arg, from, to);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the virtual registers among the arguments of a builtin.
+fn reg_args(args: &[Operand]) -> Vec<VReg> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            Operand::Reg(reg) => Some(*reg),
+            _ => None,
+        })
+        .collect()
+}
+
+fn replace_reg(reg: &mut VReg, from: VReg, to: VReg) {
+    if *reg == from {
+        *reg = to;
+    }
+}
+
+fn replace_operand(operand: &mut Operand, from: VReg, to: VReg) {
+    if *operand == Operand::Reg(from) {
+        *operand = Operand::Reg(to);
+    }
+}
+
+impl IrFunction {
+    /// Returns the block with the given id.
+    pub fn block(&self, id: BlockId) -> &BasicBlock {
+        self.blocks
+            .iter()
+            .find(|block| block.id == id)
+            .expect("Invalid BlockId used in IR!")
+    }
+}
+
+#[cfg(test)]
+impl IrFunction {
+    /// Builds a function from its blocks, given as instructions and a terminator. Used by tests to
+    /// write IR by hand. Block ids follow the order of the blocks, starting at the entry.
+    pub fn from_blocks(name: &str, blocks: Vec<(Vec<Inst>, Terminator)>) -> IrFunction {
+        let blocks: Vec<BasicBlock> = blocks
+            .into_iter()
+            .enumerate()
+            .map(|(i, (insts, term))| BasicBlock {
+                id: BlockId(i),
+                label: format!("{name}_bb{i}"),
+                insts,
+                term,
+            })
+            .collect();
+        let next_vreg: u32 = blocks
+            .iter()
+            .flat_map(|block| {
+                let insts = block
+                    .insts
+                    .iter()
+                    .flat_map(|inst| inst.uses().into_iter().chain(inst.def()));
+                insts.chain(block.term.uses()).collect::<Vec<VReg>>()
+            })
+            .map(|reg| reg.0 + 1)
+            .max()
+            .unwrap_or(0);
+
+        IrFunction {
+            name: name.to_string(),
+            params: Vec::new(),
+            inline: InlineHint::Default,
+            blocks,
+            entry: BlockId(0),
+            next_vreg,
+        }
+    }
+}
+
+/*
+* Pretty printing of the IR, used by --emit ir
+*/
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "{reg}"),
+            Operand::Const(val) => write!(f, "{val}"),
+            Operand::Var(var) => write!(f, "[{var}]"),
+        }
+    }
+}
+
+impl Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::LoadConst { dst, value } => write!(f, "{dst} = {value}"),
+            Inst::Load { dst, var } => write!(f, "{dst} = load [{var}]"),
+            Inst::Store { var, src } => write!(f, "store [{var}], {src}"),
+            Inst::BinOp { op, dst, lhs, rhs } => {
+                write!(
+                    f,
+                    "{dst} = {} {lhs}, {rhs}",
+                    format!("{op:?}").to_lowercase()
+                )
+            }
+            Inst::Builtin { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{name}({})", args.join(", "))
+            }
+            Inst::SetCond { dst, cond } => write!(f, "{dst} = set {cond}"),
+            Inst::MoveTo { var, src, addr } => write!(f, "move_to [{var}], {src} -> {addr}"),
+            Inst::LoadPtr { dst, addr } => write!(f, "{dst} = load *{addr}"),
+            Inst::StorePtr { addr, src } => write!(f, "store *{addr}, {src}"),
+            Inst::Asm { code } => write!(f, "asm {{ {} }}", code.join("; ")),
+            Inst::Call { name, params, dst } => {
+                if let Some(dst) = dst {
+                    write!(f, "{dst} = ")?;
+                }
+                let params: Vec<String> = params.iter().map(|p| format!("[{p}]")).collect();
+                write!(f, "call {name}({})", params.join(", "))
+            }
+        }
+    }
+}
+
+impl Display for BranchCond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchCond::Cmp { op, lhs, rhs } => {
+                write!(f, "{} {lhs}, {rhs}", format!("{op:?}").to_lowercase())
+            }
+            BranchCond::Builtin { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{name}({})", args.join(", "))
+            }
+        }
+    }
+}
+
+impl Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Terminator::Jump(target) => write!(f, "jump {target}"),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => write!(f, "branch {cond} ? {then_block} : {else_block}"),
+            Terminator::Return(Some(val)) => write!(f, "return {val}"),
+            Terminator::Return(None) => write!(f, "return"),
+        }
+    }
+}
+
+impl Display for IrFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self.params.iter().map(|p| format!("[{p}]")).collect();
+        writeln!(f, "fn {}({}):", self.name, params.join(", "))?;
+        for block in &self.blocks {
+            writeln!(f, "  {} ({}):", block.id, block.label)?;
+            for inst in &block.insts {
+                writeln!(f, "    {inst}")?;
+            }
+            writeln!(f, "    {}", block.term)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for IrProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for func in &self.functions {
+            writeln!(f, "{func}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_instructions() {
+        let printed: Vec<String> = [
+            Inst::LoadConst {
+                dst: VReg(0),
+                value: -3,
+            },
+            Inst::Load {
+                dst: VReg(1),
+                var: 7,
+            },
+            Inst::BinOp {
+                op: IrOp::Sub,
+                dst: VReg(2),
+                lhs: VReg(1),
+                rhs: Operand::Var(8),
+            },
+            Inst::Store {
+                var: 7,
+                src: VReg(2),
+            },
+            Inst::Builtin {
+                name: "sleep".to_string(),
+                args: vec![Operand::Const(10)],
+            },
+            Inst::SetCond {
+                dst: VReg(3),
+                cond: BranchCond::Builtin {
+                    name: "is_pressed".to_string(),
+                    args: vec![Operand::Reg(VReg(2))],
+                },
+            },
+            Inst::MoveTo {
+                var: 7,
+                src: VReg(2),
+                addr: 250,
+            },
+        ]
+        .iter()
+        .map(|inst| inst.to_string())
+        .collect();
+
+        assert_eq!(
+            printed,
+            [
+                "v0 = -3",
+                "v1 = load [7]",
+                "v2 = sub v1, [8]",
+                "store [7], v2",
+                "sleep(10)",
+                "v3 = set is_pressed(v2)",
+                "move_to [7], v2 -> 250",
+            ]
+        );
+    }
+
+    #[test]
+    fn prints_functions_block_by_block() {
+        let mut func = IrFunction::from_blocks(
+            "main",
+            vec![
+                (
+                    vec![Inst::Load {
+                        dst: VReg(0),
+                        var: 7,
+                    }],
+                    Terminator::Branch {
+                        cond: BranchCond::Cmp {
+                            op: CmpOp::GreatEq,
+                            lhs: VReg(0),
+                            rhs: Operand::Const(2),
+                        },
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                (Vec::new(), Terminator::Return(Some(Operand::Reg(VReg(0))))),
+                (Vec::new(), Terminator::Return(None)),
+            ],
+        );
+        func.params.push(7);
+
+        assert_eq!(
+            func.to_string().lines().collect::<Vec<&str>>(),
+            [
+                "fn main([7]):",
+                "  bb0 (main_bb0):",
+                "    v0 = load [7]",
+                "    branch greateq v0, 2 ? bb1 : bb2",
+                "  bb1 (main_bb1):",
+                "    return v0",
+                "  bb2 (main_bb2):",
+                "    return",
+            ]
+        );
+    }
+}
@@ -4,6 +4,8 @@
 * parse into an AST.
 */
 
+use super::stdlib::builtins::lookup_builtin;
+use crate::utils::literals::escaped_char;
 use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,23 +23,24 @@ pub enum TokenType {
     CloseScope,       // }
     ArrayAccessOpen,  // [
     ArrayAccessClose, // ]
-    BinaryOperator,   // +, -, *, /
+    BinaryOperator,   // +, -, *, /, %, &, |, ^, <<, >> and ~ in front of a value
     Comparison,       // ==, <=, >=
     LogicOperator,    // !, &&, ||
     TypeIndicator,    // Used to declare variable type and function return
-    Loop,
-    Branch,    // If conditions etc...
-    Seperator, // for identifying seperations for things like parameters (,)
-    Member,    // . representing a field for something like a struct
-    Pointer,   // Same as ptrs in C and C++, points to a memory address
-    Refrence,  // -- || --
-    Return,    // Return statement
-    Asm,       // Allows for inline assembly code
-    Eol,       // End of line, basically ; representing end of line.
+    Loop,             // while, for and do
+    LoopJump,         // break and continue
+    Branch,           // If conditions etc...
+    Seperator,        // for identifying seperations for things like parameters (,)
+    Member,           // . representing a field for something like a struct
+    Pointer,          // Same as ptrs in C and C++, points to a memory address
+    Return,           // Return statement
+    Asm,              // Allows for inline assembly code
+    Eol,              // End of line, basically ; representing end of line.
     Eof, // Represents the end of the code (EOF all caps appears to be a reserved word of some kind)
     Macro, // Basic macro functionality, such as allocating memory that the compiler is not allowed
     // to touch
     BuiltIn, // Built in functions, like sleep(), write_to()
+    Import,  // Imports another file, resolved before parsing
 }
 #[derive(Debug, Clone)]
 pub struct Token {
@@ -61,7 +64,8 @@ pub fn remove_comments(file_content: &str) -> String {
         if let Some(pos) = line.find("//") {
             trimmed_line = &trimmed_line[..pos];
         }
-        new_program.push_str(trimmed_line)
+        new_program.push_str(trimmed_line);
+        new_program.push('\n'); // Keeps words on different lines apart, eg. macros
     }
     new_program
 }
@@ -190,16 +194,39 @@ pub fn tokenize(file_content: String) -> VecDeque<Token> {
             }
 
         /*
-         * Getting refrence and var_name for refrence.
+         * Check for AND
          */
-        } else if current_char == '&' {
+        } else if current_char == '&' && src_code.front() == Some(&'&') {
             src_code.pop_front();
-            let mut token_value: String = String::from("&");
-            let var_name: String = build_word(&mut src_code);
-            token_value.push_str(&var_name);
             token = Token {
-                value: token_value,
-                token_type: TokenType::Refrence,
+                value: String::from("&&"),
+                token_type: TokenType::LogicOperator,
+            }
+
+        /*
+         * Bitwise operators. && and || are checked before & and |.
+         */
+        } else if current_char == '&'
+            || current_char == '^'
+            || current_char == '~'
+            || current_char == '%'
+            || (current_char == '|' && src_code.front() != Some(&'|'))
+        {
+            token = Token {
+                value: String::from(current_char),
+                token_type: TokenType::BinaryOperator,
+            }
+
+        /*
+         * Shifts, << and >>
+         */
+        } else if (current_char == '<' || current_char == '>')
+            && src_code.front() == Some(&current_char)
+        {
+            src_code.pop_front();
+            token = Token {
+                value: format!("{current_char}{current_char}"),
+                token_type: TokenType::BinaryOperator,
             }
 
         /*
@@ -254,10 +281,7 @@ pub fn tokenize(file_content: String) -> VecDeque<Token> {
          * Check for OR
          */
         } else if current_char == '|' {
-            if src_code.pop_front().unwrap() != '|' {
-                panic!("Missing second | in logical OR operation!");
-            }
-
+            src_code.pop_front(); // Remove the second |
             token = Token {
                 value: String::from("||"),
                 token_type: TokenType::LogicOperator,
@@ -278,8 +302,12 @@ pub fn tokenize(file_content: String) -> VecDeque<Token> {
                 token_type: TokenType::String,
             }
         } else if current_char == '\'' {
+            let mut char_value: char = src_code.pop_front().unwrap();
+            if char_value == '\\' {
+                char_value = escaped_char(src_code.pop_front().unwrap());
+            }
             token = Token {
-                value: String::from(src_code.pop_front().unwrap()),
+                value: String::from(char_value),
                 token_type: TokenType::Char,
             };
             if src_code.pop_front().unwrap() != '\'' {
@@ -381,8 +409,13 @@ fn is_reserved_keywords(word: &str) -> Option<TokenType> {
         ("if", TokenType::Branch),
         ("else", TokenType::Branch),
         ("while", TokenType::Loop),
+        ("for", TokenType::Loop),
+        ("do", TokenType::Loop),
+        ("break", TokenType::LoopJump),
+        ("continue", TokenType::LoopJump),
         ("return", TokenType::Return),
         ("asm", TokenType::Asm),
+        ("import", TokenType::Import),
     ]);
 
     if keyword_map.contains_key(word) {
@@ -393,16 +426,7 @@ fn is_reserved_keywords(word: &str) -> Option<TokenType> {
 
 /// Returns if a detected word is a builtin function
 fn is_builtin(word: &str) -> Option<TokenType> {
-    let keyword_map: HashMap<&str, TokenType> = HashMap::from([
-        ("sleep", TokenType::BuiltIn),
-        ("move_to", TokenType::BuiltIn),
-        ("is_pressed", TokenType::BuiltIn),
-    ]);
-
-    if keyword_map.contains_key(word) {
-        return Some(keyword_map[word]);
-    }
-    None
+    lookup_builtin(word).map(|_| TokenType::BuiltIn)
 }
 
 /// Builds a string (a word or number) from a series of chars.
@@ -417,12 +441,16 @@ fn build_word(src_code: &mut VecDeque<char>) -> String {
     string_val
 }
 
-/// Builds a float value for the float TokenType.
+/// Builds a number, which is either a float or an integer literal such as 0x3F00 or 0b1010_0001.
+/// The literal is checked when it is parsed.
 fn build_num(src_code: &mut VecDeque<char>) -> String {
     let mut float_string: String = String::new();
     let found_decimal_points: i8 = 0;
 
-    while is_num(*src_code.front().unwrap()) || *src_code.front().unwrap() == '.' {
+    while src_code
+        .front()
+        .is_some_and(|c| is_num(*c) || is_letter(*c) || *c == '.')
+    {
         if found_decimal_points >= 2 {
             panic!("Too many decimal points found!");
         }
@@ -443,3 +471,35 @@ fn build_string(src_code: &mut VecDeque<char>) -> String {
 
     string_val
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the values of the tokens found in code.
+    fn values(code: &str) -> Vec<String> {
+        tokenize(code.to_string())
+            .into_iter()
+            .map(|token| token.value)
+            .collect()
+    }
+
+    #[test]
+    fn bitwise_operators_are_told_apart_from_logic() {
+        assert_eq!(
+            values("a & b && c | d || ~e ^ f % g;"),
+            ["a", "&", "b", "&&", "c", "|", "d", "||", "~", "e", "^", "f", "%", "g", ";"]
+        );
+        let tokens = tokenize(String::from("a | b || c;"));
+        assert_eq!(tokens[1].token_type, TokenType::BinaryOperator);
+        assert_eq!(tokens[3].token_type, TokenType::LogicOperator);
+    }
+
+    #[test]
+    fn shifts_are_told_apart_from_comparisons() {
+        assert_eq!(
+            values("a << 2 < b >> 1 >= c;"),
+            ["a", "<<", "2", "<", "b", ">>", "1", ">=", "c", ";"]
+        );
+    }
+}
@@ -1,7 +1,9 @@
 pub mod ass_gen;
 pub mod ast;
 pub mod compile;
+pub mod ir;
 pub mod lexer;
+pub mod modules;
 pub mod optimizations;
 pub mod parser;
 pub mod stdlib;
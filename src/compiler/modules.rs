@@ -0,0 +1,598 @@
+/*
+* Loads a NID program spread over several files.
+*
+* `import "physics.nid";` makes the functions of physics.nid available as `physics.name()`. Imports
+* are looked for next to the importing file first, then in the -I include paths in order. Every
+* file is only lexed once no matter how many files import it, and importing a file that is
+* already being imported further up the chain is an error.
+*
+* Imports are resolved on the tokens, before parsing. Functions in an imported file are renamed to
+* `namespace.name`, where the namespace is the file name without .nid, and all the files are then
+* handed to the parser as a single program with the imported files first.
+*
+* Modules of the standard library are imported by name instead, eg. `import heap;`, and are
+* handled like any other imported file. Calling a function from the prelude, such as alloc(),
+* imports its module without asking, and so does using an operator the hardware can only do by
+* calling a runtime function, such as * without a mul instruction.
+*
+* When compiling a file to an object file on its own, the functions of the file itself are put
+* into its namespace as well, so that they have the same name as when the file is imported.
+*/
+
+use super::lexer::{remove_comments, tokenize, Token, TokenType};
+use super::stdlib::{prelude_module, runtime_function, std_module};
+use crate::utils::{hardware_conf::Hardware, nid_fs::read_file};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A file or module of the standard library imported by a file.
+#[derive(Debug, PartialEq)]
+enum Import {
+    File(String),
+    Std(String),
+}
+
+/// Keeps track of the files loaded so far.
+struct Loader<'a> {
+    include_paths: &'a [PathBuf],
+    hardware_conf: &'a Hardware,
+    loaded: HashSet<PathBuf>,
+    namespaces: HashMap<String, PathBuf>, // Namespace of every imported file
+    import_stack: Vec<PathBuf>,           // Files currently being imported, for finding cycles
+    tokens: VecDeque<Token>,
+}
+
+/// Lexes the entry file and every file it imports. Returns the tokens of the whole program. The
+/// entry file is only given a namespace when compiling it to an object file on its own.
+pub fn load_program(
+    entry: &Path,
+    include_paths: &[PathBuf],
+    namespace: Option<&str>,
+    hardware_conf: &Hardware,
+) -> VecDeque<Token> {
+    let mut loader: Loader = Loader {
+        include_paths,
+        hardware_conf,
+        loaded: HashSet::new(),
+        namespaces: HashMap::new(),
+        import_stack: Vec::new(),
+        tokens: VecDeque::new(),
+    };
+    loader.load(entry, namespace);
+    loader.tokens
+}
+
+impl Loader<'_> {
+    /// Loads a single file after the files it imports. The entry file has no namespace.
+    fn load(&mut self, path: &Path, namespace: Option<&str>) {
+        let canonical: PathBuf = fs::canonicalize(path)
+            .unwrap_or_else(|e| panic!("Failed to find {}! Err: {e}", path.display()));
+        self.load_source(path, canonical, read_file(path), namespace);
+    }
+
+    /// Loads a module of the standard library, which is built into nidc.
+    fn load_std(&mut self, name: &str) {
+        let source: &str = std_module(name)
+            .unwrap_or_else(|| panic!("There is no module {name} in the standard library!"));
+        let path: PathBuf = std_path(name);
+        self.load_source(&path, path.clone(), source.to_string(), Some(name));
+    }
+
+    /// Lexes the source of a file and loads everything it imports. canonical identifies the file,
+    /// to only load it once.
+    fn load_source(
+        &mut self,
+        path: &Path,
+        canonical: PathBuf,
+        source: String,
+        namespace: Option<&str>,
+    ) {
+        if let Some(start) = self.import_stack.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = self.import_stack[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|p| p.display().to_string())
+                .collect();
+            panic!("Import cycle found: {}", cycle.join(" -> "));
+        }
+        if !self.loaded.insert(canonical.clone()) {
+            return;
+        }
+        self.import_stack.push(canonical.clone());
+
+        let mut tokens: VecDeque<Token> = tokenize(remove_comments(&source));
+        let mut imports: Vec<Import> = take_imports(&mut tokens, path);
+        let mut std_imports: Vec<&str> = prelude_imports(&tokens);
+        std_imports.append(&mut runtime_imports(&tokens, self.hardware_conf));
+        for module in std_imports {
+            let import: Import = Import::Std(module.to_string());
+            if !imports.contains(&import) {
+                imports.push(import);
+            }
+        }
+
+        let mut imported: HashSet<String> = HashSet::new();
+        for import in imports.iter() {
+            let (import_path, import_namespace, import_canonical): (PathBuf, String, PathBuf) =
+                match import {
+                    Import::File(file) => {
+                        let import_path: PathBuf = self.resolve(path, file);
+                        let import_canonical: PathBuf = fs::canonicalize(&import_path).unwrap();
+                        (
+                            import_path.clone(),
+                            module_namespace(&import_path),
+                            import_canonical,
+                        )
+                    }
+                    Import::Std(name) => (std_path(name), name.clone(), std_path(name)),
+                };
+
+            match self.namespaces.get(&import_namespace) {
+                Some(existing) if *existing != import_canonical => panic!(
+                    "Both {} and {} are imported as {import_namespace}!",
+                    existing.display(),
+                    import_canonical.display()
+                ),
+                _ => {
+                    self.namespaces
+                        .insert(import_namespace.clone(), import_canonical);
+                }
+            }
+
+            match import {
+                Import::File(_) => self.load(&import_path, Some(&import_namespace)),
+                Import::Std(name) => self.load_std(name),
+            }
+            imported.insert(import_namespace);
+        }
+
+        qualify_names(&mut tokens, namespace, &imported, path);
+        self.tokens.extend(tokens);
+        self.import_stack.pop();
+    }
+
+    /// Finds an imported file, first next to the importing file, then in the include paths.
+    fn resolve(&self, importer: &Path, import: &str) -> PathBuf {
+        let importer_dir: PathBuf = importer.parent().unwrap_or(Path::new("")).to_path_buf();
+
+        [importer_dir]
+            .iter()
+            .chain(self.include_paths.iter())
+            .map(|dir| dir.join(import))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| {
+                panic!(
+                    "Could not find {import} imported in {}! Searched next to it and in: {:?}",
+                    importer.display(),
+                    self.include_paths
+                )
+            })
+    }
+}
+
+/// Returns the path shown for a module of the standard library, which also tells it apart from
+/// the files of the program.
+fn std_path(name: &str) -> PathBuf {
+    PathBuf::from(format!("<std>/{name}.nid"))
+}
+
+/// Returns the namespace of an imported file, which is its name without the extension.
+pub fn module_namespace(path: &Path) -> String {
+    let namespace: String = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let is_identifier: bool = namespace
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && namespace.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !is_identifier {
+        panic!(
+            "Can't import {}, the file name has to be a valid identifier!",
+            path.display()
+        );
+    }
+    namespace
+}
+
+/// Removes every `import "file";` and `import module;` from the tokens and returns the imports.
+fn take_imports(tokens: &mut VecDeque<Token>, path: &Path) -> Vec<Import> {
+    let mut imports: Vec<Import> = Vec::new();
+    let mut remaining: VecDeque<Token> = VecDeque::new();
+    let mut depth: usize = 0;
+
+    while let Some(token) = tokens.pop_front() {
+        match token.token_type {
+            TokenType::OpenScope => depth += 1,
+            TokenType::CloseScope => depth = depth.saturating_sub(1),
+            TokenType::Import => {
+                if depth > 0 {
+                    panic!(
+                        "Imports are only allowed outside of functions! In {}",
+                        path.display()
+                    );
+                }
+                let import: Import = match tokens.pop_front() {
+                    Some(file) if file.token_type == TokenType::String => Import::File(file.value),
+                    Some(module) if module.token_type == TokenType::Identifier => {
+                        Import::Std(module.value)
+                    }
+                    _ => panic!(
+                        "Expected file or module name after import in {}!",
+                        path.display()
+                    ),
+                };
+                if tokens.pop_front().map(|eol| eol.token_type) != Some(TokenType::Eol) {
+                    panic!("Missing ; after import in {}!", path.display());
+                }
+                imports.push(import);
+                continue;
+            }
+            _ => {}
+        }
+        remaining.push_back(token);
+    }
+
+    *tokens = remaining;
+    imports
+}
+
+/// Returns the modules of the prelude functions called by a file, unless it defines them itself.
+fn prelude_imports(tokens: &VecDeque<Token>) -> Vec<&'static str> {
+    let defined: HashSet<String> = defined_functions(tokens);
+    let mut modules: Vec<&str> = (0..tokens.len())
+        .filter(|index| is_call_or_definition(tokens, *index))
+        .filter(|index| !defined.contains(&tokens[*index].value))
+        .filter_map(|index| prelude_module(&tokens[index].value))
+        .collect();
+    modules.dedup();
+    modules
+}
+
+/// Returns the modules of the runtime functions needed for the operators used in a file.
+fn runtime_imports(tokens: &VecDeque<Token>, hardware_conf: &Hardware) -> Vec<&'static str> {
+    let mut modules: Vec<&str> = (1..tokens.len())
+        .filter(|index| is_binary_operator(tokens, *index))
+        .filter_map(|index| runtime_function(&tokens[index].value, hardware_conf))
+        .filter_map(|function| function.split_once('.').map(|(module, _)| module))
+        .collect();
+    modules.dedup();
+    modules
+}
+
+/// Returns whether the token at index is a binary operator. * is also used to dereference, which is
+/// only a multiplication when it follows a value.
+fn is_binary_operator(tokens: &VecDeque<Token>, index: usize) -> bool {
+    tokens[index].token_type == TokenType::BinaryOperator
+        && matches!(
+            tokens[index - 1].token_type,
+            TokenType::Integer
+                | TokenType::Char
+                | TokenType::Bool
+                | TokenType::Identifier
+                | TokenType::Pointer
+                | TokenType::CloseParen
+                | TokenType::ArrayAccessClose
+        )
+}
+
+/// Returns the names of the functions defined in a file, which are preceded by their return type.
+fn defined_functions(tokens: &VecDeque<Token>) -> HashSet<String> {
+    (1..tokens.len())
+        .filter(|index| is_call_or_definition(tokens, *index))
+        .filter(|index| tokens[index - 1].token_type == TokenType::TypeIndicator)
+        .map(|index| tokens[index].value.clone())
+        .collect()
+}
+
+/// Joins `namespace . name` into a single identifier, then puts the functions defined in the file
+/// into its namespace. Calls into other namespaces are checked against the files it imports.
+fn qualify_names(
+    tokens: &mut VecDeque<Token>,
+    namespace: Option<&str>,
+    imported: &HashSet<String>,
+    path: &Path,
+) {
+    let mut joined: VecDeque<Token> = VecDeque::new();
+    while let Some(token) = tokens.pop_front() {
+        let is_qualified: bool = token.token_type == TokenType::Identifier
+            && tokens
+                .front()
+                .is_some_and(|t| t.token_type == TokenType::Member)
+            && tokens
+                .get(1)
+                .is_some_and(|t| t.token_type == TokenType::Identifier);
+
+        if is_qualified {
+            tokens.pop_front(); // Remove .
+            let name: Token = tokens.pop_front().unwrap();
+            joined.push_back(Token {
+                value: format!("{}.{}", token.value, name.value),
+                token_type: TokenType::Identifier,
+            });
+        } else {
+            joined.push_back(token);
+        }
+    }
+    *tokens = joined;
+
+    let defined: HashSet<String> = defined_functions(tokens);
+
+    for index in 0..tokens.len() {
+        if !is_call_or_definition(tokens, index) {
+            continue;
+        }
+
+        let name: &str = &tokens[index].value;
+        if let Some((called_namespace, _)) = name.split_once('.') {
+            if !imported.contains(called_namespace) {
+                panic!(
+                    "{name}() is called in {}, but {called_namespace} is not imported there!",
+                    path.display()
+                );
+            }
+        } else if let Some(module) = prelude_module(name).filter(|_| !defined.contains(name)) {
+            tokens[index].value = format!("{module}.{name}");
+        } else if let Some(namespace) = namespace {
+            // main() is where execution starts, no matter which file it is in
+            if defined.contains(name) && name != "main" {
+                tokens[index].value = format!("{namespace}.{name}");
+            }
+        }
+    }
+}
+
+/// Returns whether the token at index names a function, by being followed by (.
+fn is_call_or_definition(tokens: &VecDeque<Token>, index: usize) -> bool {
+    tokens[index].token_type == TokenType::Identifier
+        && tokens
+            .get(index + 1)
+            .is_some_and(|t| t.token_type == TokenType::OpenParen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes the files into a fresh directory under the system temp dir and returns it.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir: PathBuf = std::env::temp_dir().join(format!("nidc_{test}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, source) in files {
+            let path: PathBuf = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    /// Loads main.nid from the directory and returns the identifiers found in the program.
+    fn identifiers(dir: &Path, include_paths: &[PathBuf]) -> Vec<String> {
+        load_program(
+            &dir.join("main.nid"),
+            include_paths,
+            None,
+            &Hardware::default(),
+        )
+        .into_iter()
+        .filter(|token| token.token_type == TokenType::Identifier)
+        .map(|token| token.value)
+        .collect()
+    }
+
+    #[test]
+    fn imported_functions_are_namespaced() {
+        let dir = write_files(
+            "namespaced",
+            &[
+                (
+                    "main.nid",
+                    "import \"physics.nid\";\nvoid main() {\nint g = physics.gravity();\n}",
+                ),
+                ("physics.nid", "int gravity() {\nreturn 10;\n}"),
+            ],
+        );
+        assert_eq!(
+            identifiers(&dir, &[]),
+            ["physics.gravity", "main", "g", "physics.gravity"]
+        );
+    }
+
+    #[test]
+    fn looks_next_to_the_importer_before_include_paths() {
+        let dir = write_files(
+            "include_paths",
+            &[
+                (
+                    "main.nid",
+                    "import \"a.nid\";\nimport \"b.nid\";\nvoid main() {}",
+                ),
+                ("a.nid", "void local() {}"),
+                ("lib/a.nid", "void shadowed() {}"),
+                ("lib/b.nid", "void from_lib() {}"),
+            ],
+        );
+        assert_eq!(
+            identifiers(&dir, &[dir.join("lib")]),
+            ["a.local", "b.from_lib", "main"]
+        );
+    }
+
+    #[test]
+    fn files_imported_twice_are_loaded_once() {
+        let dir = write_files(
+            "diamond",
+            &[
+                (
+                    "main.nid",
+                    "import \"left.nid\";\nimport \"right.nid\";\nvoid main() {}",
+                ),
+                ("left.nid", "import \"shared.nid\";\nvoid l() {}"),
+                ("right.nid", "import \"shared.nid\";\nvoid r() {}"),
+                ("shared.nid", "void s() {}"),
+            ],
+        );
+        assert_eq!(
+            identifiers(&dir, &[]),
+            ["shared.s", "left.l", "right.r", "main"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Import cycle found")]
+    fn import_cycles_are_rejected() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("main.nid", "import \"a.nid\";\nvoid main() {}"),
+                ("a.nid", "import \"b.nid\";\nvoid a() {}"),
+                ("b.nid", "import \"a.nid\";\nvoid b() {}"),
+            ],
+        );
+        identifiers(&dir, &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "physics is not imported there")]
+    fn calls_into_files_not_imported_are_rejected() {
+        let dir = write_files(
+            "not_imported",
+            &[("main.nid", "void main() {\nphysics.gravity();\n}")],
+        );
+        identifiers(&dir, &[]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Imports are only allowed outside of functions")]
+    fn imports_inside_functions_are_rejected() {
+        let dir = write_files(
+            "nested_import",
+            &[
+                ("main.nid", "void main() {\nimport \"a.nid\";\n}"),
+                ("a.nid", "void a() {}"),
+            ],
+        );
+        identifiers(&dir, &[]);
+    }
+
+    #[test]
+    fn object_files_put_their_own_functions_in_their_namespace() {
+        let dir = write_files(
+            "own_namespace",
+            &[(
+                "main.nid",
+                "int step() {\nreturn 1;\n}\nvoid main() {\nstep();\n}",
+            )],
+        );
+        let names: Vec<String> = load_program(
+            &dir.join("main.nid"),
+            &[],
+            Some("game"),
+            &Hardware::default(),
+        )
+        .into_iter()
+        .filter(|token| token.token_type == TokenType::Identifier)
+        .map(|token| token.value)
+        .collect();
+        assert_eq!(names, ["game.step", "main", "game.step"]);
+    }
+
+    /// Returns the functions defined in the program, which are preceded by their return type.
+    fn functions(dir: &Path) -> Vec<String> {
+        functions_on(dir, &Hardware::default())
+    }
+
+    /// Returns the functions defined in the program when compiled for hardware_conf.
+    fn functions_on(dir: &Path, hardware_conf: &Hardware) -> Vec<String> {
+        let tokens: Vec<Token> =
+            load_program(&dir.join("main.nid"), &[], None, hardware_conf).into();
+        tokens
+            .windows(2)
+            .filter(|pair| pair[0].token_type == TokenType::TypeIndicator)
+            .filter(|pair| pair[1].token_type == TokenType::Identifier)
+            .map(|pair| pair[1].value.clone())
+            .filter(|name| name.contains('.') || name == "main")
+            .collect()
+    }
+
+    #[test]
+    fn std_modules_are_imported_by_name() {
+        let dir = write_files(
+            "std_import",
+            &[(
+                "main.nid",
+                "import mem;\nvoid main() {\nmem.fill(100, 4, 0);\n}",
+            )],
+        );
+        assert_eq!(functions(&dir), ["mem.fill", "mem.copy", "main"]);
+    }
+
+    #[test]
+    fn prelude_functions_import_their_module() {
+        let dir = write_files(
+            "prelude",
+            &[("main.nid", "void main() {\nint p = alloc(2);\nfree(p);\n}")],
+        );
+        assert_eq!(
+            functions(&dir),
+            ["heap.init", "heap.alloc", "heap.free", "main"]
+        );
+        let calls: Vec<String> = identifiers(&dir, &[]);
+        assert!(calls.contains(&String::from("heap.alloc")));
+        assert!(!calls.contains(&String::from("alloc")));
+    }
+
+    #[test]
+    fn files_may_define_functions_named_like_the_prelude() {
+        let dir = write_files(
+            "prelude_shadowed",
+            &[(
+                "main.nid",
+                "int alloc(int size) {\nreturn 0;\n}\nvoid main() {\nint p = alloc(2);\n}",
+            )],
+        );
+        assert_eq!(functions(&dir), ["main"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no module sound in the standard library!")]
+    fn unknown_std_modules_are_rejected() {
+        let dir = write_files(
+            "std_unknown",
+            &[("main.nid", "import sound;\nvoid main() {\n}")],
+        );
+        load_program(&dir.join("main.nid"), &[], None, &Hardware::default());
+    }
+
+    #[test]
+    fn operators_without_an_instruction_import_math() {
+        let dir = write_files(
+            "runtime_math",
+            &[("main.nid", "void main() {\nint x = 3;\nint y = x * 5;\n}")],
+        );
+        assert_eq!(functions(&dir), ["main"]);
+
+        let no_mul = Hardware {
+            has_mul: false,
+            ..Hardware::default()
+        };
+        assert!(functions_on(&dir, &no_mul).contains(&String::from("math.mul")));
+    }
+
+    #[test]
+    fn dereferencing_is_not_multiplying() {
+        let dir = write_files(
+            "runtime_deref",
+            &[("main.nid", "void main() {\nint p = 100;\nint x = *p;\n}")],
+        );
+        let no_mul = Hardware {
+            has_mul: false,
+            ..Hardware::default()
+        };
+        assert_eq!(functions_on(&dir, &no_mul), ["main"]);
+    }
+}
@@ -0,0 +1,398 @@
+/*
+* Caches values from DM in registers, working directly on the generated ASS code.
+*
+* Codegen stores every assignment to DM and loads variables again right before using them, even
+* when the value is still sitting in a register. This pass keeps track of which registers hold the
+* same value as which DM address, and removes:
+* - Loads of a value that is already in the register.
+* - Stores of a value that DM already holds.
+* - Stores that are overwritten before anything reads them.
+*
+* What is known about the registers flows across jumps and branches, so a value loaded before a
+* loop is still known inside of it as long as nothing in the loop changes it. Everything is
+* forgotten at calls, routine labels and inline assembly, since any of them might change both
+* registers and DM.
+*
+* Only addresses handed out by the compiler are cached. Anything else, such as preallocated
+* memory or addresses used with move_to(), might change or be read without the compiler knowing.
+*/
+
+use crate::compiler::ass_gen::instruction_parser::{ASM_BLOCK_END, ASM_BLOCK_START};
+use crate::compiler::ass_gen::memory_manager::{is_compiler_addr, parse_addr};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Pairs of registers and DM addresses that are known to hold the same value.
+type Cache = BTreeSet<(u8, u16)>;
+
+/// What an ASS instruction does, as far as caching is concerned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line {
+    Label(String),
+    RoutineLabel,
+    Load { reg: u8, addr: u16 },
+    Store { reg: u8, addr: u16 },
+    WriteReg { reg: u8, read: Option<u16> }, // Changes a register, optionally reading DM
+    Read(Option<u16>),                       // Leaves registers and DM alone
+    Jump(String),
+    Branch(String),
+    Call,
+    Ret,
+    Comment,
+    Unknown, // Inline assembly or anything not understood, assumed to change everything
+}
+
+/// A straight run of lines only entered at the top.
+struct Block {
+    start: usize,
+    end: usize, // Exclusive
+    successors: Vec<usize>,
+    is_entry: bool, // Can be entered without coming from another block
+}
+
+/// Removes redundant loads and stores from the ASS program. Returns the number of removed lines.
+pub fn cache_memory(program: &mut Vec<String>) -> usize {
+    let old_len: usize = program.len();
+
+    // Redundant loads and stores are removed first, as removing them might leave more stores
+    // without readers.
+    let lines: Vec<Line> = parse_lines(program);
+    let redundant: HashSet<usize> = find_redundant(&lines);
+    remove_lines(program, &redundant);
+
+    let lines: Vec<Line> = parse_lines(program);
+    let dead: HashSet<usize> = find_dead_stores(&lines);
+    remove_lines(program, &dead);
+
+    old_len - program.len()
+}
+
+fn remove_lines(program: &mut Vec<String>, remove: &HashSet<usize>) {
+    let mut index: usize = 0;
+    program.retain(|_| {
+        index += 1;
+        !remove.contains(&(index - 1))
+    });
+}
+
+/// Parses every line of the program. Everything inside asm {} is marked as unknown.
+fn parse_lines(program: &[String]) -> Vec<Line> {
+    let mut in_asm: bool = false;
+
+    program
+        .iter()
+        .map(|line| {
+            if line == ASM_BLOCK_START {
+                in_asm = true;
+                Line::Unknown
+            } else if line == ASM_BLOCK_END {
+                in_asm = false;
+                Line::Unknown
+            } else if in_asm {
+                Line::Unknown
+            } else {
+                parse_line(line)
+            }
+        })
+        .collect()
+}
+
+/// Parses a single line of generated ASS, eg. `ld, r0, 4` or `jmp #label`.
+fn parse_line(line: &str) -> Line {
+    let line: &str = line.trim();
+    if line.is_empty() || line.starts_with(';') {
+        return Line::Comment;
+    }
+    if line.ends_with(':') {
+        return Line::RoutineLabel;
+    }
+
+    let parts: Vec<&str> = line
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect();
+    let reg = |index: usize| -> Option<u8> { parts.get(index)?.strip_prefix('r')?.parse().ok() };
+    let addr = |index: usize| -> Option<u16> { parse_addr(parts.get(index)?) };
+
+    match (parts[0], parts.len()) {
+        (label, 1) if label.starts_with('#') => Line::Label(label.to_string()),
+        ("ld", 3) => match (reg(1), addr(2)) {
+            (Some(reg), Some(addr)) => Line::Load { reg, addr },
+            _ => Line::Unknown,
+        },
+        ("st", 3) => match (reg(1), addr(2)) {
+            (Some(reg), Some(addr)) => Line::Store { reg, addr },
+            _ => Line::Unknown,
+        },
+        ("add" | "sub" | "mul" | "div" | "and" | "or" | "xor" | "not", 3) => match reg(1) {
+            Some(reg) => Line::WriteReg { reg, read: addr(2) },
+            None => Line::Unknown,
+        },
+        ("ldi" | "addi" | "subi" | "muli" | "divi" | "andi" | "ori" | "xori", 3)
+        | ("lsl" | "lsr", 2) => match reg(1) {
+            Some(reg) => Line::WriteReg { reg, read: None },
+            None => Line::Unknown,
+        },
+        ("cmp", 3) => Line::Read(addr(2)),
+        ("cmpi", 3) | ("wait" | "kbd", 2) | ("nop", 1) => Line::Read(None),
+        ("jmp", 2) => Line::Jump(parts[1].to_string()),
+        ("beq" | "bne" | "blt" | "bge" | "bpr" | "bnr" | "byk", 2) => {
+            Line::Branch(parts[1].to_string())
+        }
+        ("call", 2) => Line::Call,
+        ("ret", 1) => Line::Ret,
+        _ => Line::Unknown,
+    }
+}
+
+/// Splits the program into blocks at labels and after jumps, and links them together.
+fn build_blocks(lines: &[Line]) -> Vec<Block> {
+    let mut starts: Vec<usize> = vec![0];
+    for (index, line) in lines.iter().enumerate() {
+        match line {
+            Line::Label(_) | Line::RoutineLabel => starts.push(index),
+            Line::Jump(_) | Line::Branch(_) | Line::Ret => starts.push(index + 1),
+            _ => {}
+        }
+    }
+    starts.retain(|start| *start < lines.len());
+    starts.dedup();
+
+    let label_blocks: HashMap<&str, usize> = starts
+        .iter()
+        .enumerate()
+        .filter_map(|(block, start)| match &lines[*start] {
+            Line::Label(label) => Some((label.as_str(), block)),
+            _ => None,
+        })
+        .collect();
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(block, start)| {
+            let end: usize = starts.get(block + 1).copied().unwrap_or(lines.len());
+            let next: Option<usize> = (block + 1 < starts.len()).then_some(block + 1);
+
+            let successors: Vec<usize> = match &lines[end - 1] {
+                Line::Jump(label) => label_blocks
+                    .get(label.as_str())
+                    .copied()
+                    .into_iter()
+                    .collect(),
+                Line::Branch(label) => label_blocks
+                    .get(label.as_str())
+                    .copied()
+                    .into_iter()
+                    .chain(next)
+                    .collect(),
+                Line::Ret => Vec::new(),
+                _ => next.into_iter().collect(),
+            };
+
+            Block {
+                start: *start,
+                end,
+                successors,
+                is_entry: block == 0 || lines[*start] == Line::RoutineLabel,
+            }
+        })
+        .collect()
+}
+
+/// Updates the cache with the effect of a line. Returns true if the line does nothing, because
+/// the register or DM already holds the value it moves.
+fn apply_line(line: &Line, cache: &mut Cache) -> bool {
+    match line {
+        Line::Load { reg, addr } => {
+            if !is_compiler_addr(*addr) {
+                forget_reg(cache, *reg);
+                return false;
+            }
+            if cache.contains(&(*reg, *addr)) {
+                return true;
+            }
+            forget_reg(cache, *reg);
+            cache.insert((*reg, *addr));
+        }
+        Line::Store { reg, addr } => {
+            if !is_compiler_addr(*addr) {
+                return false;
+            }
+            if cache.contains(&(*reg, *addr)) {
+                return true;
+            }
+            cache.retain(|(_, cached)| cached != addr);
+            cache.insert((*reg, *addr));
+        }
+        Line::WriteReg { reg, .. } => forget_reg(cache, *reg),
+        Line::RoutineLabel | Line::Call | Line::Unknown => cache.clear(),
+        _ => {}
+    }
+    false
+}
+
+fn forget_reg(cache: &mut Cache, reg: u8) {
+    cache.retain(|(cached, _)| *cached != reg);
+}
+
+/// Finds the loads and stores that move a value to where it already is.
+fn find_redundant(lines: &[Line]) -> HashSet<usize> {
+    let blocks: Vec<Block> = build_blocks(lines);
+
+    // None means no way to reach the block has been found yet
+    let mut block_in: Vec<Option<Cache>> = blocks
+        .iter()
+        .map(|block| block.is_entry.then(Cache::new))
+        .collect();
+    let mut worklist: Vec<usize> = (0..blocks.len())
+        .filter(|block| block_in[*block].is_some())
+        .collect();
+
+    while let Some(index) = worklist.pop() {
+        let block: &Block = &blocks[index];
+        let mut cache: Cache = block_in[index].clone().unwrap();
+        for line in lines[block.start..block.end].iter() {
+            apply_line(line, &mut cache);
+        }
+
+        for succ in block.successors.iter() {
+            let new_in: Cache = match &block_in[*succ] {
+                // Only what every way into the block agrees on is known
+                Some(old_in) => old_in.intersection(&cache).copied().collect(),
+                None => cache.clone(),
+            };
+            if block_in[*succ].as_ref() != Some(&new_in) {
+                block_in[*succ] = Some(new_in);
+                worklist.push(*succ);
+            }
+        }
+    }
+
+    let mut redundant: HashSet<usize> = HashSet::new();
+    for (block, input) in blocks.iter().zip(block_in) {
+        // Unreachable code is left alone
+        let Some(mut cache) = input else {
+            continue;
+        };
+        for (index, line) in lines.iter().enumerate().take(block.end).skip(block.start) {
+            if apply_line(line, &mut cache) {
+                redundant.insert(index);
+            }
+        }
+    }
+    redundant
+}
+
+/// Finds stores that are overwritten later in the same block without being read first.
+fn find_dead_stores(lines: &[Line]) -> HashSet<usize> {
+    let mut dead: HashSet<usize> = HashSet::new();
+
+    for block in build_blocks(lines) {
+        // Addresses that are written before being read, from the current line to the block end
+        let mut overwritten: HashSet<u16> = HashSet::new();
+
+        for index in (block.start..block.end).rev() {
+            match &lines[index] {
+                Line::Store { addr, .. }
+                    if is_compiler_addr(*addr) && !overwritten.insert(*addr) =>
+                {
+                    dead.insert(index);
+                }
+                Line::Load { addr, .. }
+                | Line::WriteReg {
+                    read: Some(addr), ..
+                }
+                | Line::Read(Some(addr)) => {
+                    overwritten.remove(addr);
+                }
+                Line::Call | Line::Unknown | Line::RoutineLabel => overwritten.clear(),
+                _ => {}
+            }
+        }
+    }
+
+    dead
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ass_gen::memory_manager::set_memory_layout;
+    use crate::utils::hardware_conf::{MemRegion, RegionKind};
+
+    /// Caches the program with globals at 0..=199 and memory mapped I/O at 200..=255.
+    fn cache(program: &[&str]) -> Vec<String> {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        set_memory_layout(vec![
+            region("globals", RegionKind::Globals, 0, 199),
+            region("io", RegionKind::Mmio, 200, 255),
+        ]);
+
+        let mut program: Vec<String> = program.iter().map(|line| line.to_string()).collect();
+        cache_memory(&mut program);
+        program
+    }
+
+    #[test]
+    fn removes_reloads_of_cached_values() {
+        assert_eq!(
+            cache(&["ld r0, 10", "st r0, 11", "ld r0, 10", "st r0, 11"]),
+            ["ld r0, 10", "st r0, 11"]
+        );
+        // Variables are the same address, whether written with @ or not
+        assert_eq!(
+            cache(&["ld r0, @10", "st r0, @11", "ld r0, 10", "add r0, @11"]),
+            ["ld r0, @10", "st r0, @11", "add r0, @11"]
+        );
+    }
+
+    #[test]
+    fn keeps_only_what_every_predecessor_agrees_on() {
+        let program = [
+            "ld r0, 10",
+            "bne #other",
+            "ld r1, 11",
+            "jmp #join",
+            "#other",
+            "ld r1, 12",
+            "#join",
+            "ld r0, 10", // Same on both ways in
+            "ld r1, 11", // Only known coming from the first side
+            "ret",
+        ];
+        let mut expected: Vec<&str> = program.to_vec();
+        expected.remove(7);
+        assert_eq!(cache(&program), expected);
+    }
+
+    #[test]
+    fn forgets_everything_at_asm_blocks() {
+        let program = [
+            "ld r0, 10",
+            ASM_BLOCK_START,
+            "nop",
+            ASM_BLOCK_END,
+            "ld r0, 10",
+        ];
+        assert_eq!(cache(&program), program);
+    }
+
+    #[test]
+    fn keeps_loads_and_stores_outside_compiler_memory() {
+        let program = ["ld r0, 210", "ld r0, 210", "st r0, 211", "st r0, 211"];
+        assert_eq!(cache(&program), program);
+    }
+
+    #[test]
+    fn removes_stores_overwritten_before_being_read() {
+        assert_eq!(
+            cache(&["ldi r0, 1", "st r0, 10", "ldi r0, 2", "st r0, 10"]),
+            ["ldi r0, 1", "ldi r0, 2", "st r0, 10"]
+        );
+    }
+}
@@ -0,0 +1,506 @@
+/*
+* Constant propagation and folding.
+*
+* Keeps track of which variables and virtual registers are known to hold a constant value at every
+* point in the program. Known values are then used in place of loads, calculations between
+* constants are done at compile time and branches with a known outcome are turned into jumps.
+*
+* Variable values are propagated between blocks as a forward dataflow problem. A variable is only
+* known at the start of a block if it holds the same value at the end of every block jumping to it.
+*
+* Writes through a pointer can reach the variables too, since they live in the globals regions of
+* DM. Everything known is forgotten after them, unless they go to a constant address outside of
+* those regions.
+*/
+
+use crate::compiler::ir::builder::{compare, fold_op};
+use crate::compiler::ir::{
+    BlockId, BranchCond, Inst, IrFunction, IrProgram, Operand, Terminator, VReg, VarId,
+};
+use crate::utils::hardware_conf::{MemRegion, RegionKind};
+use std::collections::{HashMap, HashSet};
+
+/// Known constant values of variables at a point in the program.
+type ConstMap = HashMap<VarId, i16>;
+
+/// Runs constant propagation and folding on every function in the program. regions is the layout
+/// of DM, used to tell which pointer writes may change a variable.
+pub fn fold_constants(program: &mut IrProgram, regions: &[MemRegion]) {
+    for func in program.functions.iter_mut() {
+        fold_function(func, regions);
+    }
+}
+
+/// Runs constant propagation and folding on a single function.
+pub fn fold_function(func: &mut IrFunction, regions: &[MemRegion]) {
+    let block_inputs: HashMap<BlockId, ConstMap> = propagate_constants(func, regions);
+    let mut reg_consts: HashMap<VReg, i16> = HashMap::new();
+
+    for block in func.blocks.iter_mut() {
+        // Blocks that are never reached have no input, leave them for dead code elimination
+        let Some(input) = block_inputs.get(&block.id) else {
+            continue;
+        };
+        let mut var_consts: ConstMap = input.clone();
+
+        for inst in block.insts.iter_mut() {
+            rewrite_inst(inst, &mut var_consts, &mut reg_consts, regions);
+        }
+        rewrite_terminator(&mut block.term, &var_consts, &reg_consts);
+    }
+
+    remove_unused_regs(func);
+}
+
+/// Finds the known variable values at the start of every reachable block.
+fn propagate_constants(func: &IrFunction, regions: &[MemRegion]) -> HashMap<BlockId, ConstMap> {
+    let mut block_inputs: HashMap<BlockId, ConstMap> = HashMap::new();
+    let mut block_outputs: HashMap<BlockId, ConstMap> = HashMap::new();
+
+    // Nothing is known about the variables when entering a function
+    block_inputs.insert(func.entry, ConstMap::new());
+    let mut worklist: Vec<BlockId> = vec![func.entry];
+
+    while let Some(id) = worklist.pop() {
+        let block = func.block(id);
+
+        let mut var_consts: ConstMap = block_inputs[&id].clone();
+        let mut reg_consts: HashMap<VReg, i16> = HashMap::new();
+        for inst in block.insts.iter() {
+            let mut inst = inst.clone();
+            rewrite_inst(&mut inst, &mut var_consts, &mut reg_consts, regions);
+        }
+
+        if block_outputs.get(&id) == Some(&var_consts) {
+            continue;
+        }
+
+        // Only follow the branches that can actually be taken
+        let mut term = block.term.clone();
+        rewrite_terminator(&mut term, &var_consts, &reg_consts);
+
+        for succ in term.successors() {
+            let new_input: ConstMap = match block_inputs.get(&succ) {
+                // A variable stays known only if all incoming blocks agree on its value
+                Some(old_input) => old_input
+                    .iter()
+                    .filter(|(var, val)| var_consts.get(var) == Some(val))
+                    .map(|(var, val)| (*var, *val))
+                    .collect(),
+                None => var_consts.clone(),
+            };
+
+            if block_inputs.get(&succ) != Some(&new_input) || !block_outputs.contains_key(&succ) {
+                block_inputs.insert(succ, new_input);
+                worklist.push(succ);
+            }
+        }
+        block_outputs.insert(id, var_consts);
+    }
+
+    block_inputs
+}
+
+/// Replaces everything with a known value in an instruction with constants, and updates what is
+/// known after the instruction has run.
+fn rewrite_inst(
+    inst: &mut Inst,
+    var_consts: &mut ConstMap,
+    reg_consts: &mut HashMap<VReg, i16>,
+    regions: &[MemRegion],
+) {
+    match inst {
+        Inst::LoadConst { dst, value } => {
+            reg_consts.insert(*dst, *value);
+        }
+        Inst::Load { dst, var } => {
+            if let Some(value) = var_consts.get(var) {
+                reg_consts.insert(*dst, *value);
+                *inst = Inst::LoadConst {
+                    dst: *dst,
+                    value: *value,
+                };
+            }
+        }
+        Inst::Store { var, src } => match reg_consts.get(src) {
+            Some(value) => {
+                var_consts.insert(*var, *value);
+            }
+            None => {
+                var_consts.remove(var);
+            }
+        },
+        Inst::BinOp { op, dst, lhs, rhs } => {
+            *rhs = known_operand(rhs, var_consts, reg_consts);
+
+            if let (Some(left), Operand::Const(right)) = (reg_consts.get(lhs), *rhs) {
+                if let Some(value) = fold_op(*op, *left, right) {
+                    reg_consts.insert(*dst, value);
+                    *inst = Inst::LoadConst { dst: *dst, value };
+                }
+            }
+        }
+        Inst::Builtin { args, .. } => {
+            for arg in args.iter_mut() {
+                *arg = known_operand(arg, var_consts, reg_consts);
+            }
+        }
+        Inst::SetCond { dst, cond } => {
+            if let Some(holds) = rewrite_cond(cond, var_consts, reg_consts) {
+                let value: i16 = holds as i16;
+                reg_consts.insert(*dst, value);
+                *inst = Inst::LoadConst { dst: *dst, value };
+            }
+        }
+        Inst::MoveTo { var, .. } => {
+            var_consts.remove(var);
+        }
+        Inst::LoadPtr { addr, .. } => {
+            *addr = known_operand(addr, var_consts, reg_consts);
+        }
+        Inst::StorePtr { addr, .. } => {
+            *addr = known_operand(addr, var_consts, reg_consts);
+            let outside_globals: bool = match addr {
+                Operand::Const(addr) => !regions.iter().any(|region| {
+                    region.kind == RegionKind::Globals && region.contains(*addr as u16)
+                }),
+                _ => false,
+            };
+            // The pointer may point at any of the variables
+            if !outside_globals {
+                var_consts.clear();
+            }
+        }
+        Inst::Asm { .. } => {
+            // Inline assembly can write anywhere in DM
+            var_consts.clear();
+        }
+        Inst::Call { .. } => {
+            // Functions can't be recursive, so the callee never touches the variables of this
+            // function. The return value is unknown.
+        }
+    }
+}
+
+/// Replaces known operands in a terminator, and turns branches with a known outcome into jumps.
+fn rewrite_terminator(
+    term: &mut Terminator,
+    var_consts: &ConstMap,
+    reg_consts: &HashMap<VReg, i16>,
+) {
+    match term {
+        Terminator::Branch {
+            cond,
+            then_block,
+            else_block,
+        } => {
+            if let Some(holds) = rewrite_cond(cond, var_consts, reg_consts) {
+                let target: BlockId = if holds { *then_block } else { *else_block };
+                *term = Terminator::Jump(target);
+            }
+        }
+        Terminator::Return(Some(val)) => *val = known_operand(val, var_consts, reg_consts),
+        _ => {}
+    }
+}
+
+/// Replaces known operands in a condition. Returns whether it holds, if that is known.
+fn rewrite_cond(
+    cond: &mut BranchCond,
+    var_consts: &ConstMap,
+    reg_consts: &HashMap<VReg, i16>,
+) -> Option<bool> {
+    match cond {
+        BranchCond::Cmp { op, lhs, rhs } => {
+            *rhs = known_operand(rhs, var_consts, reg_consts);
+            match (reg_consts.get(lhs), *rhs) {
+                (Some(left), Operand::Const(right)) => Some(compare(*op, *left, right)),
+                _ => None,
+            }
+        }
+        BranchCond::Builtin { args, .. } => {
+            for arg in args.iter_mut() {
+                *arg = known_operand(arg, var_consts, reg_consts);
+            }
+            None
+        }
+    }
+}
+
+/// Returns the operand as a constant if its value is known.
+fn known_operand(
+    operand: &Operand,
+    var_consts: &ConstMap,
+    reg_consts: &HashMap<VReg, i16>,
+) -> Operand {
+    let known: Option<&i16> = match operand {
+        Operand::Reg(reg) => reg_consts.get(reg),
+        Operand::Var(var) => var_consts.get(var),
+        Operand::Const(_) => None,
+    };
+
+    match known {
+        Some(value) => Operand::Const(*value),
+        None => *operand,
+    }
+}
+
+/// Removes instructions calculating values into registers that are never read. Folding leaves a
+/// lot of these behind, such as loads of variables whose value ended up being used as a constant.
+pub fn remove_unused_regs(func: &mut IrFunction) {
+    loop {
+        let mut used: HashSet<VReg> = HashSet::new();
+        for block in func.blocks.iter() {
+            for inst in block.insts.iter() {
+                used.extend(inst.uses());
+            }
+            used.extend(block.term.uses());
+        }
+
+        let mut removed: bool = false;
+        for block in func.blocks.iter_mut() {
+            let old_len: usize = block.insts.len();
+            block.insts.retain(|inst| match inst.def() {
+                Some(reg) => used.contains(&reg) || matches!(inst, Inst::Call { .. }),
+                None => true,
+            });
+            // Calls have side effects, so only their unused return value is dropped
+            for inst in block.insts.iter_mut() {
+                if let Inst::Call { dst, .. } = inst {
+                    if dst.is_some_and(|reg| !used.contains(&reg)) {
+                        *dst = None;
+                    }
+                }
+            }
+            removed |= block.insts.len() != old_len;
+        }
+
+        if !removed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ir::{CmpOp, IrOp};
+
+    const X: VarId = 1;
+
+    /// Globals at 0..=199 and memory mapped I/O at 200..=255.
+    fn regions() -> Vec<MemRegion> {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        vec![
+            region("globals", RegionKind::Globals, 0, 199),
+            region("io", RegionKind::Mmio, 200, 255),
+        ]
+    }
+
+    fn load_const(dst: u32, value: i16) -> Inst {
+        Inst::LoadConst {
+            dst: VReg(dst),
+            value,
+        }
+    }
+
+    fn store(src: u32) -> Inst {
+        Inst::Store {
+            var: X,
+            src: VReg(src),
+        }
+    }
+
+    fn return_reg(reg: u32) -> Terminator {
+        Terminator::Return(Some(Operand::Reg(VReg(reg))))
+    }
+
+    /// Folds `left op right` and returns what the function ends up returning.
+    fn fold_binop(op: IrOp, left: i16, right: i16) -> Terminator {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![(
+                vec![
+                    load_const(0, left),
+                    Inst::BinOp {
+                        op,
+                        dst: VReg(1),
+                        lhs: VReg(0),
+                        rhs: Operand::Const(right),
+                    },
+                ],
+                return_reg(1),
+            )],
+        );
+        fold_function(&mut func, &regions());
+        func.blocks[0].term.clone()
+    }
+
+    #[test]
+    fn folds_every_op() {
+        let cases: &[(IrOp, i16, i16, i16)] = &[
+            (IrOp::Add, 32767, 1, -32768),
+            (IrOp::Sub, -32768, 1, 32767),
+            (IrOp::Mul, 300, 300, 24464),
+            (IrOp::Div, -7, 2, -3),
+            (IrOp::Div, -32768, -1, -32768),
+            (IrOp::Mod, -7, 2, -1),
+            (IrOp::And, 0b1100, 0b1010, 0b1000),
+            (IrOp::Or, 0b1100, 0b1010, 0b1110),
+            (IrOp::Xor, 0b1100, 0b1010, 0b0110),
+            (IrOp::Shl, 1, 15, -32768),
+            (IrOp::Shl, 1, 16, 0),
+            (IrOp::Shr, -32768, 15, 1),
+        ];
+        for (op, left, right, result) in cases {
+            assert_eq!(
+                fold_binop(*op, *left, *right),
+                Terminator::Return(Some(Operand::Const(*result))),
+                "{left} {op:?} {right}"
+            );
+        }
+    }
+
+    #[test]
+    fn leaves_division_by_zero_unfolded() {
+        for op in [IrOp::Div, IrOp::Mod] {
+            assert_eq!(fold_binop(op, 5, 0), return_reg(1));
+        }
+    }
+
+    #[test]
+    fn known_branch_becomes_jump() {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![
+                (
+                    vec![load_const(0, 3)],
+                    Terminator::Branch {
+                        cond: BranchCond::Cmp {
+                            op: CmpOp::LessThan,
+                            lhs: VReg(0),
+                            rhs: Operand::Const(5),
+                        },
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                (Vec::new(), Terminator::Return(None)),
+                (Vec::new(), Terminator::Return(None)),
+            ],
+        );
+        fold_function(&mut func, &regions());
+        assert_eq!(func.blocks[0].term, Terminator::Jump(BlockId(1)));
+    }
+
+    #[test]
+    fn known_comparison_becomes_bool() {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![(
+                vec![
+                    load_const(0, 7),
+                    Inst::SetCond {
+                        dst: VReg(1),
+                        cond: BranchCond::Cmp {
+                            op: CmpOp::GreatThan,
+                            lhs: VReg(0),
+                            rhs: Operand::Const(5),
+                        },
+                    },
+                ],
+                return_reg(1),
+            )],
+        );
+        fold_function(&mut func, &regions());
+        assert_eq!(
+            func.blocks[0].term,
+            Terminator::Return(Some(Operand::Const(1)))
+        );
+    }
+
+    /// Stores 1 to X, then stores other_value to it on one side of a branch only. Returns X once
+    /// both sides have joined again.
+    fn fold_join(other_value: i16) -> Terminator {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![
+                (
+                    vec![load_const(0, 1), store(0)],
+                    Terminator::Branch {
+                        cond: BranchCond::Builtin {
+                            name: "is_pressed".to_string(),
+                            args: vec![Operand::Const(0)],
+                        },
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                (
+                    vec![load_const(1, other_value), store(1)],
+                    Terminator::Jump(BlockId(3)),
+                ),
+                (Vec::new(), Terminator::Jump(BlockId(3))),
+                (
+                    vec![Inst::Load {
+                        dst: VReg(2),
+                        var: X,
+                    }],
+                    return_reg(2),
+                ),
+            ],
+        );
+        fold_function(&mut func, &regions());
+        func.blocks[3].term.clone()
+    }
+
+    #[test]
+    fn variable_known_on_one_edge_is_unknown_after_join() {
+        assert_eq!(fold_join(2), return_reg(2));
+    }
+
+    #[test]
+    fn variable_known_on_every_edge_is_known_after_join() {
+        assert_eq!(fold_join(1), Terminator::Return(Some(Operand::Const(1))));
+    }
+
+    /// Stores 5 to X, writes through a pointer and returns X.
+    fn fold_pointer_store(addr: Operand) -> Terminator {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![(
+                vec![
+                    load_const(0, 5),
+                    store(0),
+                    Inst::StorePtr { addr, src: VReg(0) },
+                    Inst::Load {
+                        dst: VReg(1),
+                        var: X,
+                    },
+                ],
+                return_reg(1),
+            )],
+        );
+        fold_function(&mut func, &regions());
+        func.blocks[0].term.clone()
+    }
+
+    #[test]
+    fn pointer_store_forgets_variables() {
+        assert_eq!(fold_pointer_store(Operand::Const(0)), return_reg(1));
+        assert_eq!(fold_pointer_store(Operand::Var(2)), return_reg(1));
+    }
+
+    #[test]
+    fn pointer_store_outside_globals_keeps_variables() {
+        assert_eq!(
+            fold_pointer_store(Operand::Const(200)),
+            Terminator::Return(Some(Operand::Const(5)))
+        );
+    }
+}
@@ -0,0 +1,660 @@
+/*
+* Dead code elimination.
+*
+* Removes everything that can't affect the result of the program:
+* - Functions that can't be reached from main().
+* - Blocks that can't be reached from the start of their function, such as code after a return
+*   statement or the untaken side of a branch resolved by constant folding.
+* - Blocks that only jump somewhere else.
+* - Stores to variables that are never read before being overwritten (dead stores), along with
+*   the calculations that only existed to produce the stored value.
+* - Variables that nothing in the program reads, along with every store to them.
+*
+* Removing a store can leave the value it stored unused, which can make another store dead, so
+* these are repeated until nothing changes.
+*/
+
+use super::constant_folding::remove_unused_regs;
+use super::estimate_words;
+use crate::compiler::ir::{
+    BlockId, BranchCond, Inst, IrFunction, IrProgram, Operand, Terminator, VarId,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Runs all dead code elimination on the program. Returns the estimated amount of words saved.
+pub fn eliminate_dead_code(program: &mut IrProgram) -> usize {
+    let words_before: usize = estimate_words(program);
+
+    remove_unused_functions(program);
+    for func in program.functions.iter_mut() {
+        thread_jumps(func);
+        remove_unreachable_blocks(func);
+    }
+
+    loop {
+        let words: usize = estimate_words(program);
+        for func in program.functions.iter_mut() {
+            remove_dead_stores(func);
+            remove_unused_regs(func);
+        }
+        remove_unused_vars(program);
+
+        if estimate_words(program) == words {
+            break;
+        }
+    }
+
+    words_before.saturating_sub(estimate_words(program))
+}
+
+/// Removes every function that is never called, directly or indirectly, from main().
+pub fn remove_unused_functions(program: &mut IrProgram) {
+    let mut reachable: HashSet<String> = HashSet::new();
+    let mut worklist: Vec<usize> = program
+        .functions
+        .iter()
+        .enumerate()
+        .filter(|(index, func)| {
+            Some(*index) == program.entry_point || program.exports.contains(&func.name)
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    while let Some(index) = worklist.pop() {
+        let func = &program.functions[index];
+        if !reachable.insert(func.name.clone()) {
+            continue;
+        }
+
+        for callee in called_functions(func) {
+            match program.functions.iter().position(|f| f.name == callee) {
+                Some(callee_index) => worklist.push(callee_index),
+                None if program.externs.contains(&callee) => {} // Linked in later
+                None => panic!(
+                    "Function {callee}() called from {}() doesn't exist!",
+                    func.name
+                ),
+            }
+        }
+    }
+
+    let main_name: Option<String> = program
+        .entry_point
+        .map(|index| program.functions[index].name.clone());
+    program
+        .functions
+        .retain(|func| reachable.contains(&func.name));
+    program.entry_point = main_name.map(|main_name| {
+        program
+            .functions
+            .iter()
+            .position(|func| func.name == main_name)
+            .unwrap()
+    });
+}
+
+/// Returns the names of the functions called by a function, including calls made from inline
+/// assembly.
+pub fn called_functions(func: &IrFunction) -> Vec<String> {
+    let mut callees: Vec<String> = Vec::new();
+
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            match inst {
+                Inst::Call { name, .. } => callees.push(name.clone()),
+                Inst::Asm { code } => callees.extend(code.iter().filter_map(|line| {
+                    line.strip_prefix("call")
+                        .map(|name| name.trim_matches(|c: char| c == ',' || c.is_whitespace()))
+                        .filter(|name| !name.is_empty())
+                        .map(String::from)
+                })),
+                _ => {}
+            }
+        }
+    }
+
+    callees
+}
+
+/// Skips over blocks that do nothing but jump to another block, by sending everything that jumps
+/// to them straight to where they jump.
+pub fn thread_jumps(func: &mut IrFunction) {
+    let forwards: HashMap<BlockId, BlockId> = func
+        .blocks
+        .iter()
+        .filter(|block| block.insts.is_empty())
+        .filter_map(|block| match block.term {
+            Terminator::Jump(target) if target != block.id => Some((block.id, target)),
+            _ => None,
+        })
+        .collect();
+
+    // Follows a chain of empty blocks to its end. Stops if the chain loops back on itself, which
+    // happens for empty infinite loops.
+    let resolve = |start: BlockId| -> BlockId {
+        let mut seen: HashSet<BlockId> = HashSet::new();
+        let mut current: BlockId = start;
+        while let Some(next) = forwards.get(&current) {
+            if !seen.insert(current) {
+                break;
+            }
+            current = *next;
+        }
+        current
+    };
+
+    func.entry = resolve(func.entry);
+    for block in func.blocks.iter_mut() {
+        match &mut block.term {
+            Terminator::Jump(target) => *target = resolve(*target),
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => {
+                *then_block = resolve(*then_block);
+                *else_block = resolve(*else_block);
+            }
+            Terminator::Return(_) => {}
+        }
+
+        // Both sides of the branch ended up in the same place, so there is nothing to check
+        if let Terminator::Branch {
+            then_block,
+            else_block,
+            ..
+        } = block.term
+        {
+            if then_block == else_block {
+                block.term = Terminator::Jump(then_block);
+            }
+        }
+    }
+}
+
+/// Removes all blocks that can't be reached from the entry of the function.
+pub fn remove_unreachable_blocks(func: &mut IrFunction) {
+    let mut reachable: HashSet<BlockId> = HashSet::new();
+    let mut worklist: Vec<BlockId> = vec![func.entry];
+
+    while let Some(id) = worklist.pop() {
+        if reachable.insert(id) {
+            worklist.extend(func.block(id).term.successors());
+        }
+    }
+
+    func.blocks.retain(|block| reachable.contains(&block.id));
+
+    // Execution starts at the first block laid out
+    if let Some(index) = func.blocks.iter().position(|block| block.id == func.entry) {
+        let entry = func.blocks.remove(index);
+        func.blocks.insert(0, entry);
+    }
+}
+
+/// Removes stores to variables that are overwritten or never read again afterwards. Variables
+/// that have been moved to a fixed address with move_to() are left alone, as something else might
+/// be reading that address.
+pub fn remove_dead_stores(func: &mut IrFunction) {
+    // Inline assembly could read any variable, so there is no telling which stores are dead
+    let has_asm: bool = func
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .any(|inst| matches!(inst, Inst::Asm { .. }));
+    if has_asm {
+        return;
+    }
+
+    let pinned: HashSet<VarId> = func
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|inst| match inst {
+            Inst::MoveTo { var, .. } => Some(*var),
+            _ => None,
+        })
+        .collect();
+
+    let live_out: HashMap<BlockId, HashSet<VarId>> = var_liveness(func);
+
+    for block in func.blocks.iter_mut() {
+        let mut live: HashSet<VarId> = live_out[&block.id].clone();
+        live.extend(terminator_var_uses(&block.term));
+
+        let mut keep: Vec<bool> = vec![true; block.insts.len()];
+        for (index, inst) in block.insts.iter().enumerate().rev() {
+            if let Inst::Store { var, .. } = inst {
+                if !live.contains(var) && !pinned.contains(var) {
+                    keep[index] = false;
+                }
+                live.remove(var);
+            }
+            live.extend(inst_var_uses(inst));
+        }
+
+        let mut keep = keep.into_iter();
+        block.insts.retain(|_| keep.next().unwrap());
+    }
+}
+
+/// Removes every store to a variable that isn't read anywhere in the program, including
+/// parameters their function never reads. Parameters of functions from other object files count
+/// as read, since there is no telling what those do with them.
+pub fn remove_unused_vars(program: &mut IrProgram) {
+    let all_insts = || {
+        program
+            .functions
+            .iter()
+            .flat_map(|func| func.blocks.iter())
+            .flat_map(|block| block.insts.iter())
+    };
+    // Inline assembly could read any variable
+    if all_insts().any(|inst| matches!(inst, Inst::Asm { .. })) {
+        return;
+    }
+
+    let mut read: HashSet<VarId> = HashSet::new();
+    for block in program.functions.iter().flat_map(|func| func.blocks.iter()) {
+        for inst in block.insts.iter() {
+            match inst {
+                Inst::Call { name, params, .. } => {
+                    if !program.functions.iter().any(|func| &func.name == name) {
+                        read.extend(params.iter().copied());
+                    }
+                }
+                _ => read.extend(inst_var_uses(inst)),
+            }
+        }
+        read.extend(terminator_var_uses(&block.term));
+    }
+
+    for block in program
+        .functions
+        .iter_mut()
+        .flat_map(|func| func.blocks.iter_mut())
+    {
+        block.insts.retain(|inst| match inst {
+            Inst::Store { var, .. } => read.contains(var),
+            _ => true,
+        });
+        for inst in block.insts.iter_mut() {
+            if let Inst::Call { params, .. } = inst {
+                params.retain(|param| read.contains(param));
+            }
+        }
+    }
+}
+
+/// Calculates which variables might still be read at the end of every block.
+fn var_liveness(func: &IrFunction) -> HashMap<BlockId, HashSet<VarId>> {
+    let mut live_in: HashMap<BlockId, HashSet<VarId>> =
+        func.blocks.iter().map(|b| (b.id, HashSet::new())).collect();
+    let mut live_out: HashMap<BlockId, HashSet<VarId>> = live_in.clone();
+
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+
+        for block in func.blocks.iter().rev() {
+            let mut out: HashSet<VarId> = HashSet::new();
+            for succ in block.term.successors() {
+                out.extend(live_in[&succ].iter().copied());
+            }
+
+            let mut live: HashSet<VarId> = out.clone();
+            live.extend(terminator_var_uses(&block.term));
+            for inst in block.insts.iter().rev() {
+                if let Inst::Store { var, .. } = inst {
+                    live.remove(var);
+                }
+                live.extend(inst_var_uses(inst));
+            }
+
+            if live != live_in[&block.id] || out != live_out[&block.id] {
+                changed = true;
+                live_in.insert(block.id, live);
+                live_out.insert(block.id, out);
+            }
+        }
+    }
+
+    live_out
+}
+
+/// Returns the variables read by an instruction.
+fn inst_var_uses(inst: &Inst) -> Vec<VarId> {
+    match inst {
+        Inst::Load { var, .. } | Inst::MoveTo { var, .. } => vec![*var],
+        Inst::Call { params, .. } => params.clone(),
+        Inst::BinOp {
+            rhs: Operand::Var(var),
+            ..
+        }
+        | Inst::LoadPtr {
+            addr: Operand::Var(var),
+            ..
+        }
+        | Inst::StorePtr {
+            addr: Operand::Var(var),
+            ..
+        } => vec![*var],
+        Inst::Builtin { args, .. } => var_args(args),
+        Inst::SetCond { cond, .. } => cond_var_uses(cond),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the variables read by a terminator.
+fn terminator_var_uses(term: &Terminator) -> Vec<VarId> {
+    match term {
+        Terminator::Branch { cond, .. } => cond_var_uses(cond),
+        Terminator::Return(Some(Operand::Var(var))) => vec![*var],
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the variables read by a condition.
+fn cond_var_uses(cond: &BranchCond) -> Vec<VarId> {
+    match cond {
+        BranchCond::Cmp {
+            rhs: Operand::Var(var),
+            ..
+        } => vec![*var],
+        BranchCond::Cmp { .. } => Vec::new(),
+        BranchCond::Builtin { args, .. } => var_args(args),
+    }
+}
+
+/// Returns the variables among the arguments of a builtin.
+fn var_args(args: &[Operand]) -> Vec<VarId> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            Operand::Var(var) => Some(*var),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ir::{IrOp, VReg};
+
+    const X: VarId = 1;
+
+    fn call(name: &str) -> Inst {
+        Inst::Call {
+            name: name.to_string(),
+            params: Vec::new(),
+            dst: None,
+        }
+    }
+
+    fn function(name: &str, insts: Vec<Inst>) -> IrFunction {
+        IrFunction::from_blocks(name, vec![(insts, Terminator::Return(None))])
+    }
+
+    fn program(functions: Vec<IrFunction>) -> IrProgram {
+        IrProgram {
+            functions,
+            entry_point: Some(0),
+            exports: Vec::new(),
+            externs: Vec::new(),
+            prealloc_start: None,
+            prealloc_end: None,
+            reserved: Vec::new(),
+        }
+    }
+
+    fn names(program: &IrProgram) -> Vec<&str> {
+        program.functions.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn removes_functions_never_called_from_main() {
+        let mut program = program(vec![
+            function("main", vec![call("used")]),
+            function("unused", vec![call("used")]),
+            function("used", vec![call("nested")]),
+            function("nested", Vec::new()),
+        ]);
+        remove_unused_functions(&mut program);
+        assert_eq!(names(&program), ["main", "used", "nested"]);
+        assert_eq!(program.entry_point, Some(0));
+    }
+
+    #[test]
+    fn keeps_functions_called_from_asm() {
+        let asm = Inst::Asm {
+            code: vec!["call helper".to_string()],
+        };
+        let mut program = program(vec![
+            function("helper", Vec::new()),
+            function("main", vec![asm]),
+        ]);
+        program.entry_point = Some(1);
+        remove_unused_functions(&mut program);
+        assert_eq!(names(&program), ["helper", "main"]);
+        assert_eq!(program.entry_point, Some(1));
+    }
+
+    #[test]
+    fn threads_jump_chain_into_entry() {
+        // bb0 -> bb1 -> bb2 are empty, so the function really starts at bb3
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![
+                (Vec::new(), Terminator::Jump(BlockId(1))),
+                (Vec::new(), Terminator::Jump(BlockId(2))),
+                (Vec::new(), Terminator::Jump(BlockId(3))),
+                (vec![call("g")], Terminator::Return(None)),
+            ],
+        );
+        thread_jumps(&mut func);
+        assert_eq!(func.entry, BlockId(3));
+
+        remove_unreachable_blocks(&mut func);
+        let ids: Vec<BlockId> = func.blocks.iter().map(|block| block.id).collect();
+        assert_eq!(ids, [BlockId(3)]);
+    }
+
+    #[test]
+    fn threaded_branch_with_one_target_becomes_jump() {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![
+                (
+                    Vec::new(),
+                    Terminator::Branch {
+                        cond: BranchCond::Builtin {
+                            name: "is_pressed".to_string(),
+                            args: vec![Operand::Const(0)],
+                        },
+                        then_block: BlockId(1),
+                        else_block: BlockId(2),
+                    },
+                ),
+                (Vec::new(), Terminator::Jump(BlockId(2))),
+                (Vec::new(), Terminator::Return(None)),
+            ],
+        );
+        thread_jumps(&mut func);
+        assert_eq!(func.blocks[0].term, Terminator::Jump(BlockId(2)));
+    }
+
+    #[test]
+    fn keeps_empty_infinite_loop() {
+        let mut func = IrFunction::from_blocks(
+            "f",
+            vec![
+                (Vec::new(), Terminator::Jump(BlockId(1))),
+                (Vec::new(), Terminator::Jump(BlockId(0))),
+            ],
+        );
+        thread_jumps(&mut func);
+        remove_unreachable_blocks(&mut func);
+        assert!(!func.blocks.is_empty());
+    }
+
+    /// Stores to X twice in a row with inst in between, then returns. Returns how many stores are
+    /// left after removing dead stores.
+    fn stores_left(inst: Option<Inst>) -> usize {
+        let mut insts: Vec<Inst> = vec![
+            Inst::LoadConst {
+                dst: VReg(0),
+                value: 1,
+            },
+            Inst::Store {
+                var: X,
+                src: VReg(0),
+            },
+        ];
+        insts.extend(inst);
+        insts.push(Inst::Store {
+            var: X,
+            src: VReg(0),
+        });
+
+        let mut func = function("f", insts);
+        remove_dead_stores(&mut func);
+        func.blocks[0]
+            .insts
+            .iter()
+            .filter(|inst| matches!(inst, Inst::Store { .. }))
+            .count()
+    }
+
+    #[test]
+    fn removes_overwritten_and_unread_stores() {
+        assert_eq!(stores_left(None), 0);
+    }
+
+    #[test]
+    fn keeps_stores_read_in_between() {
+        let load = Inst::Load {
+            dst: VReg(1),
+            var: X,
+        };
+        assert_eq!(stores_left(Some(load)), 1);
+    }
+
+    #[test]
+    fn keeps_every_store_with_asm() {
+        let asm = Inst::Asm {
+            code: vec!["nop".to_string()],
+        };
+        assert_eq!(stores_left(Some(asm)), 2);
+    }
+
+    #[test]
+    fn removes_chains_of_dead_stores() {
+        // int m = c % 3; int n = m & 0x1F; with n never read
+        const C: VarId = 2;
+        const N: VarId = 3;
+        let binop = |op: IrOp, dst: u32, lhs: u32, rhs: i16| Inst::BinOp {
+            op,
+            dst: VReg(dst),
+            lhs: VReg(lhs),
+            rhs: Operand::Const(rhs),
+        };
+        let insts: Vec<Inst> = vec![
+            Inst::Load {
+                dst: VReg(0),
+                var: C,
+            },
+            binop(IrOp::Mod, 1, 0, 3),
+            Inst::Store {
+                var: X,
+                src: VReg(1),
+            },
+            Inst::Load {
+                dst: VReg(2),
+                var: X,
+            },
+            binop(IrOp::And, 3, 2, 0x1F),
+            Inst::Store {
+                var: N,
+                src: VReg(3),
+            },
+        ];
+        let mut program = program(vec![function("main", insts)]);
+        eliminate_dead_code(&mut program);
+        assert_eq!(program.functions[0].blocks[0].insts, []);
+    }
+
+    #[test]
+    fn removes_parameters_that_are_never_read() {
+        const USED: VarId = 2;
+        let call = |params: Vec<VarId>| Inst::Call {
+            name: "f".to_string(),
+            params,
+            dst: None,
+        };
+        let store = |var: VarId| Inst::Store { var, src: VReg(0) };
+        let main = function(
+            "main",
+            vec![
+                Inst::LoadConst {
+                    dst: VReg(0),
+                    value: 1,
+                },
+                store(X),
+                store(USED),
+                call(vec![X, USED]),
+            ],
+        );
+        let mut f = function(
+            "f",
+            vec![Inst::Load {
+                dst: VReg(0),
+                var: USED,
+            }],
+        );
+        f.params = vec![X, USED];
+        f.blocks[0].term = Terminator::Return(Some(Operand::Reg(VReg(0))));
+
+        let mut program = program(vec![main, f]);
+        remove_unused_vars(&mut program);
+        assert_eq!(
+            program.functions[0].blocks[0].insts[1..],
+            [store(USED), call(vec![USED])]
+        );
+    }
+
+    #[test]
+    fn keeps_parameters_of_functions_from_other_objects() {
+        let main = function(
+            "main",
+            vec![
+                Inst::LoadConst {
+                    dst: VReg(0),
+                    value: 1,
+                },
+                Inst::Store {
+                    var: X,
+                    src: VReg(0),
+                },
+                Inst::Call {
+                    name: "lib.f".to_string(),
+                    params: vec![X],
+                    dst: None,
+                },
+            ],
+        );
+        let mut program = program(vec![main]);
+        program.externs = vec!["lib.f".to_string()];
+        remove_unused_vars(&mut program);
+        assert_eq!(program.functions[0].blocks[0].insts.len(), 3);
+    }
+
+    #[test]
+    fn keeps_stores_to_variables_moved_with_move_to() {
+        let move_to = Inst::MoveTo {
+            var: X,
+            src: VReg(0),
+            addr: 200,
+        };
+        assert_eq!(stores_left(Some(move_to)), 2);
+    }
+}
@@ -0,0 +1,443 @@
+/*
+* Function inlining.
+*
+* Replaces calls with a copy of the called function, saving the call and ret instructions as well
+* as opening up for constant folding across the call. Functions called from a single place are
+* always inlined, since that never makes the program bigger. Functions called from several places
+* are only inlined if the program grows by at most the inline threshold (in words).
+*
+* #INLINE and #NOINLINE in front of a function override the heuristic.
+*/
+
+use super::dead_code::called_functions;
+use super::estimate_function_words;
+use crate::compiler::ir::builder::random_branch_name;
+use crate::compiler::ir::{
+    BasicBlock, BlockId, InlineHint, Inst, IrFunction, IrProgram, Operand, Terminator, VReg, VarId,
+};
+use crate::compiler::parser::variable_hasher;
+use std::collections::{HashMap, HashSet};
+
+/// How many words the program is allowed to grow by when inlining a function, unless the CLI or
+/// hardware config says otherwise.
+pub const DEFAULT_INLINE_THRESHOLD: isize = 4;
+
+/// Inlines every call to functions worth inlining. Returns the names of the inlined functions.
+pub fn inline_functions(program: &mut IrProgram, threshold: isize) -> Vec<String> {
+    let call_sites: HashMap<String, usize> = count_call_sites(program);
+    let main_name: Option<&str> = program
+        .entry_point
+        .map(|index| program.functions[index].name.as_str());
+
+    // Exported functions stay around for other object files anyway, so inlining them only pays
+    // off when asked for
+    let inlined: HashSet<String> = program
+        .functions
+        .iter()
+        .filter(|func| Some(func.name.as_str()) != main_name)
+        .filter(|func| !program.exports.contains(&func.name) || func.inline == InlineHint::Always)
+        .filter(|func| should_inline(func, call_sites.get(&func.name).copied(), threshold))
+        .map(|func| func.name.clone())
+        .collect();
+
+    // Callees are handled before their callers, so calls inside inlined code are already gone
+    for name in bottom_up_order(program) {
+        let index: usize = program
+            .functions
+            .iter()
+            .position(|func| func.name == name)
+            .unwrap();
+        let mut func: IrFunction = program.functions[index].clone();
+
+        while let Some((block_index, inst_index, callee_name)) = find_call(&func, &inlined) {
+            let callee: &IrFunction = program
+                .functions
+                .iter()
+                .find(|f| f.name == callee_name)
+                .unwrap();
+            inline_call(&mut func, block_index, inst_index, callee);
+        }
+
+        program.functions[index] = func;
+    }
+
+    let mut inlined: Vec<String> = inlined.into_iter().collect();
+    inlined.sort();
+    inlined
+}
+
+/// Counts how many times every function is called.
+fn count_call_sites(program: &IrProgram) -> HashMap<String, usize> {
+    let mut call_sites: HashMap<String, usize> = HashMap::new();
+    for func in program.functions.iter() {
+        for callee in called_functions(func) {
+            *call_sites.entry(callee).or_default() += 1;
+        }
+    }
+    call_sites
+}
+
+/// Decides whether a function should be inlined everywhere it is called.
+fn should_inline(func: &IrFunction, call_sites: Option<usize>, threshold: isize) -> bool {
+    let Some(call_sites) = call_sites else {
+        return false; // Never called
+    };
+
+    match func.inline {
+        InlineHint::Always => true,
+        InlineHint::Never => false,
+        InlineHint::Default => {
+            // Labels in inline assembly would be duplicated by inlining it more than once
+            let has_asm: bool = func
+                .blocks
+                .iter()
+                .flat_map(|block| block.insts.iter())
+                .any(|inst| matches!(inst, Inst::Asm { .. }));
+            if has_asm && call_sites > 1 {
+                return false;
+            }
+
+            // Every copy saves a call and the ret, while the function itself is no longer needed
+            let size: isize = estimate_function_words(func) as isize;
+            let sites: isize = call_sites as isize;
+            let growth: isize = (sites - 1) * size - 2 * sites;
+            growth <= threshold
+        }
+    }
+}
+
+/// Returns the names of all functions, ordered so that every function comes after the functions
+/// it calls.
+fn bottom_up_order(program: &IrProgram) -> Vec<String> {
+    fn visit(
+        program: &IrProgram,
+        name: &str,
+        visited: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !visited.insert(name.to_string()) {
+            return;
+        }
+        if let Some(func) = program.functions.iter().find(|f| f.name == name) {
+            for callee in called_functions(func) {
+                visit(program, &callee, visited, order);
+            }
+            order.push(name.to_string());
+        }
+    }
+
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut order: Vec<String> = Vec::new();
+    for func in program.functions.iter() {
+        visit(program, &func.name, &mut visited, &mut order);
+    }
+    order
+}
+
+/// Finds the first call to a function that should be inlined. Returns the block index,
+/// instruction index and name of the called function.
+fn find_call(func: &IrFunction, inlined: &HashSet<String>) -> Option<(usize, usize, String)> {
+    for (block_index, block) in func.blocks.iter().enumerate() {
+        for (inst_index, inst) in block.insts.iter().enumerate() {
+            if let Inst::Call { name, .. } = inst {
+                if inlined.contains(name) {
+                    return Some((block_index, inst_index, name.clone()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Replaces a single call with the body of the called function. The block containing the call is
+/// split in two, with the blocks of the callee placed in between. Returns jump to the second half,
+/// passing the return value through DM since the callee can return from several places.
+fn inline_call(
+    caller: &mut IrFunction,
+    block_index: usize,
+    inst_index: usize,
+    callee: &IrFunction,
+) {
+    // Virtual registers of the callee are moved past every register used by either function
+    let vreg_offset: u32 = caller.next_vreg.max(callee.next_vreg);
+    let mut next_vreg: u32 = vreg_offset + callee.next_vreg;
+
+    let mut next_block: usize = caller.blocks.iter().map(|b| b.id.0).max().unwrap() + 1;
+    let mut new_block_id = || {
+        let id = BlockId(next_block);
+        next_block += 1;
+        id
+    };
+    let block_map: HashMap<BlockId, BlockId> = callee
+        .blocks
+        .iter()
+        .map(|block| (block.id, new_block_id()))
+        .collect();
+    let continue_id: BlockId = new_block_id();
+
+    // Split the calling block at the call
+    let block: &mut BasicBlock = &mut caller.blocks[block_index];
+    let after_call: Vec<Inst> = block.insts.split_off(inst_index + 1);
+    let Some(Inst::Call { dst, .. }) = block.insts.pop() else {
+        panic!("Trying to inline something that is not a call!");
+    };
+    let caller_term: Terminator =
+        std::mem::replace(&mut block.term, Terminator::Jump(block_map[&callee.entry]));
+
+    let return_var: VarId = variable_hasher("return", &callee.name);
+    let rename = |reg: VReg| VReg(reg.0 + vreg_offset);
+
+    let mut new_blocks: Vec<BasicBlock> = Vec::new();
+    for callee_block in callee.blocks.iter() {
+        let mut insts: Vec<Inst> = callee_block
+            .insts
+            .iter()
+            .map(|inst| {
+                let mut inst = inst.clone();
+                for reg in inst.uses() {
+                    inst.replace_use(reg, rename(reg));
+                }
+                if let Some(reg) = inst.def() {
+                    inst.replace_def(rename(reg));
+                }
+                inst
+            })
+            .collect();
+
+        let term: Terminator = match &callee_block.term {
+            Terminator::Jump(target) => Terminator::Jump(block_map[target]),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let mut term = Terminator::Branch {
+                    cond: cond.clone(),
+                    then_block: block_map[then_block],
+                    else_block: block_map[else_block],
+                };
+                for reg in term.uses() {
+                    term.replace_use(reg, rename(reg));
+                }
+                term
+            }
+            Terminator::Return(value) => {
+                if let (Some(value), Some(_)) = (value, dst) {
+                    let src: VReg = match value {
+                        Operand::Reg(reg) => rename(*reg),
+                        Operand::Const(value) => {
+                            let reg = VReg(next_vreg);
+                            next_vreg += 1;
+                            insts.push(Inst::LoadConst {
+                                dst: reg,
+                                value: *value,
+                            });
+                            reg
+                        }
+                        Operand::Var(var) => {
+                            let reg = VReg(next_vreg);
+                            next_vreg += 1;
+                            insts.push(Inst::Load {
+                                dst: reg,
+                                var: *var,
+                            });
+                            reg
+                        }
+                    };
+                    insts.push(Inst::Store {
+                        var: return_var,
+                        src,
+                    });
+                }
+                Terminator::Jump(continue_id)
+            }
+        };
+
+        new_blocks.push(BasicBlock {
+            id: block_map[&callee_block.id],
+            label: random_branch_name(),
+            insts,
+            term,
+        });
+    }
+
+    // Second half of the calling block picks up the return value
+    let mut continue_insts: Vec<Inst> = Vec::new();
+    if let Some(dst) = dst {
+        continue_insts.push(Inst::Load {
+            dst,
+            var: return_var,
+        });
+    }
+    continue_insts.extend(after_call);
+    new_blocks.push(BasicBlock {
+        id: continue_id,
+        label: random_branch_name(),
+        insts: continue_insts,
+        term: caller_term,
+    });
+
+    caller
+        .blocks
+        .splice(block_index + 1..block_index + 1, new_blocks);
+    caller.next_vreg = next_vreg;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn call(name: &str, dst: Option<u32>) -> Inst {
+        Inst::Call {
+            name: name.to_string(),
+            params: Vec::new(),
+            dst: dst.map(VReg),
+        }
+    }
+
+    /// A function taking up the given amount of words of program memory, counting the return.
+    fn function_of_size(name: &str, words: usize) -> IrFunction {
+        let insts: Vec<Inst> = (0..words - 2)
+            .map(|i| Inst::LoadConst {
+                dst: VReg(i as u32),
+                value: 0,
+            })
+            .collect();
+        IrFunction::from_blocks(name, vec![(insts, Terminator::Return(None))])
+    }
+
+    fn program(functions: Vec<IrFunction>) -> IrProgram {
+        IrProgram {
+            functions,
+            entry_point: Some(0),
+            exports: Vec::new(),
+            externs: Vec::new(),
+            prealloc_start: None,
+            prealloc_end: None,
+            reserved: Vec::new(),
+        }
+    }
+
+    /// Inlines f, which is called from two places in main. Returns the inlined functions.
+    fn inline_called_twice(f: IrFunction, threshold: isize) -> Vec<String> {
+        let main = IrFunction::from_blocks(
+            "main",
+            vec![(
+                vec![call("f", None), call("f", None)],
+                Terminator::Return(None),
+            )],
+        );
+        inline_functions(&mut program(vec![main, f]), threshold)
+    }
+
+    #[test]
+    fn inlines_up_to_the_threshold() {
+        // Two copies of 8 words replace 8 words and two calls and rets, growing by 4
+        let f = function_of_size("f", 8);
+        assert_eq!(estimate_function_words(&f), 8);
+        assert_eq!(
+            inline_called_twice(f.clone(), DEFAULT_INLINE_THRESHOLD),
+            ["f"]
+        );
+        assert!(inline_called_twice(f, DEFAULT_INLINE_THRESHOLD - 1).is_empty());
+
+        let f = function_of_size("f", 9);
+        assert!(inline_called_twice(f, DEFAULT_INLINE_THRESHOLD).is_empty());
+    }
+
+    #[test]
+    fn always_inlines_functions_called_once() {
+        let f = function_of_size("f", 100);
+        assert!(should_inline(&f, Some(1), 0));
+        assert!(!should_inline(&f, None, DEFAULT_INLINE_THRESHOLD));
+    }
+
+    #[test]
+    fn hints_override_the_threshold() {
+        let mut f = function_of_size("f", 100);
+        f.inline = InlineHint::Always;
+        assert!(should_inline(&f, Some(5), 0));
+
+        let mut f = function_of_size("f", 2);
+        f.inline = InlineHint::Never;
+        assert!(!should_inline(&f, Some(1), DEFAULT_INLINE_THRESHOLD));
+    }
+
+    #[test]
+    fn renames_callee_registers_past_the_caller() {
+        // The caller only uses v0 and v1, while the callee goes up to v9
+        let mut caller = IrFunction::from_blocks(
+            "main",
+            vec![(
+                vec![
+                    Inst::LoadConst {
+                        dst: VReg(0),
+                        value: 1,
+                    },
+                    call("f", Some(1)),
+                ],
+                Terminator::Return(Some(Operand::Reg(VReg(1)))),
+            )],
+        );
+        let callee_insts: Vec<Inst> = (0..10)
+            .map(|i| Inst::LoadConst {
+                dst: VReg(i),
+                value: i as i16,
+            })
+            .collect();
+        let callee = IrFunction::from_blocks(
+            "f",
+            vec![(
+                callee_insts,
+                Terminator::Return(Some(Operand::Reg(VReg(9)))),
+            )],
+        );
+        assert!(callee.next_vreg > caller.next_vreg);
+
+        inline_call(&mut caller, 0, 1, &callee);
+
+        // Every register is still written only once, and stays below next_vreg
+        let mut defs: HashSet<VReg> = HashSet::new();
+        for inst in caller.blocks.iter().flat_map(|block| block.insts.iter()) {
+            if let Some(reg) = inst.def() {
+                assert!(defs.insert(reg), "{reg} is written twice");
+                assert!(reg.0 < caller.next_vreg, "{reg} is past next_vreg");
+            }
+        }
+        assert_eq!(defs.len(), 12);
+    }
+
+    #[test]
+    fn inlines_callee_without_return_value_into_call_with_dst() {
+        let mut caller = IrFunction::from_blocks(
+            "main",
+            vec![(
+                vec![call("f", Some(0))],
+                Terminator::Return(Some(Operand::Reg(VReg(0)))),
+            )],
+        );
+        let callee = IrFunction::from_blocks("f", vec![(Vec::new(), Terminator::Return(None))]);
+
+        inline_call(&mut caller, 0, 0, &callee);
+
+        // The callee stores nothing, but the register still gets a value like after a real call
+        let return_var: VarId = variable_hasher("return", "f");
+        let blocks: Vec<&BasicBlock> = caller.blocks.iter().collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].term, Terminator::Jump(blocks[1].id));
+        assert!(blocks[1].insts.is_empty());
+        assert_eq!(blocks[1].term, Terminator::Jump(blocks[2].id));
+        assert_eq!(
+            blocks[2].insts,
+            [Inst::Load {
+                dst: VReg(0),
+                var: return_var
+            }]
+        );
+        assert_eq!(
+            blocks[2].term,
+            Terminator::Return(Some(Operand::Reg(VReg(0))))
+        );
+    }
+}
@@ -3,3 +3,47 @@
 * places in the code which can be optmized, such as calculations or loops
 * which can be done in compile time rather than runtime.
 */
+
+pub mod cacher;
+pub mod constant_folding;
+pub mod dead_code;
+pub mod inliner;
+pub mod pass_manager;
+pub mod peephole;
+
+use crate::compiler::ir::{BranchCond, CmpOp, Inst, IrFunction, IrProgram, Terminator};
+
+/// Estimates how many words of program memory the IR will take up once lowered to ASS. Used to
+/// report how much the optimizations saved, so it doesn't have to be exact.
+pub fn estimate_words(program: &IrProgram) -> usize {
+    program.functions.iter().map(estimate_function_words).sum()
+}
+
+/// Estimates the size of a single function, see estimate_words().
+pub fn estimate_function_words(func: &IrFunction) -> usize {
+    let mut words: usize = 0;
+
+    for block in func.blocks.iter() {
+        for inst in block.insts.iter() {
+            words += match inst {
+                Inst::Asm { code } => code.len(),
+                _ => 1,
+            };
+        }
+
+        words += match &block.term {
+            Terminator::Jump(_) => 1,
+            Terminator::Branch {
+                cond: BranchCond::Cmp { op, .. },
+                ..
+            } => match op {
+                CmpOp::GreatThan | CmpOp::LessEq => 3,
+                _ => 2,
+            },
+            Terminator::Branch { .. } => 3, // The builtin, such as kbd and byk, and the jump
+            Terminator::Return(_) => 2,
+        };
+    }
+
+    words
+}
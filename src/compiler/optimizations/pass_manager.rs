@@ -0,0 +1,232 @@
+/*
+* Decides which optimization passes to run and runs them in order.
+*
+* The optimization level picks a default set of passes, which -f<pass> and -fno-<pass> can then
+* turn on or off one at a time. Passes either work on the IR, before it is lowered, or on the
+* generated ASS code. In verbose mode the time spent in every pass is printed along with how much
+* the program grew or shrunk.
+*/
+
+use super::cacher::cache_memory;
+use super::constant_folding::fold_constants;
+use super::dead_code::eliminate_dead_code;
+use super::estimate_words;
+use super::inliner::inline_functions;
+use super::peephole::run_peephole;
+use crate::compiler::ir::IrProgram;
+use crate::utils::compile_times::{calc_total_time, time_now};
+use crate::utils::hardware_conf::MemRegion;
+use std::time::{Duration, Instant};
+
+/// Optimization levels picked with -O0, -O1, -O2 and -Os.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    O0, // No optimizations
+    O1, // Optimizations that never make the program bigger
+    O2, // Everything, including inlining
+    Os, // Everything, but only inlining when it doesn't make the program bigger
+}
+
+/// Settings the passes are run with.
+pub struct PassConfig {
+    pub level: OptLevel,
+    pub inline_threshold: isize,
+    pub verbose: bool,
+    pub regions: Vec<MemRegion>, // Layout of DM, for passes that need to know where variables are
+}
+
+enum PassKind {
+    Ir(fn(&mut IrProgram, &PassConfig)),
+    Ass(fn(&mut Vec<String>, &PassConfig)),
+}
+
+/// An optimization pass.
+struct Pass {
+    name: &'static str,
+    levels: &'static [OptLevel], // Levels the pass is on by default at
+    kind: PassKind,
+}
+
+/// Every pass, in the order they are run in.
+const PASSES: &[Pass] = &[
+    Pass {
+        name: "inline",
+        levels: &[OptLevel::O2, OptLevel::Os],
+        kind: PassKind::Ir(inline_pass),
+    },
+    Pass {
+        name: "fold-constants",
+        levels: &[OptLevel::O1, OptLevel::O2, OptLevel::Os],
+        kind: PassKind::Ir(|program, config| fold_constants(program, &config.regions)),
+    },
+    Pass {
+        name: "dead-code",
+        levels: &[OptLevel::O1, OptLevel::O2, OptLevel::Os],
+        kind: PassKind::Ir(|program, _| {
+            eliminate_dead_code(program);
+        }),
+    },
+    Pass {
+        name: "cache",
+        levels: &[OptLevel::O1, OptLevel::O2, OptLevel::Os],
+        kind: PassKind::Ass(|program, _| {
+            cache_memory(program);
+        }),
+    },
+    Pass {
+        name: "peephole",
+        levels: &[OptLevel::O1, OptLevel::O2, OptLevel::Os],
+        kind: PassKind::Ass(peephole_pass),
+    },
+];
+
+/// Keeps track of which passes are turned on, and runs them.
+pub struct PassManager {
+    config: PassConfig,
+    enabled: Vec<bool>, // Indexed the same way as PASSES
+}
+
+impl PassManager {
+    /// Picks the passes for the optimization level, then applies the -f flags in the order they
+    /// were given. The flags have already been checked against pass_names() by the CLI.
+    pub fn new(config: PassConfig, pass_flags: &[(String, bool)]) -> PassManager {
+        let mut enabled: Vec<bool> = PASSES
+            .iter()
+            .map(|pass| pass.levels.contains(&config.level))
+            .collect();
+
+        for (name, on) in pass_flags.iter() {
+            if let Some(index) = PASSES.iter().position(|pass| pass.name == name) {
+                enabled[index] = *on;
+            }
+        }
+
+        PassManager { config, enabled }
+    }
+
+    /// Runs the enabled passes working on the IR.
+    pub fn run_ir(&self, program: &mut IrProgram) {
+        for pass in self.enabled_passes() {
+            if let PassKind::Ir(run) = pass.kind {
+                let before: usize = estimate_words(program);
+                let start: Instant = time_now();
+                run(program, &self.config);
+                self.log(
+                    pass,
+                    calc_total_time(&start),
+                    before,
+                    estimate_words(program),
+                );
+            }
+        }
+    }
+
+    /// Runs the enabled passes working on the generated ASS code.
+    pub fn run_ass(&self, program: &mut Vec<String>) {
+        for pass in self.enabled_passes() {
+            if let PassKind::Ass(run) = pass.kind {
+                let before: usize = count_words(program);
+                let start: Instant = time_now();
+                run(program, &self.config);
+                self.log(pass, calc_total_time(&start), before, count_words(program));
+            }
+        }
+    }
+
+    fn enabled_passes(&self) -> impl Iterator<Item = &Pass> {
+        PASSES
+            .iter()
+            .zip(self.enabled.iter())
+            .filter_map(|(pass, on)| on.then_some(pass))
+    }
+
+    fn log(&self, pass: &Pass, time: Duration, before: usize, after: usize) {
+        if self.config.verbose {
+            let delta: isize = after as isize - before as isize;
+            println!(
+                "Pass {}: {time:?}, {before} -> {after} words ({delta:+})",
+                pass.name
+            );
+        }
+    }
+}
+
+/// Names of every pass, for error and help messages.
+pub fn pass_names() -> Vec<&'static str> {
+    PASSES.iter().map(|pass| pass.name).collect()
+}
+
+/// Counts the lines of ASS that end up as words in program memory.
+fn count_words(program: &[String]) -> usize {
+    program
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .filter(|line| !line.starts_with('#') && !line.ends_with(':'))
+        .count()
+}
+
+fn inline_pass(program: &mut IrProgram, config: &PassConfig) {
+    let inlined: Vec<String> = inline_functions(program, config.inline_threshold);
+    if config.verbose && !inlined.is_empty() {
+        println!("Inlined functions: {}", inlined.join(", "));
+    }
+}
+
+fn peephole_pass(program: &mut Vec<String>, config: &PassConfig) {
+    let level: u8 = match config.level {
+        OptLevel::O0 | OptLevel::O1 => 1, // -fpeephole at -O0 still gets the basic rules
+        OptLevel::O2 | OptLevel::Os => 2,
+    };
+    let rewrites: Vec<(&str, usize)> = run_peephole(program, level);
+    if config.verbose {
+        for (rule, count) in rewrites.iter().filter(|(_, count)| *count > 0) {
+            println!("Peephole rule {rule} applied {count} times");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled(level: OptLevel, pass_flags: &[(&str, bool)]) -> Vec<&'static str> {
+        let config = PassConfig {
+            level,
+            inline_threshold: 0,
+            verbose: false,
+            regions: Vec::new(),
+        };
+        let pass_flags: Vec<(String, bool)> = pass_flags
+            .iter()
+            .map(|(name, on)| (name.to_string(), *on))
+            .collect();
+        let manager = PassManager::new(config, &pass_flags);
+        manager.enabled_passes().map(|pass| pass.name).collect()
+    }
+
+    #[test]
+    fn levels_pick_default_passes() {
+        assert!(enabled(OptLevel::O0, &[]).is_empty());
+        assert_eq!(
+            enabled(OptLevel::O1, &[]),
+            ["fold-constants", "dead-code", "cache", "peephole"]
+        );
+        assert_eq!(enabled(OptLevel::O2, &[]), pass_names());
+    }
+
+    #[test]
+    fn toggles_apply_in_order() {
+        assert_eq!(
+            enabled(OptLevel::O0, &[("peephole", true), ("inline", true)]),
+            ["inline", "peephole"]
+        );
+        assert_eq!(
+            enabled(
+                OptLevel::O2,
+                &[("inline", false), ("cache", false), ("cache", true)]
+            ),
+            ["fold-constants", "dead-code", "cache", "peephole"]
+        );
+    }
+}
@@ -0,0 +1,589 @@
+/*
+* Peephole optimizer, cleaning up patterns in the generated ASS code that are easier to spot after
+* codegen than before it.
+*
+* Every rule looks at the code starting at a line and either leaves it alone or tells how many
+* lines to replace and with what. The rules are run over the program again and again until none of
+* them changes anything. Inline assembly is never touched.
+*
+* Arithmetic instructions set the flags read by branches, while loads don't. Rules removing one are
+* only used when no branch can read the flags before they are set again.
+*
+* New rules are added to RULES, along with the lowest optimization level they are used at.
+*/
+
+use crate::compiler::ass_gen::instruction_parser::{ASM_BLOCK_END, ASM_BLOCK_START};
+use crate::compiler::ass_gen::memory_manager::{is_compiler_addr, parse_addr};
+
+/// Result of a rule matching: the number of lines matched and the lines replacing them.
+type Rewrite = (usize, Vec<String>);
+
+/// A single rewrite rule.
+struct Rule {
+    name: &'static str,
+    level: u8, // Lowest optimization level the rule is used at
+    apply: fn(&[String], usize) -> Option<Rewrite>,
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        name: "jump-to-next",
+        level: 1,
+        apply: jump_to_next,
+    },
+    Rule {
+        name: "store-then-load",
+        level: 1,
+        apply: store_then_load,
+    },
+    Rule {
+        name: "fold-immediate",
+        level: 1,
+        apply: fold_immediate,
+    },
+    Rule {
+        name: "identity-op",
+        level: 1,
+        apply: identity_op,
+    },
+    Rule {
+        name: "jump-to-jump",
+        level: 2,
+        apply: jump_to_jump,
+    },
+    Rule {
+        name: "invert-branch",
+        level: 2,
+        apply: invert_branch,
+    },
+    Rule {
+        name: "unreachable-code",
+        level: 2,
+        apply: unreachable_code,
+    },
+];
+
+/// Runs every rule enabled at the optimization level until nothing changes. Returns the number of
+/// times each rule was applied.
+pub fn run_peephole(program: &mut Vec<String>, level: u8) -> Vec<(&'static str, usize)> {
+    let rules: Vec<&Rule> = RULES.iter().filter(|rule| rule.level <= level).collect();
+    let applied: Vec<usize> = run_rules(program, &rules);
+
+    rules
+        .iter()
+        .zip(applied)
+        .map(|(rule, count)| (rule.name, count))
+        .collect()
+}
+
+/// Runs the rules until nothing changes. Returns the number of times each rule was applied.
+fn run_rules(program: &mut Vec<String>, rules: &[&Rule]) -> Vec<usize> {
+    let mut applied: Vec<usize> = vec![0; rules.len()];
+
+    let mut changed: bool = true;
+    while changed {
+        changed = false;
+
+        let mut index: usize = 0;
+        while index < program.len() {
+            if program[index] == ASM_BLOCK_START {
+                while index < program.len() && program[index] != ASM_BLOCK_END {
+                    index += 1;
+                }
+                index += 1;
+                continue;
+            }
+
+            let rewrite = rules
+                .iter()
+                .enumerate()
+                .find_map(|(rule_index, rule)| Some((rule_index, (rule.apply)(program, index)?)));
+
+            match rewrite {
+                Some((rule_index, (matched, replacement))) => {
+                    program.splice(index..index + matched, replacement);
+                    applied[rule_index] += 1;
+                    changed = true;
+                }
+                None => index += 1,
+            }
+        }
+    }
+
+    applied
+}
+
+/*
+* Helpers for reading lines of ASS
+*/
+
+/// Splits a line into its parts, eg. `ld, r0, 4` into ["ld", "r0", "4"].
+fn parts(line: &str) -> Vec<&str> {
+    line.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+fn is_label(line: &str) -> bool {
+    line.starts_with('#') && parts(line).len() == 1
+}
+
+fn is_branch(op: &str) -> bool {
+    matches!(op, "beq" | "bne" | "blt" | "bge" | "bpr" | "bnr" | "byk")
+}
+
+/// Returns the index of the first line after index that is not a label.
+fn skip_labels(program: &[String], mut index: usize) -> usize {
+    while index < program.len() && is_label(&program[index]) {
+        index += 1;
+    }
+    index
+}
+
+/// Returns whether the flags set by the line at index might be read by a branch before anything
+/// sets them again. Jumps are followed, and anything not understood, like inline assembly, is
+/// assumed to read them. Flags are never passed to or from a function, so calls and returns don't.
+fn flags_read_after(program: &[String], index: usize) -> bool {
+    let mut visited: Vec<&str> = Vec::new();
+    let mut index: usize = index + 1;
+
+    while index < program.len() {
+        match parts(&program[index])[..] {
+            [label] if is_label(label) => {}
+            ["add" | "addi" | "sub" | "subi" | "cmp" | "cmpi", ..] | ["call", _] | ["ret"] => {
+                return false
+            }
+            ["ld" | "ldi" | "st" | "nop", ..] => {}
+            ["jmp", label] => {
+                if visited.contains(&label) {
+                    return false; // Looping without reading them
+                }
+                visited.push(label);
+                match program.iter().position(|line| line == label) {
+                    Some(position) => index = position,
+                    None => return true,
+                }
+            }
+            _ => return true,
+        }
+        index += 1;
+    }
+    false
+}
+
+/// Returns whether label is placed between index and the next instruction.
+fn label_follows(program: &[String], index: usize, label: &str) -> bool {
+    program[index..skip_labels(program, index)]
+        .iter()
+        .any(|line| line == label)
+}
+
+/*
+* The rules
+*/
+
+/// `jmp L` right before `L`.
+fn jump_to_next(program: &[String], index: usize) -> Option<Rewrite> {
+    match parts(&program[index])[..] {
+        ["jmp", label] if label_follows(program, index + 1, label) => Some((1, Vec::new())),
+        _ => None,
+    }
+}
+
+/// `st rX, a` followed by `ld rX, a`. The register already holds the value.
+fn store_then_load(program: &[String], index: usize) -> Option<Rewrite> {
+    let next: &String = program.get(index + 1)?;
+    match (&parts(&program[index])[..], &parts(next)[..]) {
+        (["st", st_reg, st_addr], ["ld", ld_reg, ld_addr])
+            if st_reg == ld_reg
+                && st_addr == ld_addr
+                && parse_addr(st_addr).is_some_and(is_compiler_addr) =>
+        {
+            Some((2, vec![program[index].clone()]))
+        }
+        _ => None,
+    }
+}
+
+/// `ldi rX, a` followed by `addi rX, b` or `subi rX, b`, which is the same as `ldi rX, a+b`.
+fn fold_immediate(program: &[String], index: usize) -> Option<Rewrite> {
+    let next: &String = program.get(index + 1)?;
+    match (&parts(&program[index])[..], &parts(next)[..]) {
+        (["ldi", reg, value], [op @ ("addi" | "subi"), op_reg, operand])
+            if reg == op_reg && !flags_read_after(program, index + 1) =>
+        {
+            let value: i16 = value.parse().ok()?;
+            let operand: i16 = operand.parse().ok()?;
+            let result: i16 = if *op == "addi" {
+                value.wrapping_add(operand)
+            } else {
+                value.wrapping_sub(operand)
+            };
+            Some((2, vec![format!("ldi, {reg}, {result}")]))
+        }
+        _ => None,
+    }
+}
+
+/// Operations that leave the register as it was, like `addi rX, 0`.
+fn identity_op(program: &[String], index: usize) -> Option<Rewrite> {
+    match parts(&program[index])[..] {
+        ["addi" | "subi", _, "0"] | ["muli" | "divi", _, "1"]
+            if !flags_read_after(program, index) =>
+        {
+            Some((1, Vec::new()))
+        }
+        _ => None,
+    }
+}
+
+/// Jumps and branches to a label that only jumps somewhere else go straight there instead.
+fn jump_to_jump(program: &[String], index: usize) -> Option<Rewrite> {
+    let line_parts = parts(&program[index]);
+    let [op, label] = line_parts[..] else {
+        return None;
+    };
+    if op != "jmp" && !is_branch(op) {
+        return None;
+    }
+
+    // Follows the whole chain of jumps, giving up if it loops back on itself
+    let mut visited: Vec<&str> = vec![label];
+    let mut target: &str = label;
+    loop {
+        let position: usize = program.iter().position(|line| line == target)?;
+        let first_inst: &String = program.get(skip_labels(program, position))?;
+        match parts(first_inst)[..] {
+            ["jmp", next_label] => {
+                if visited.contains(&next_label) {
+                    return None;
+                }
+                visited.push(next_label);
+                target = next_label;
+            }
+            _ => break,
+        }
+    }
+
+    if target == label {
+        return None;
+    }
+    Some((1, vec![format!("{op} {target}")]))
+}
+
+/// `beq L`, `jmp M`, `L` is the same as `bne M`, `L`.
+fn invert_branch(program: &[String], index: usize) -> Option<Rewrite> {
+    let next: &String = program.get(index + 1)?;
+    match (&parts(&program[index])[..], &parts(next)[..]) {
+        ([branch, label], ["jmp", jump_label]) if label_follows(program, index + 2, label) => {
+            let inverted: &str = match *branch {
+                "beq" => "bne",
+                "bne" => "beq",
+                "blt" => "bge",
+                "bge" => "blt",
+                _ => return None,
+            };
+            Some((2, vec![format!("{inverted} {jump_label}")]))
+        }
+        _ => None,
+    }
+}
+
+/// Code after an unconditional jump or return that no label leads to.
+fn unreachable_code(program: &[String], index: usize) -> Option<Rewrite> {
+    match parts(&program[index])[..] {
+        ["jmp", _] | ["ret"] => {}
+        _ => return None,
+    }
+
+    let dead: usize = program[index + 1..]
+        .iter()
+        .take_while(|line| !is_label(line) && !line.ends_with(':') && *line != ASM_BLOCK_START)
+        .count();
+    if dead == 0 {
+        return None;
+    }
+    Some((dead + 1, vec![program[index].clone()]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ass_gen::memory_manager::set_memory_layout;
+    use crate::utils::hardware_conf::{MemRegion, RegionKind};
+    use std::collections::HashMap;
+
+    /// Registers and DM after running a program.
+    #[derive(Debug, PartialEq)]
+    struct State {
+        regs: [i16; 16],
+        mem: HashMap<u16, i16>,
+    }
+
+    /// Runs a program until it returns, with just enough of the instruction set for the rules.
+    fn run(program: &[String]) -> State {
+        let mut state = State {
+            regs: [0; 16],
+            mem: HashMap::new(),
+        };
+        let (mut zero, mut negative, mut overflow): (bool, bool, bool) = (false, false, false);
+        let mut pc: usize = 0;
+
+        for _ in 0..1000 {
+            let line: Vec<&str> = parts(&program[pc]);
+            pc += 1;
+            let reg = |index: usize| -> usize { line[index][1..].parse().unwrap() };
+            let num = |index: usize| -> i16 { line[index].parse().unwrap() };
+            let jump = |label: &str| -> usize { program.iter().position(|l| l == label).unwrap() };
+
+            match line[..] {
+                [label] if is_label(label) => {}
+                ["ret"] => return state,
+                ["nop"] => {}
+                ["ldi", ..] => state.regs[reg(1)] = num(2),
+                ["ld", ..] => state.regs[reg(1)] = *state.mem.get(&(num(2) as u16)).unwrap_or(&0),
+                ["st", ..] => {
+                    state.mem.insert(num(2) as u16, state.regs[reg(1)]);
+                }
+                ["jmp", label] => pc = jump(label),
+                [branch, label] if is_branch(branch) => {
+                    let taken: bool = match branch {
+                        "beq" => zero,
+                        "bne" => !zero,
+                        "blt" => negative != overflow,
+                        "bge" => negative == overflow,
+                        _ => panic!("Unsupported branch {branch}"),
+                    };
+                    if taken {
+                        pc = jump(label);
+                    }
+                }
+                [op, _, _] => {
+                    let left: i16 = state.regs[reg(1)];
+                    let right: i16 = match op.strip_suffix('i') {
+                        Some(_) => num(2),
+                        None => *state.mem.get(&(num(2) as u16)).unwrap_or(&0),
+                    };
+                    let (result, overflowed): (i16, bool) = match op.trim_end_matches('i') {
+                        "add" => left.overflowing_add(right),
+                        "sub" | "cmp" => left.overflowing_sub(right),
+                        "mul" => left.overflowing_mul(right),
+                        "div" => left.overflowing_div(right),
+                        _ => panic!("Unsupported instruction {}", program[pc - 1]),
+                    };
+                    (zero, negative, overflow) = (result == 0, result < 0, overflowed);
+                    if !op.starts_with("cmp") {
+                        state.regs[reg(1)] = result;
+                    }
+                }
+                _ => panic!("Unsupported instruction {}", program[pc - 1]),
+            }
+        }
+        panic!("Program never returned");
+    }
+
+    /// Runs a single rule over the program, checking that it ran the expected number of times and
+    /// that the program ends with the same registers and DM as before.
+    fn check_rule(name: &str, program: &[&str], expected_count: usize) -> Vec<String> {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        set_memory_layout(vec![
+            region("globals", RegionKind::Globals, 0, 199),
+            region("io", RegionKind::Mmio, 200, 255),
+        ]);
+
+        let before: Vec<String> = program.iter().map(|line| line.to_string()).collect();
+        let mut after: Vec<String> = before.clone();
+        let rule: &Rule = RULES.iter().find(|rule| rule.name == name).unwrap();
+        assert_eq!(
+            run_rules(&mut after, &[rule]),
+            [expected_count],
+            "{after:?}"
+        );
+        assert_eq!(
+            run(&before),
+            run(&after),
+            "{name} changed {before:?} into {after:?}"
+        );
+        after
+    }
+
+    #[test]
+    fn jump_to_next() {
+        let program = [
+            "ldi r0, 1",
+            "jmp #next",
+            "#next",
+            "addi r0, 2",
+            "st r0, 10",
+            "ret",
+        ];
+        assert_eq!(check_rule("jump-to-next", &program, 1).len(), 5);
+    }
+
+    #[test]
+    fn store_then_load() {
+        let program = [
+            "ldi r0, 5",
+            "st r0, 10",
+            "ld r0, 10",
+            "addi r0, 1",
+            "st r0, 11",
+            "ret",
+        ];
+        check_rule("store-then-load", &program, 1);
+    }
+
+    #[test]
+    fn store_then_load_outside_compiler_memory() {
+        let program = ["ldi r0, 5", "st r0, 210", "ld r0, 210", "ret"];
+        check_rule("store-then-load", &program, 0);
+    }
+
+    #[test]
+    fn fold_immediate() {
+        let program = [
+            "ldi r0, 5",
+            "addi r0, 3",
+            "ldi r1, -32768",
+            "subi r1, 1",
+            "st r0, 10",
+            "st r1, 11",
+            "ret",
+        ];
+        check_rule("fold-immediate", &program, 2);
+    }
+
+    #[test]
+    fn fold_immediate_keeps_flags_read_by_branch() {
+        let program = [
+            "ldi r1, 0",
+            "ldi r0, 5",
+            "subi r0, 5",
+            "beq #zero",
+            "ldi r1, 1",
+            "#zero",
+            "st r1, 10",
+            "ret",
+        ];
+        check_rule("fold-immediate", &program, 0);
+    }
+
+    #[test]
+    fn fold_immediate_follows_jumps_to_flag_reads() {
+        let program = [
+            "ldi r1, 0",
+            "ldi r0, 5",
+            "subi r0, 5",
+            "jmp #check",
+            "#check",
+            "blt #negative",
+            "ldi r1, 1",
+            "#negative",
+            "st r1, 10",
+            "ret",
+        ];
+        check_rule("fold-immediate", &program, 0);
+    }
+
+    #[test]
+    fn fold_immediate_before_compare() {
+        let program = [
+            "ldi r0, 5",
+            "subi r0, 5",
+            "cmpi r0, 1",
+            "beq #one",
+            "st r0, 10",
+            "#one",
+            "ret",
+        ];
+        check_rule("fold-immediate", &program, 1);
+    }
+
+    #[test]
+    fn identity_op() {
+        let program = [
+            "ldi r0, 7",
+            "addi r0, 0",
+            "muli r0, 1",
+            "divi r0, 1",
+            "subi r0, 0",
+            "st r0, 10",
+            "ret",
+        ];
+        assert_eq!(check_rule("identity-op", &program, 4).len(), 3);
+    }
+
+    #[test]
+    fn identity_op_keeps_flags_read_by_branch() {
+        let program = [
+            "ldi r1, 0",
+            "ldi r0, 0",
+            "cmpi r0, 1",
+            "addi r0, 0",
+            "beq #zero",
+            "ldi r1, 1",
+            "#zero",
+            "st r1, 10",
+            "ret",
+        ];
+        check_rule("identity-op", &program, 0);
+    }
+
+    #[test]
+    fn jump_to_jump() {
+        let program = [
+            "ldi r0, 1",
+            "cmpi r0, 1",
+            "beq #first",
+            "ldi r0, 2",
+            "#first",
+            "jmp #second",
+            "ldi r0, 3",
+            "#second",
+            "st r0, 10",
+            "ret",
+        ];
+        let after: Vec<String> = check_rule("jump-to-jump", &program, 1);
+        assert_eq!(after[2], "beq #second");
+    }
+
+    #[test]
+    fn invert_branch() {
+        for value in ["3", "4"] {
+            let load: String = format!("ldi r0, {value}");
+            let program = [
+                &load,
+                "ldi r1, 0",
+                "cmpi r0, 3",
+                "beq #equal",
+                "jmp #done",
+                "#equal",
+                "ldi r1, 1",
+                "#done",
+                "st r1, 10",
+                "ret",
+            ];
+            let after: Vec<String> = check_rule("invert-branch", &program, 1);
+            assert_eq!(after[3], "bne #done");
+        }
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let program = [
+            "ldi r0, 1",
+            "jmp #skip",
+            "ldi r0, 2",
+            "st r0, 11",
+            "#skip",
+            "st r0, 10",
+            "ret",
+        ];
+        assert_eq!(check_rule("unreachable-code", &program, 1).len(), 5);
+    }
+}
@@ -5,6 +5,8 @@
 
 use super::ast::{self, Node, Value, ValueEnum, Variable};
 use super::lexer::{Token, TokenType};
+use super::stdlib::builtins::builtin;
+use crate::utils::literals::parse_int_literal;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
@@ -32,35 +34,15 @@ pub fn hash_variables(ast: &mut [Box<dyn Node>], path: &str) {
          */
         } else if let Some(func) = node.as_any_mut().downcast_mut::<ast::Function>() {
             let new_path: String = format!("{}{}", path, func.get_name());
+            hash_variables(&mut func.params, &new_path);
             hash_variables(&mut func.body.body, &new_path);
 
         /*
          * Hash variables inside assignment statement
          */
         } else if let Some(assign) = node.as_any_mut().downcast_mut::<ast::Assignment>() {
-            if let Some(var) = (*assign.var).as_any_mut().downcast_mut::<ast::Variable>() {
-                var.identifier = variable_hasher(&var.identifier, path).to_string();
-            }
-            if let Some(other_var) = assign
-                .expression
-                .as_any_mut()
-                .downcast_mut::<ast::Variable>()
-            {
-                other_var.identifier = variable_hasher(&other_var.identifier, path).to_string();
-            }
-            if let Some(bin_exp) = assign
-                .expression
-                .as_any_mut()
-                .downcast_mut::<ast::BinaryExpression>()
-            {
-                if let Some(var) = bin_exp.right.as_any_mut().downcast_mut::<ast::Variable>() {
-                    var.identifier = variable_hasher(&var.identifier, path).to_string();
-                }
-
-                if let Some(var) = bin_exp.left.as_any_mut().downcast_mut::<ast::Variable>() {
-                    var.identifier = variable_hasher(&var.identifier, path).to_string();
-                }
-            }
+            hash_expression(assign.var.as_mut(), path);
+            hash_expression(assign.expression.as_mut(), path);
 
         /*
          * Hash variables inside of code blocks
@@ -72,20 +54,7 @@ pub fn hash_variables(ast: &mut [Box<dyn Node>], path: &str) {
          * Hash variables inside if-statements
          */
         } else if let Some(branch) = node.as_any_mut().downcast_mut::<ast::Branch>() {
-            if let Some(left) = &mut branch.condition.left {
-                if let Some(l_var) = left.as_any_mut().downcast_mut::<ast::Variable>() {
-                    l_var.identifier = variable_hasher(&l_var.identifier, path).to_string();
-                }
-            }
-            if let Some(r_var) = branch
-                .condition
-                .right
-                .as_any_mut()
-                .downcast_mut::<ast::Variable>()
-            {
-                r_var.identifier = variable_hasher(&r_var.identifier, path).to_string();
-            }
-
+            hash_condition(&mut branch.condition, path);
             hash_variables(branch.true_body.body.as_mut_slice(), path);
 
             if let Some(false_body) = &mut branch.false_body {
@@ -96,19 +65,9 @@ pub fn hash_variables(ast: &mut [Box<dyn Node>], path: &str) {
          * Hash variables inside if-statements
          */
         } else if let Some(nid_loop) = node.as_any_mut().downcast_mut::<ast::Loop>() {
-            if let Some(left) = &mut nid_loop.condition.left {
-                if let Some(l_var) = left.as_any_mut().downcast_mut::<ast::Variable>() {
-                    l_var.identifier = variable_hasher(&l_var.identifier, path).to_string();
-                }
-            }
-            if let Some(r_var) = nid_loop
-                .condition
-                .right
-                .as_any_mut()
-                .downcast_mut::<ast::Variable>()
-            {
-                r_var.identifier = variable_hasher(&r_var.identifier, path).to_string();
-            }
+            hash_variables(nid_loop.init.body.as_mut_slice(), path);
+            hash_condition(&mut nid_loop.condition, path);
+            hash_variables(nid_loop.step.body.as_mut_slice(), path);
             hash_variables(nid_loop.body.body.as_mut_slice(), path);
 
         /*
@@ -116,17 +75,47 @@ pub fn hash_variables(ast: &mut [Box<dyn Node>], path: &str) {
          */
         } else if let Some(nid_return) = node.as_any_mut().downcast_mut::<ast::Return>() {
             if let Some(return_val) = &mut nid_return.return_value {
-                if let Some(var) = return_val.as_any_mut().downcast_mut::<ast::Variable>() {
-                    var.identifier = variable_hasher(&var.identifier, path).to_string();
-                }
-            }
-        } else if let Some(builtin) = node.as_any_mut().downcast_mut::<ast::Builtin>() {
-            for param in builtin.params.iter_mut() {
-                if let Some(var) = param.as_any_mut().downcast_mut::<ast::Variable>() {
-                    var.identifier = variable_hasher(&var.identifier, path).to_string();
-                }
+                hash_expression(return_val.as_mut(), path);
             }
+
+        /*
+         * Hash variables passed to builtin functions and function calls
+         */
+        } else if node.get_type() == ast::AstType::Builtin
+            || node.get_type() == ast::AstType::FunctionCall
+        {
+            hash_expression(node.as_mut(), path);
+        }
+    }
+}
+
+/// Hashes the variables in both operands of a condition, and in the conditions joined by it.
+fn hash_condition(condition: &mut ast::Condition, path: &str) {
+    if let Some(left) = &mut condition.left {
+        hash_expression(left.as_mut(), path);
+    }
+    hash_expression(condition.right.as_mut(), path);
+}
+
+/// Recursively hashes all variables found in an expression.
+fn hash_expression(node: &mut dyn Node, path: &str) {
+    if let Some(var) = node.as_any_mut().downcast_mut::<ast::Variable>() {
+        var.identifier = variable_hasher(&var.identifier, path).to_string();
+    } else if let Some(bin_exp) = node.as_any_mut().downcast_mut::<ast::BinaryExpression>() {
+        hash_expression(bin_exp.left.as_mut(), path);
+        hash_expression(bin_exp.right.as_mut(), path);
+    } else if let Some(builtin) = node.as_any_mut().downcast_mut::<ast::Builtin>() {
+        for param in builtin.params.iter_mut() {
+            hash_expression(param.as_mut(), path);
+        }
+    } else if let Some(call) = node.as_any_mut().downcast_mut::<ast::FunctionCall>() {
+        for arg in call.args.iter_mut() {
+            hash_expression(arg.as_mut(), path);
         }
+    } else if let Some(deref) = node.as_any_mut().downcast_mut::<ast::Deref>() {
+        hash_expression(deref.address.as_mut(), path);
+    } else if let Some(condition) = node.as_any_mut().downcast_mut::<ast::Condition>() {
+        hash_condition(condition, path);
     }
 }
 
@@ -189,24 +178,7 @@ fn parse_body(tokens: &mut VecDeque<Token>) -> Vec<Box<dyn ast::Node>> {
                     type_dec = None;
                 }
 
-                let assign_to_var: Box<dyn ast::Node> =
-                    build_var_or_value(tokens.pop_front().unwrap());
-
-                let assigned_to: Box<dyn ast::Node> =
-                    if tokens.front().unwrap().token_type == TokenType::BinaryOperator {
-                        let left = assign_to_var;
-                        let op = match tokens.pop_front().unwrap().value.as_str() {
-                            "+" => ast::BinaryOperator::Add,
-                            "-" => ast::BinaryOperator::Sub,
-                            "*" => ast::BinaryOperator::Mul,
-                            "/" => ast::BinaryOperator::Div,
-                            _ => panic!("Invalid token value!"),
-                        };
-                        let right = build_var_or_value(tokens.pop_front().unwrap());
-                        Box::new(ast::BinaryExpression { left, op, right })
-                    } else {
-                        assign_to_var
-                    };
+                let assigned_to: Box<dyn ast::Node> = build_expression(tokens);
 
                 // Return the assignment struct
                 Some(Box::new(ast::Assignment {
@@ -231,7 +203,15 @@ fn parse_body(tokens: &mut VecDeque<Token>) -> Vec<Box<dyn ast::Node>> {
              */
             TokenType::Identifier => {
                 if is_function(tokens) {
-                    Some(build_function(&token, tokens))
+                    // Function definitions are preceded by their return type
+                    if code_body
+                        .last()
+                        .is_some_and(|node| node.get_type() == ast::AstType::Type)
+                    {
+                        Some(build_function(&token, tokens))
+                    } else {
+                        Some(build_function_call(&token, tokens))
+                    }
                 } else {
                     // Return a variable
                     Some(build_var_or_value(token))
@@ -239,9 +219,25 @@ fn parse_body(tokens: &mut VecDeque<Token>) -> Vec<Box<dyn ast::Node>> {
             }
 
             /*
-             * While loops
+             * Pointers, either declared (int *p) or dereferenced to be written to (*p = x)
+             */
+            TokenType::Pointer => {
+                if code_body
+                    .last()
+                    .is_some_and(|node| node.get_type() == ast::AstType::Type)
+                {
+                    Some(build_var_or_value(pointer_name(token)))
+                } else {
+                    Some(build_deref(token, tokens))
+                }
+            }
+            TokenType::BinaryOperator if token.value == "*" => Some(build_deref(token, tokens)),
+
+            /*
+             * Loops, and break and continue inside of them
              */
-            TokenType::Loop => Some(build_loop(tokens)),
+            TokenType::Loop => Some(build_loop(&token, tokens)),
+            TokenType::LoopJump => Some(build_loop_jump(&token, tokens)),
 
             /*
              * Nid-lang macros
@@ -356,6 +352,7 @@ fn build_function(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::Funct
 
         let param: Box<dyn ast::Node> = match token.token_type {
             TokenType::Identifier => build_var_or_value(token),
+            TokenType::Pointer => build_var_or_value(pointer_name(token)),
             TokenType::TypeIndicator => match token.value.as_str() {
                 "int" => Box::new(ast::Type {
                     type_value: ast::ValueEnum::Int(0),
@@ -401,21 +398,25 @@ fn build_function(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::Funct
     })
 }
 
-/// Builds a builtin function node
+/// Builds a builtin function node. Arguments can be any expression, the builtin checks them.
 fn build_builtin(name: &str, tokens: &mut VecDeque<Token>) -> Box<ast::Builtin> {
     if tokens.pop_front().unwrap().token_type != TokenType::OpenParen {
         panic!("Expected parenthesis after builtin identifier!")
     }
 
     let mut params: Vec<Box<dyn Node>> = Vec::new();
-    let mut token: Token = tokens.pop_front().unwrap();
-
-    while token.token_type != TokenType::CloseParen {
-        if token.token_type != TokenType::Seperator {
-            params.push(build_var_or_value(token));
+    while tokens
+        .front()
+        .expect("Missing closing paren in builtin call!")
+        .token_type
+        != TokenType::CloseParen
+    {
+        params.push(build_expression(tokens));
+        if tokens.front().unwrap().token_type == TokenType::Seperator {
+            tokens.pop_front().unwrap();
         }
-        token = tokens.pop_front().unwrap();
     }
+    tokens.pop_front().unwrap(); // Remove )
 
     Box::new(ast::Builtin {
         identifier: name.to_string(),
@@ -423,13 +424,92 @@ fn build_builtin(name: &str, tokens: &mut VecDeque<Token>) -> Box<ast::Builtin>
     })
 }
 
-/// Build a loop Node at current position in tokens.
-fn build_loop(tokens: &mut VecDeque<Token>) -> Box<ast::Loop> {
+/// Builds a call to a function defined in NID code. Arguments can be any expression.
+fn build_function_call(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::FunctionCall> {
     if tokens.pop_front().unwrap().token_type != TokenType::OpenParen {
-        panic!("Invalid If-statement! No parenthesis!");
+        panic!("Expected parenthesis after function name!")
     }
 
-    let condition = build_condition(tokens);
+    let mut args: Vec<Box<dyn Node>> = Vec::new();
+    while tokens
+        .front()
+        .expect("Missing closing paren in function call!")
+        .token_type
+        != TokenType::CloseParen
+    {
+        args.push(build_expression(tokens));
+        if tokens.front().unwrap().token_type == TokenType::Seperator {
+            tokens.pop_front().unwrap();
+        }
+    }
+    tokens.pop_front().unwrap(); // Remove )
+
+    Box::new(ast::FunctionCall {
+        identifier: token.value.clone(),
+        args,
+    })
+}
+
+/// Build a loop Node at current position in tokens. token is the while, for or do starting it.
+fn build_loop(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::Loop> {
+    let empty = || ast::Block { body: Vec::new() };
+
+    // do { body } while (condition);
+    if token.value == "do" {
+        if tokens.pop_front().unwrap().token_type != TokenType::OpenScope {
+            panic!("Missing loop body!");
+        }
+        let body: ast::Block = ast::Block {
+            body: parse_body(tokens),
+        };
+        if tokens.pop_front().unwrap().value != "while"
+            || tokens.pop_front().unwrap().token_type != TokenType::OpenParen
+        {
+            panic!("Expected while (condition) after the body of do!");
+        }
+        let condition = build_condition(tokens);
+        if tokens.pop_front().unwrap().token_type != TokenType::Eol {
+            panic!("Missing ;");
+        }
+
+        return Box::new(ast::Loop {
+            kind: ast::LoopKind::DoWhile,
+            init: empty(),
+            condition,
+            step: empty(),
+            body,
+        });
+    }
+
+    if tokens.pop_front().unwrap().token_type != TokenType::OpenParen {
+        panic!("Invalid loop! No parenthesis!");
+    }
+
+    // for (init; condition; step), where the condition can be left out to loop forever
+    let (init, condition, step) = if token.value == "for" {
+        let init: ast::Block = build_statements(tokens, TokenType::Eol);
+        let condition: Box<ast::Condition> = if tokens.front().unwrap().token_type == TokenType::Eol
+        {
+            Box::new(ast::Condition {
+                operator: ast::ConditionalOperator::Eq,
+                left: Some(Box::new(Value {
+                    value: ValueEnum::Bool(true),
+                })),
+                right: Box::new(Value {
+                    value: ValueEnum::Int(1),
+                }),
+            })
+        } else {
+            build_logic_condition(tokens, "||")
+        };
+        if tokens.pop_front().unwrap().token_type != TokenType::Eol {
+            panic!("Missing ; after the condition of the for loop!");
+        }
+        let step: ast::Block = build_statements(tokens, TokenType::CloseParen);
+        (init, condition, step)
+    } else {
+        (empty(), build_condition(tokens), empty())
+    };
 
     if tokens.pop_front().unwrap().token_type != TokenType::OpenScope {
         panic!("Missing loop body!");
@@ -439,7 +519,54 @@ fn build_loop(tokens: &mut VecDeque<Token>) -> Box<ast::Loop> {
         body: parse_body(tokens),
     };
 
-    Box::new(ast::Loop { condition, body })
+    Box::new(ast::Loop {
+        kind: ast::LoopKind::While,
+        init,
+        condition,
+        step,
+        body,
+    })
+}
+
+/// Builds the statements up to the end token, such as the init and step of a for loop. Removes the
+/// end token.
+fn build_statements(tokens: &mut VecDeque<Token>, end: TokenType) -> ast::Block {
+    let mut statements: VecDeque<Token> = VecDeque::new();
+    let mut depth: usize = 0;
+    loop {
+        let token: Token = tokens.pop_front().expect("Missing end of for loop!");
+        match token.token_type {
+            TokenType::OpenParen => depth += 1,
+            TokenType::CloseParen if depth > 0 => depth -= 1,
+            _ if token.token_type == end => break,
+            _ => {}
+        }
+        statements.push_back(token);
+    }
+
+    // Ends like a block of its own, which parse_body stops at
+    for (value, token_type) in [(";", TokenType::Eol), ("}", TokenType::CloseScope)] {
+        statements.push_back(Token {
+            value: value.to_string(),
+            token_type,
+        });
+    }
+    ast::Block {
+        body: parse_body(&mut statements),
+    }
+}
+
+/// Builds a break or continue, which has to be followed by ;.
+fn build_loop_jump(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::LoopJump> {
+    if tokens.pop_front().unwrap().token_type != TokenType::Eol {
+        panic!("Missing ; after {}!", token.value);
+    }
+    Box::new(ast::LoopJump {
+        kind: match token.value.as_str() {
+            "break" => ast::LoopJumpKind::Break,
+            _ => ast::LoopJumpKind::Continue,
+        },
+    })
 }
 
 /// Builds a return Node at current position in tokens.
@@ -449,7 +576,8 @@ fn build_return(tokens: &mut VecDeque<Token>) -> Box<ast::Return> {
     let return_value: Option<Box<dyn ast::Node>> = if token.token_type == TokenType::Eol {
         None
     } else {
-        Some(build_var_or_value(token))
+        tokens.push_front(token);
+        Some(build_expression(tokens))
     };
 
     // Make sure user doesn't try to return anything else, and didn't forget about ';'
@@ -473,7 +601,7 @@ fn build_var_or_value(token: Token) -> Box<dyn ast::Node> {
     // Else assume, Value
     let val = match token.token_type {
         TokenType::Integer => Value {
-            value: ValueEnum::Int(token.value.parse::<i16>().unwrap()),
+            value: ValueEnum::Int(parse_int(&token.value, false)),
         },
         TokenType::Floating => Value {
             value: ValueEnum::Float(token.value.parse::<f32>().unwrap()),
@@ -493,88 +621,378 @@ fn build_var_or_value(token: Token) -> Box<dyn ast::Node> {
     Box::new(val)
 }
 
-/// Helper function used to build conditions for both Branches and Loops
+/// Parses an integer literal, negated when it follows a unary minus. Literals up to 65535 are kept as
+/// their 16 bits, which makes them negative from 32768, while negative ones go down to -32768.
+fn parse_int(literal: &str, negative: bool) -> i16 {
+    let value: i64 =
+        parse_int_literal(literal).unwrap_or_else(|| panic!("Invalid integer literal {literal}!"));
+    let value: i64 = if negative { -value } else { value };
+    if !(i16::MIN as i64..=u16::MAX as i64).contains(&value) {
+        panic!(
+            "Integer {}{literal} doesn't fit in 16 bits!",
+            if negative { "-" } else { "" }
+        );
+    }
+    value as i16
+}
+
+/// Parses a literal used as an address, which can't be negative.
+fn parse_addr(literal: &str) -> Option<u16> {
+    parse_int_literal(literal).and_then(|value| u16::try_from(value).ok())
+}
+
+/// Helper function used to build conditions for both Branches and Loops. Also removes the closing
+/// paren of the branch or loop.
 fn build_condition(tokens: &mut VecDeque<Token>) -> Box<ast::Condition> {
-    // If is_pressed() was sent
-    if tokens.front().unwrap().token_type == TokenType::BuiltIn {
-        let token = tokens.pop_front().unwrap();
-        if token.value != "is_pressed" {
-            panic!("Invalid builtin function sent as condition!")
-        }
-        let builtin = build_builtin(&token.value, tokens);
-        tokens.pop_front().unwrap(); // Remove the closing paren
-        return Box::new(ast::Condition {
-            operator: ast::ConditionalOperator::Eq,
-            left: None,
-            right: builtin,
+    let condition: Box<ast::Condition> = build_logic_condition(tokens, "||");
+    if tokens.pop_front().unwrap().token_type != TokenType::CloseParen {
+        panic!("No closing paren!")
+    }
+    condition
+}
+
+/// Builds conditions joined by || or &&. || binds looser, so its sides are && conditions.
+fn build_logic_condition(tokens: &mut VecDeque<Token>, op: &str) -> Box<ast::Condition> {
+    let build_side = |tokens: &mut VecDeque<Token>| match op {
+        "||" => build_logic_condition(tokens, "&&"),
+        _ => build_not_condition(tokens),
+    };
+
+    let mut condition: Box<ast::Condition> = build_side(tokens);
+    while tokens.front().is_some_and(|token| token.value == op) {
+        tokens.pop_front().unwrap();
+        let right: Box<ast::Condition> = build_side(tokens);
+        condition = Box::new(ast::Condition {
+            operator: match op {
+                "||" => ast::ConditionalOperator::Or,
+                _ => ast::ConditionalOperator::And,
+            },
+            left: Some(condition),
+            right,
         });
     }
+    condition
+}
 
-    // If only one token was sent as param (eg. while(true) or while(x))
-    if tokens[1].token_type == TokenType::CloseParen {
-        if tokens.front().unwrap().token_type == TokenType::Bool
-            || tokens.front().unwrap().token_type == TokenType::Identifier
-        {
-            let working_token = tokens.pop_front().unwrap();
-            tokens.pop_front().unwrap(); // Remove the closing paren
+/// Builds a single condition, which may be negated with !.
+fn build_not_condition(tokens: &mut VecDeque<Token>) -> Box<ast::Condition> {
+    if tokens.front().unwrap().value != "!" {
+        return build_comparison(tokens);
+    }
+    tokens.pop_front().unwrap();
+
+    // ! in front of a condition negates it, eg. !is_pressed(k) or !(a < b)
+    let front: &Token = tokens.front().unwrap();
+    let is_condition: bool = front.value == "!"
+        || is_condition_group(tokens)
+        || (front.token_type == TokenType::BuiltIn && builtin(&front.value).in_condition);
+    let right: Box<dyn ast::Node> = if is_condition {
+        build_not_condition(tokens)
+    } else {
+        // ! in front of a value checks that it is 0, eg. !x
+        build_binary_expression(tokens, 0)
+    };
+
+    Box::new(ast::Condition {
+        operator: ast::ConditionalOperator::Not,
+        left: None,
+        right,
+    })
+}
+
+/// Builds a comparison, a condition in parens, or a single value or builtin checked on its own.
+fn build_comparison(tokens: &mut VecDeque<Token>) -> Box<ast::Condition> {
+    // The sides are plain expressions, a < b < c has to be written as (a < b) < c
+    let left_op: Box<dyn ast::Node> = if is_condition_group(tokens) {
+        tokens.pop_front().unwrap(); // Remove (
+        let group: Box<ast::Condition> = build_condition(tokens);
+
+        // The group can still be the first operand of a longer expression, eg. (a < b) == c
+        let is_operand: bool = peek_binary_operator(tokens).is_some()
+            || tokens
+                .front()
+                .is_some_and(|token| comparison_operator(token).is_some());
+        if !is_operand {
+            return group;
+        }
+        extend_binary_expression(tokens, group, 0)
+    } else {
+        build_binary_expression(tokens, 0)
+    };
+    let operator: ast::ConditionalOperator = match comparison_operator(tokens.front().unwrap()) {
+        Some(operator) => operator,
+        None => {
+            // Builtins such as is_pressed() can be checked on their own
+            let is_builtin_condition: bool = left_op
+                .as_any()
+                .downcast_ref::<ast::Builtin>()
+                .is_some_and(|call| builtin(&call.identifier).in_condition);
+            if is_builtin_condition {
+                return Box::new(ast::Condition {
+                    operator: ast::ConditionalOperator::Eq,
+                    left: None,
+                    right: left_op,
+                });
+            }
+
+            // If only one operand was sent as param (eg. while(true) or while(x))
             return Box::new(ast::Condition {
                 operator: ast::ConditionalOperator::Eq,
-                left: Some(build_var_or_value(working_token)),
+                left: Some(left_op),
                 right: Box::new(Value {
                     value: ValueEnum::Int(1),
                 }),
             });
         }
-        panic!(
-            "Invalid type supplied alone to while loop! Type: {:?}",
-            tokens.front().unwrap()
-        )
+    };
+    tokens.pop_front().unwrap();
+
+    let right_op = build_binary_expression(tokens, 0);
+    Box::new(ast::Condition {
+        operator,
+        left: Some(left_op),
+        right: right_op,
+    })
+}
+
+/// Returns the comparison operator of a token, if it is one.
+fn comparison_operator(token: &Token) -> Option<ast::ConditionalOperator> {
+    match token.value.as_str() {
+        "!=" => Some(ast::ConditionalOperator::NotEq),
+        "==" => Some(ast::ConditionalOperator::Eq),
+        ">" => Some(ast::ConditionalOperator::GreatThan),
+        "<" => Some(ast::ConditionalOperator::LessThan),
+        ">=" => Some(ast::ConditionalOperator::GreatEq),
+        "<=" => Some(ast::ConditionalOperator::LessEq),
+        _ => None,
+    }
+}
+
+/// Returns whether the tokens start with parens around a condition, rather than around an
+/// expression like (a + b).
+fn is_condition_group(tokens: &VecDeque<Token>) -> bool {
+    if tokens.front().unwrap().token_type != TokenType::OpenParen {
+        return false;
+    }
+
+    let mut depth: usize = 0;
+    for token in tokens.iter() {
+        match token.token_type {
+            TokenType::OpenParen => depth += 1,
+            TokenType::CloseParen => {
+                depth -= 1;
+                if depth == 0 {
+                    return false;
+                }
+            }
+            TokenType::LogicOperator | TokenType::Comparison => return true,
+            TokenType::BuiltIn if builtin(&token.value).in_condition => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Builds an expression at current position in tokens, such as `a * 4 + 1`. Stops at the first
+/// token that can't continue the expression, like ; or a closing paren. Comparisons and logic
+/// operators turn the expression into a condition, which is 1 when true and 0 when false.
+fn build_expression(tokens: &mut VecDeque<Token>) -> Box<dyn ast::Node> {
+    if is_condition_expression(tokens) {
+        return build_logic_condition(tokens, "||");
+    }
+    build_binary_expression(tokens, 0)
+}
+
+/// Returns whether the expression at the front of tokens compares or combines values, eg.
+/// `hp > 0` or `!done`. Only looks outside of parens, those are checked when they are parsed.
+fn is_condition_expression(tokens: &VecDeque<Token>) -> bool {
+    let mut depth: usize = 0;
+    for token in tokens.iter() {
+        match token.token_type {
+            TokenType::OpenParen => depth += 1,
+            TokenType::CloseParen if depth == 0 => return false,
+            TokenType::CloseParen => depth -= 1,
+            TokenType::Eol | TokenType::Seperator | TokenType::OpenScope if depth == 0 => {
+                return false
+            }
+            TokenType::LogicOperator | TokenType::Comparison if depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Precedence climbing. Keeps adding operators to the expression as long as they bind at least as
+/// hard as min_precedence, letting the recursive calls handle the operators binding harder.
+fn build_binary_expression(tokens: &mut VecDeque<Token>, min_precedence: u8) -> Box<dyn ast::Node> {
+    let left: Box<dyn ast::Node> = build_operand(tokens);
+    extend_binary_expression(tokens, left, min_precedence)
+}
+
+/// Continues an expression whose first operand has already been built.
+fn extend_binary_expression(
+    tokens: &mut VecDeque<Token>,
+    mut left: Box<dyn ast::Node>,
+    min_precedence: u8,
+) -> Box<dyn ast::Node> {
+    while let Some(op) = peek_binary_operator(tokens) {
+        let precedence: u8 = operator_precedence(&op);
+        if precedence < min_precedence {
+            break;
+        }
+
+        // The lexer reads `a *b` as a pointer, so the pointer is split back into * and b
+        let token: Token = tokens.pop_front().unwrap();
+        if token.token_type == TokenType::Pointer {
+            tokens.push_front(pointer_name(token));
+        }
+
+        let right: Box<dyn ast::Node> = build_binary_expression(tokens, precedence + 1);
+        left = Box::new(ast::BinaryExpression { left, op, right });
+    }
+
+    left
+}
+
+/// Builds a single operand of an expression, which is either a variable, a value, a function call,
+/// a dereferenced pointer or an expression inside of parenthesis.
+fn build_operand(tokens: &mut VecDeque<Token>) -> Box<dyn ast::Node> {
+    let token: Token = tokens.pop_front().expect("Expected expression!");
+
+    // -x is 0 - x, while negative literals are constants of their own
+    if token.token_type == TokenType::BinaryOperator && token.value == "-" {
+        if tokens
+            .front()
+            .is_some_and(|t| t.token_type == TokenType::Integer)
+        {
+            return Box::new(Value {
+                value: ValueEnum::Int(parse_int(&tokens.pop_front().unwrap().value, true)),
+            });
+        }
+        return Box::new(ast::BinaryExpression {
+            left: Box::new(Value {
+                value: ValueEnum::Int(0),
+            }),
+            op: ast::BinaryOperator::Sub,
+            right: build_operand(tokens),
+        });
     }
 
-    let left_op: Option<Box<dyn ast::Node>> = if tokens.front().unwrap().token_type
-        != TokenType::Comparison
-        && tokens.front().unwrap().token_type != TokenType::LogicOperator
+    // ~x flips every bit, which is the same as x ^ -1
+    if token.token_type == TokenType::BinaryOperator && token.value == "~" {
+        return Box::new(ast::BinaryExpression {
+            left: build_operand(tokens),
+            op: ast::BinaryOperator::Xor,
+            right: Box::new(Value {
+                value: ValueEnum::Int(-1),
+            }),
+        });
+    }
+
+    if token.token_type == TokenType::Pointer
+        || (token.token_type == TokenType::BinaryOperator && token.value == "*")
     {
-        Some(build_var_or_value(tokens.pop_front().unwrap()))
-    } else {
-        None
-    };
+        return build_deref(token, tokens);
+    }
+
+    if token.token_type == TokenType::OpenParen {
+        let expression = build_expression(tokens);
+        if tokens.pop_front().unwrap().token_type != TokenType::CloseParen {
+            panic!("No closing paren in expression!")
+        }
+        return expression;
+    }
+
+    if token.token_type == TokenType::Identifier && is_function(tokens) {
+        return build_function_call(&token, tokens);
+    }
 
-    let operator: ast::ConditionalOperator = match tokens.pop_front().unwrap().value.as_str() {
-        "!" => ast::ConditionalOperator::Not,
-        "!=" => ast::ConditionalOperator::NotEq,
-        "==" => ast::ConditionalOperator::Eq,
-        ">" => ast::ConditionalOperator::GreatThan,
-        "<" => ast::ConditionalOperator::LessThan,
-        ">=" => ast::ConditionalOperator::GreatEq,
-        "<=" => ast::ConditionalOperator::LessEq,
-        _ => panic!("Invalid operaor!"),
+    if token.token_type == TokenType::BuiltIn {
+        return build_builtin(&token.value, tokens);
+    }
+
+    build_var_or_value(token)
+}
+
+/// Builds a dereference from either a pointer token (*p) or a * in front of an operand (*(p + 1)).
+fn build_deref(token: Token, tokens: &mut VecDeque<Token>) -> Box<ast::Deref> {
+    let address: Box<dyn ast::Node> = if token.token_type == TokenType::Pointer {
+        build_var_or_value(pointer_name(token))
+    } else {
+        build_operand(tokens)
     };
+    Box::new(ast::Deref { address })
+}
 
-    let right_op = build_var_or_value(tokens.pop_front().unwrap());
+/// Turns a pointer token (*p) into the identifier of the pointer variable.
+fn pointer_name(token: Token) -> Token {
+    Token {
+        value: token.value[1..].to_string(),
+        token_type: TokenType::Identifier,
+    }
+}
 
-    if tokens.pop_front().unwrap().token_type != TokenType::CloseParen {
-        panic!("No closing paren!")
+/// Returns the binary operator at the front of tokens, without removing it.
+fn peek_binary_operator(tokens: &VecDeque<Token>) -> Option<ast::BinaryOperator> {
+    let token: &Token = tokens.front()?;
+    match token.token_type {
+        TokenType::BinaryOperator => match token.value.as_str() {
+            "+" => Some(ast::BinaryOperator::Add),
+            "-" => Some(ast::BinaryOperator::Sub),
+            "*" => Some(ast::BinaryOperator::Mul),
+            "/" => Some(ast::BinaryOperator::Div),
+            "%" => Some(ast::BinaryOperator::Mod),
+            "&" => Some(ast::BinaryOperator::And),
+            "|" => Some(ast::BinaryOperator::Or),
+            "^" => Some(ast::BinaryOperator::Xor),
+            "<<" => Some(ast::BinaryOperator::Shl),
+            ">>" => Some(ast::BinaryOperator::Shr),
+            "~" => panic!("~ only takes a single operand, eg. ~x!"),
+            _ => panic!("Invalid token value!"),
+        },
+        TokenType::Pointer => Some(ast::BinaryOperator::Mul),
+        _ => None,
     }
+}
 
-    Box::new(ast::Condition {
-        operator,
-        left: left_op,
-        right: right_op,
-    })
+/// Returns how hard an operator binds. Higher binds harder. Same order as in C, except that all of
+/// them bind harder than comparisons, so `flags & 4 == 4` works as expected.
+fn operator_precedence(op: &ast::BinaryOperator) -> u8 {
+    match op {
+        ast::BinaryOperator::Or => 1,
+        ast::BinaryOperator::Xor => 2,
+        ast::BinaryOperator::And => 3,
+        ast::BinaryOperator::Shl | ast::BinaryOperator::Shr => 4,
+        ast::BinaryOperator::Add | ast::BinaryOperator::Sub => 5,
+        ast::BinaryOperator::Mul | ast::BinaryOperator::Div | ast::BinaryOperator::Mod => 6,
+    }
 }
 
 /// Performs some checks and returns a Macro type if a valid existed.
 fn build_macro(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::Macro> {
     if let Some(macro_type) = get_macro_type(&token.value) {
+        // Attributes apply to the function following them and don't take a value
+        if macro_type == ast::MacroType::Inline || macro_type == ast::MacroType::NoInline {
+            return Box::new(ast::Macro {
+                macro_type,
+                macro_value: 0,
+            });
+        }
+
+        // #RESERVE name start end
+        if let ast::MacroType::Reserve { .. } = macro_type {
+            return Box::new(ast::Macro {
+                macro_type: build_reservation(tokens),
+                macro_value: 0,
+            });
+        }
+
         if tokens.front().unwrap().token_type == TokenType::Assignment {
             tokens.pop_front().unwrap();
             if let Some(value) = tokens.pop_front() {
                 return Box::new(ast::Macro {
                     macro_type,
-                    macro_value: value
-                        .value
-                        .parse::<u16>()
+                    macro_value: parse_addr(&value.value)
                         .expect("Expected u16 as value for macro!"),
                 });
             }
@@ -585,13 +1003,41 @@ fn build_macro(token: &Token, tokens: &mut VecDeque<Token>) -> Box<ast::Macro> {
     panic!("Invalid macro found!")
 }
 
+/// Builds the name and range of a #RESERVE macro.
+fn build_reservation(tokens: &mut VecDeque<Token>) -> ast::MacroType {
+    let name: Token = tokens
+        .pop_front()
+        .filter(|name| name.token_type == TokenType::Identifier)
+        .expect("Expected name after #RESERVE!");
+    let mut addr = || -> u16 {
+        tokens
+            .pop_front()
+            .filter(|addr| addr.token_type == TokenType::Integer)
+            .and_then(|addr| parse_addr(&addr.value))
+            .unwrap_or_else(|| {
+                panic!(
+                    "Expected start and end address after #RESERVE {}!",
+                    name.value
+                )
+            })
+    };
+    let start: u16 = addr();
+    let end: u16 = addr();
+
+    ast::MacroType::Reserve {
+        name: name.value.clone(),
+        start,
+        end,
+    }
+}
+
 /// Returns whether or not an identifier is for function.
 fn is_function(tokens: &mut VecDeque<Token>) -> bool {
     tokens.front().unwrap().token_type == TokenType::OpenParen
 }
 
 /// Hashes variables in the AST so that each variable gets a unique hash
-fn variable_hasher(var_name: &str, branch_path: &str) -> u32 {
+pub fn variable_hasher(var_name: &str, branch_path: &str) -> u32 {
     let mut hasher = DefaultHasher::new();
     var_name.hash(&mut hasher);
     branch_path.hash(&mut hasher);
@@ -603,6 +1049,187 @@ fn get_macro_type(m_type: &str) -> Option<ast::MacroType> {
     match m_type {
         "PREALLOCSTART" => Some(ast::MacroType::PreAllocStart),
         "PREALLOCEND" => Some(ast::MacroType::PreAllocEnd),
+        "INLINE" => Some(ast::MacroType::Inline),
+        "NOINLINE" => Some(ast::MacroType::NoInline),
+        "RESERVE" => Some(ast::MacroType::Reserve {
+            name: String::new(),
+            start: 0,
+            end: 0,
+        }),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::lexer::tokenize;
+
+    /// Writes an expression out with every binary expression in parenthesis.
+    fn show(node: &dyn Node) -> String {
+        if let Some(bin_exp) = node.as_any().downcast_ref::<ast::BinaryExpression>() {
+            format!(
+                "({} {:?} {})",
+                show(bin_exp.left.as_ref()),
+                bin_exp.op,
+                show(bin_exp.right.as_ref())
+            )
+        } else if let Some(val) = node.as_any().downcast_ref::<ast::Value>() {
+            val.value_as_i16().to_string()
+        } else {
+            "var".to_string()
+        }
+    }
+
+    /// Parses the expression assigned to x inside of main().
+    fn parse_expression(expression: &str) -> String {
+        let mut tokens = tokenize(format!("int main() {{\nint x = {expression};\n}}"));
+        let ast = generate_ast(&mut tokens);
+        let assign = ast.body[ast.entry_point.unwrap()].get_body()[0]
+            .as_any()
+            .downcast_ref::<ast::Assignment>()
+            .expect("Expected an assignment");
+        show(assign.expression.as_ref())
+    }
+
+    #[test]
+    fn multiplication_binds_harder_than_addition() {
+        assert_eq!(parse_expression("1 + 2 * 3"), "(1 Add (2 Mul 3))");
+        assert_eq!(parse_expression("1 * 2 - 3"), "((1 Mul 2) Sub 3)");
+    }
+
+    #[test]
+    fn same_precedence_groups_from_the_left() {
+        assert_eq!(parse_expression("8 - 4 - 2"), "((8 Sub 4) Sub 2)");
+        assert_eq!(parse_expression("8 / 4 * 2"), "((8 Div 4) Mul 2)");
+    }
+
+    #[test]
+    fn bitwise_operators_bind_like_in_c() {
+        assert_eq!(
+            parse_expression("1 | 2 ^ 3 & 4"),
+            "(1 Or (2 Xor (3 And 4)))"
+        );
+        assert_eq!(
+            parse_expression("1 << 2 + 3 % 4"),
+            "(1 Shl (2 Add (3 Mod 4)))"
+        );
+    }
+
+    #[test]
+    fn minus_in_front_of_an_operand_negates_it() {
+        assert_eq!(parse_expression("-5 * 2"), "(-5 Mul 2)");
+        assert_eq!(parse_expression("-y + 1"), "((0 Sub var) Add 1)");
+        assert_eq!(parse_expression("3 - -2"), "(3 Sub -2)");
+        assert_eq!(parse_expression("-(y * 2)"), "(0 Sub (var Mul 2))");
+    }
+
+    #[test]
+    fn literals_are_16_bits() {
+        assert_eq!(parse_expression("-32768"), "-32768");
+        assert_eq!(parse_expression("65535"), "-1");
+        assert_eq!(parse_expression("32768"), "-32768");
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer 65536 doesn't fit in 16 bits!")]
+    fn literals_above_16_bits_are_rejected() {
+        parse_expression("65536");
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer -32769 doesn't fit in 16 bits!")]
+    fn literals_below_16_bits_are_rejected() {
+        parse_expression("-32769");
+    }
+
+    #[test]
+    fn tilde_flips_every_bit() {
+        assert_eq!(parse_expression("~y & 7"), "((var Xor -1) And 7)");
+    }
+
+    #[test]
+    fn parenthesis_group_expressions() {
+        assert_eq!(parse_expression("(1 + 2) * 3"), "((1 Add 2) Mul 3)");
+        assert_eq!(parse_expression("2 * (y - 1)"), "(2 Mul (var Sub 1))");
+    }
+
+    /// Parses the macro at the top of a program.
+    fn parse_macro(source: &str) -> String {
+        let mut tokens = tokenize(format!("{source}\nint main() {{\n}}"));
+        let ast = generate_ast(&mut tokens);
+        let nid_macro = ast.body[0]
+            .as_any()
+            .downcast_ref::<ast::Macro>()
+            .expect("Expected a macro");
+        format!("{:?}", nid_macro.macro_type)
+    }
+
+    #[test]
+    fn reserve_takes_a_name_and_a_range() {
+        assert_eq!(
+            parse_macro("#RESERVE sprites 100 131"),
+            "Reserve { name: \"sprites\", start: 100, end: 131 }"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected start and end address after #RESERVE sprites!")]
+    fn reserve_needs_both_ends_of_the_range() {
+        parse_macro("#RESERVE sprites 100");
+    }
+
+    /// Writes a condition out with every && and || in parenthesis.
+    fn show_condition(condition: &ast::Condition) -> String {
+        let side = |node: &dyn Node| match node.as_any().downcast_ref::<ast::Condition>() {
+            Some(condition) => show_condition(condition),
+            None => show(node),
+        };
+        match &condition.left {
+            Some(left) => format!(
+                "({} {:?} {})",
+                side(left.as_ref()),
+                condition.operator,
+                side(condition.right.as_ref())
+            ),
+            None => format!(
+                "{:?} {}",
+                condition.operator,
+                side(condition.right.as_ref())
+            ),
+        }
+    }
+
+    /// Parses the condition of an if statement inside of main().
+    fn parse_condition(condition: &str) -> String {
+        let mut tokens = tokenize(format!(
+            "int main() {{\nint x = 0;\nif ({condition}) {{\nx = 1;\n}}\n}}"
+        ));
+        let ast = generate_ast(&mut tokens);
+        let branch = ast.body[ast.entry_point.unwrap()].get_body()[1]
+            .as_any()
+            .downcast_ref::<ast::Branch>()
+            .expect("Expected an if statement");
+        show_condition(&branch.condition)
+    }
+
+    #[test]
+    fn and_binds_harder_than_or() {
+        assert_eq!(
+            parse_condition("x < 1 || x > 2 && x != 5"),
+            "((var LessThan 1) Or ((var GreatThan 2) And (var NotEq 5)))"
+        );
+        assert_eq!(
+            parse_condition("(x < 1 || x > 2) && x != 5"),
+            "(((var LessThan 1) Or (var GreatThan 2)) And (var NotEq 5))"
+        );
+    }
+
+    #[test]
+    fn not_applies_to_conditions_and_values() {
+        assert_eq!(parse_condition("!(x < 1)"), "Not (var LessThan 1)");
+        assert_eq!(parse_condition("!x"), "Not var");
+        assert_eq!(parse_condition("(x + 1) > 2"), "((var Add 1) GreatThan 2)");
+        assert_eq!(parse_condition("(x < 1) == 0"), "((var LessThan 1) Eq 0)");
+    }
+}
@@ -0,0 +1,152 @@
+/*
+* Registry of the builtin functions, such as sleep() and is_pressed().
+*
+* Every builtin declares its parameters, what it returns, where it can be used and how it is turned
+* into code. The lexer, the parser, the IR builder and the code generation all look builtins up
+* here, so a new builtin only has to be added to BUILTINS along with its codegen.
+*/
+
+use super::input::is_pressed;
+use super::mem::{heap_end, heap_start};
+use super::utils::sleep;
+use crate::utils::hardware_conf::MemRegion;
+
+/// What an argument of a builtin has to be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamType {
+    Int,      // Any expression, evaluated at runtime unless it is a constant
+    Const,    // Expression the compiler can work out, for instructions that only take a constant
+    Variable, // Name of a variable
+}
+
+/// Argument of a builtin once registers are allocated. Arguments that aren't constants are always
+/// loaded into a register first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arg {
+    Const(i16),
+    Reg(u8),
+}
+
+/// What a builtin gives back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnType {
+    Void,
+    Int,
+    Bool,
+}
+
+/// How a builtin is turned into code.
+#[derive(Clone, Copy)]
+pub enum Codegen {
+    /// Statement lowered straight to ASS from its arguments
+    Inst(fn(&[Arg]) -> Vec<String>),
+    /// Condition that jumps to the branch when true
+    Branch(fn(&[Arg], &str) -> Vec<String>),
+    /// Value known at compile time from the memory regions
+    Value(fn(&[MemRegion]) -> i16),
+    /// Moves a variable to another address, which changes where it lives for the rest of the
+    /// program. Lowered to Inst::MoveTo, see stdlib::mem::move_to(). Addresses only known at
+    /// runtime can't move the variable, so its value is written there instead.
+    MoveTo,
+}
+
+/// A single builtin function.
+pub struct BuiltinDef {
+    pub name: &'static str,
+    pub params: &'static [ParamType],
+    pub returns: ReturnType,
+    pub in_expression: bool,
+    pub in_condition: bool,
+    pub codegen: Codegen,
+}
+
+const BUILTINS: &[BuiltinDef] = &[
+    BuiltinDef {
+        name: "sleep",
+        params: &[ParamType::Int],
+        returns: ReturnType::Void,
+        in_expression: false,
+        in_condition: false,
+        codegen: Codegen::Inst(sleep),
+    },
+    BuiltinDef {
+        name: "move_to",
+        params: &[ParamType::Variable, ParamType::Int],
+        returns: ReturnType::Void,
+        in_expression: false,
+        in_condition: false,
+        codegen: Codegen::MoveTo,
+    },
+    BuiltinDef {
+        name: "is_pressed",
+        params: &[ParamType::Const],
+        returns: ReturnType::Bool,
+        in_expression: true,
+        in_condition: true,
+        codegen: Codegen::Branch(is_pressed),
+    },
+    BuiltinDef {
+        name: "heap_start",
+        params: &[],
+        returns: ReturnType::Int,
+        in_expression: true,
+        in_condition: false,
+        codegen: Codegen::Value(heap_start),
+    },
+    BuiltinDef {
+        name: "heap_end",
+        params: &[],
+        returns: ReturnType::Int,
+        in_expression: true,
+        in_condition: false,
+        codegen: Codegen::Value(heap_end),
+    },
+];
+
+/// Returns the builtin with the given name.
+pub fn lookup_builtin(name: &str) -> Option<&'static BuiltinDef> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// Returns the builtin with the given name. Only used after the name has been checked by the lexer.
+pub fn builtin(name: &str) -> &'static BuiltinDef {
+    lookup_builtin(name).unwrap_or_else(|| panic!("{name}() is not a builtin function!"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::lexer::tokenize;
+    use crate::compiler::lexer::TokenType;
+
+    #[test]
+    fn builtins_are_found_by_name() {
+        assert_eq!(builtin("move_to").params.len(), 2);
+        assert_eq!(builtin("is_pressed").returns, ReturnType::Bool);
+        assert_eq!(builtin("is_pressed").params, [ParamType::Const]);
+        assert!(lookup_builtin("alloc").is_none());
+    }
+
+    #[test]
+    fn every_builtin_is_lexed_as_one() {
+        for def in BUILTINS {
+            let tokens = tokenize(format!("{}();", def.name));
+            assert_eq!(tokens[0].token_type, TokenType::BuiltIn, "{}", def.name);
+        }
+    }
+
+    #[test]
+    fn builtins_with_a_value_can_be_used_in_expressions() {
+        for def in BUILTINS {
+            assert_eq!(
+                def.returns != ReturnType::Void,
+                def.in_expression,
+                "{}",
+                def.name
+            );
+            if matches!(def.codegen, Codegen::Branch(_)) {
+                assert!(def.in_condition, "{}", def.name);
+            }
+        }
+    }
+}
@@ -2,7 +2,16 @@
 * Code related to inputs
 */
 
-/// Generates code to test if kb scancode is pressed
-pub fn is_pressed(scancode: u16, branch_name: &str) -> Vec<String> {
-    vec![format!("kbd, {scancode}"), format!("byk {branch_name}")]
+use super::builtins::Arg;
+
+/// Generates code to test if kb scancode is pressed. kbd only takes a constant, so the scancode is
+/// always known at compile time.
+pub fn is_pressed(args: &[Arg], branch_name: &str) -> Vec<String> {
+    match args {
+        [Arg::Const(scancode)] => vec![
+            format!("kbd, {}", *scancode as u16),
+            format!("byk {branch_name}"),
+        ],
+        _ => panic!("Invalid arguments supplied to is_pressed()!"),
+    }
 }
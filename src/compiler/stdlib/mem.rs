@@ -3,47 +3,56 @@
 */
 
 use crate::compiler::ass_gen::memory_manager::{
-    already_in_reg, get_reg, push_to_mem_map, read_from_dm, read_from_mem_map, remove_from_mem_map,
-    MAX_ADDR, PREALLOC_END, PREALLOC_START,
+    check_writable, is_scratch_addr, pin_addr, push_to_mem_map, region_of, remove_from_mem_map,
+    reserved_at, var_at,
 };
-
-/// Generates the required assembly code for allocating a variable in a specific mem_address
-pub fn move_to(var_id: u32, addr: u16) -> Vec<String> {
-    let mut instructions: Vec<String> = Vec::new();
-
-    unsafe {
-        if PREALLOC_START > addr || PREALLOC_END < addr {
-            println!("Warning: Trying to allocate memory inside compiler space! This may result in memory being overwritten/corrupted!");
-        }
+use crate::utils::hardware_conf::{MemRegion, RegionKind};
+
+/// Generates the required assembly code for allocating a variable in a specific mem_address. The
+/// value of the variable is expected to already be loaded into register.
+pub fn move_to(var_id: u32, addr: u16, register: u8) -> Vec<String> {
+    check_writable(addr);
+    if is_scratch_addr(addr) {
+        panic!("move_to() can't use {addr}, it is part of the scratch space of the compiler!");
     }
-    unsafe {
-        if addr > MAX_ADDR {
-            panic!("addr outside MAX_ADDR! | {addr} > {MAX_ADDR}")
-        }
+    if var_at(addr).is_some_and(|other| other != var_id) {
+        panic!("move_to() can't use {addr}, another variable is already placed there!");
+    }
+
+    let in_globals: bool = region_of(addr).is_some_and(|region| region.kind == RegionKind::Globals);
+    if in_globals && reserved_at(addr).is_none() {
+        println!("Warning: Trying to allocate memory inside compiler space! This may result in memory being overwritten/corrupted!");
     }
 
-    if let Some(reg) = already_in_reg(var_id) {
-        instructions.push(format!("st, r{reg}, {addr}"));
+    // Change the location of var_id in mem_map
+    remove_from_mem_map(var_id);
+    push_to_mem_map(var_id, addr);
+    pin_addr(addr);
 
-        // Change the location of var_id in mem_map
-        remove_from_mem_map(var_id);
-        push_to_mem_map(var_id, addr);
-    } else {
-        let reg = get_reg(Some(var_id));
-        let var_addr = read_from_mem_map(var_id);
+    vec![format!("st, r{register}, {addr}")]
+}
 
-        if var_addr.is_none() {
-            panic!("Invalid var_id supplied to write_to()!")
-        }
+/// Returns the first address of the heap region.
+pub fn heap_start(regions: &[MemRegion]) -> i16 {
+    heap_region("heap_start", regions).start as i16
+}
 
-        // Push relevant instructions to run
-        instructions.push(read_from_dm(reg, var_addr.unwrap()));
-        instructions.push(format!("st, r{reg}, {addr}"));
+/// Returns the last address of the heap region.
+pub fn heap_end(regions: &[MemRegion]) -> i16 {
+    heap_region("heap_end", regions).end as i16
+}
 
-        // Change the location of var_id in mem_map
-        remove_from_mem_map(var_id);
-        push_to_mem_map(var_id, addr);
+/// Finds the heap region, which has to hold at least the pointer to the free list and a block.
+fn heap_region<'a>(builtin: &str, regions: &'a [MemRegion]) -> &'a MemRegion {
+    let heap: &MemRegion = regions
+        .iter()
+        .find(|region| region.kind == RegionKind::Heap)
+        .unwrap_or_else(|| panic!("{builtin}() needs a heap region in the hardware config!"));
+    if heap.size() < 3 {
+        panic!(
+            "Heap region {} is too small, it needs at least 3 addresses!",
+            heap.name
+        );
     }
-
-    instructions
+    heap
 }
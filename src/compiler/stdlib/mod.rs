@@ -1,8 +1,106 @@
 /*
-* These files are not an actual stdlib, but rather and easy hack to replicate the behaviour of
-* stdlib.
+* The standard library of NID.
+*
+* The library itself is written in NID and lives in std/ at the root of the repo. Its modules are
+* built into nidc and imported by name, eg. `import heap;`, after which they are compiled just like
+* imported files. Functions in the prelude can be called without importing their module, and the
+* runtime functions are called by the compiler itself for operators the hardware has no instruction
+* for.
+*
+* The Rust files here are intrinsics, builtins that map straight to a few instructions and can't be
+* written in NID. They are listed in builtins.rs.
 */
 
+pub mod builtins;
 pub mod input;
 pub mod mem;
 pub mod utils;
+
+use crate::utils::hardware_conf::Hardware;
+
+/// Modules of the standard library, along with their source.
+const STD_MODULES: &[(&str, &str)] = &[
+    ("heap", include_str!("../../../std/heap.nid")),
+    ("math", include_str!("../../../std/math.nid")),
+    ("mem", include_str!("../../../std/mem.nid")),
+];
+
+/// Functions that can be called without importing their module, along with the module.
+const PRELUDE: &[(&str, &str)] = &[("alloc", "heap"), ("free", "heap")];
+
+/// Functions doing *, / and % in software, used when the hardware lacks mul or div. The remainder
+/// needs both, since it is calculated from a division and a multiplication.
+const RUNTIME: &[(&str, &str)] = &[("*", "math.mul"), ("/", "math.div"), ("%", "math.mod")];
+
+/// Sets up the heap, called at the start of main() when the heap module is used.
+pub const HEAP_INIT: &str = "heap.init";
+
+/// Returns the source of a module in the standard library.
+pub fn std_module(name: &str) -> Option<&'static str> {
+    STD_MODULES
+        .iter()
+        .find(|(module, _)| *module == name)
+        .map(|(_, source)| *source)
+}
+
+/// Returns the module of a function in the prelude.
+pub fn prelude_module(function: &str) -> Option<&'static str> {
+    PRELUDE
+        .iter()
+        .find(|(name, _)| *name == function)
+        .map(|(_, module)| *module)
+}
+
+/// Returns the runtime function doing a binary operator, if the hardware can't do it by itself.
+pub fn runtime_function(operator: &str, hardware_conf: &Hardware) -> Option<&'static str> {
+    let in_hardware: bool = match operator {
+        "*" => hardware_conf.has_mul,
+        "/" => hardware_conf.has_div,
+        "%" => hardware_conf.has_mul && hardware_conf.has_div,
+        _ => true,
+    };
+    RUNTIME
+        .iter()
+        .find(|(op, _)| *op == operator && !in_hardware)
+        .map(|(_, function)| *function)
+}
+
+/// Returns whether a function belongs to the standard library.
+pub fn is_std_function(name: &str) -> bool {
+    name.split_once('.')
+        .is_some_and(|(namespace, _)| std_module(namespace).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_modules_and_prelude_functions() {
+        assert!(std_module("heap").unwrap().contains("int alloc(int size)"));
+        assert_eq!(std_module("sound"), None);
+        assert_eq!(prelude_module("free"), Some("heap"));
+        assert_eq!(prelude_module("fill"), None);
+    }
+
+    #[test]
+    fn std_functions_are_told_apart_by_namespace() {
+        assert!(is_std_function("heap.alloc"));
+        assert!(is_std_function("mem.copy"));
+        assert!(!is_std_function("physics.gravity"));
+        assert!(!is_std_function("alloc"));
+    }
+
+    #[test]
+    fn runtime_functions_stand_in_for_missing_instructions() {
+        let no_div = Hardware {
+            has_div: false,
+            ..Hardware::default()
+        };
+        assert_eq!(runtime_function("*", &no_div), None);
+        assert_eq!(runtime_function("/", &no_div), Some("math.div"));
+        assert_eq!(runtime_function("%", &no_div), Some("math.mod"));
+        assert_eq!(runtime_function("+", &no_div), None);
+        assert_eq!(runtime_function("%", &Hardware::default()), None);
+    }
+}
@@ -2,7 +2,61 @@
 * Simple utility functions, currently only sleep.
 */
 
-/// Generates the assembly code for sleeping ms amount of milliseconds
-pub fn sleep(ms: u16) -> Vec<String> {
-    vec![format!("wait, {ms}")]
+use super::builtins::Arg;
+use crate::compiler::ass_gen::arithmetic::{self, AluOperand};
+use crate::compiler::ass_gen::memory_manager::{read_from_dm, scratch_addr};
+use crate::compiler::ir::builder::random_branch_name;
+
+/// Generates the assembly code for sleeping ms amount of milliseconds. wait only takes a constant,
+/// so a delay in a register sleeps a millisecond at a time until it runs out. The register is put
+/// back afterwards, since the delay may still be used.
+pub fn sleep(args: &[Arg]) -> Vec<String> {
+    match args {
+        [Arg::Const(ms)] => vec![format!("wait, {}", *ms as u16)],
+        [Arg::Reg(reg)] => {
+            let repeat: String = random_branch_name();
+            let done: String = random_branch_name();
+            let addr = scratch_addr(0);
+
+            let mut instructions: Vec<String> = vec![format!("st, r{reg}, {addr}"), repeat.clone()];
+            instructions.append(&mut arithmetic::cmp(*reg, AluOperand::Const(0)));
+            instructions.push(format!("beq {done}"));
+            instructions.push(format!("blt {done}"));
+            instructions.push(String::from("wait, 1"));
+            instructions.append(&mut arithmetic::sub(*reg, AluOperand::Const(1)));
+            instructions.push(format!("jmp {repeat}"));
+            instructions.push(done);
+            instructions.push(read_from_dm(*reg, addr));
+            instructions
+        }
+        _ => panic!("Invalid arguments supplied to sleep()!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::ass_gen::memory_manager::set_memory_layout;
+    use crate::utils::hardware_conf::{MemRegion, RegionKind};
+
+    #[test]
+    fn delays_in_registers_sleep_a_millisecond_at_a_time() {
+        let region = |name: &str, kind: RegionKind, start: u16, end: u16| MemRegion {
+            name: name.to_string(),
+            kind,
+            start,
+            end,
+        };
+        set_memory_layout(vec![
+            region("globals", RegionKind::Globals, 0, 199),
+            region("io", RegionKind::Mmio, 200, 255),
+        ]);
+
+        let code: Vec<String> = sleep(&[Arg::Reg(2)]);
+        assert_eq!(code.first().map(String::as_str), Some("st, r2, 198"));
+        assert_eq!(code.last().map(String::as_str), Some("ld, r2, 198"));
+        assert_eq!(code.iter().filter(|line| *line == "wait, 1").count(), 1);
+        assert!(code.iter().all(|line| !line.starts_with("wait, a")));
+        assert_eq!(sleep(&[Arg::Const(20)]), ["wait, 20"]);
+    }
 }
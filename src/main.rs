@@ -4,10 +4,10 @@ mod utils;
 
 use crate::utils::command_line::print_help;
 use crate::utils::hardware_conf::Hardware;
-use assembler::assemble::assemble_program;
+use assembler::assemble::{assemble_program, assemble_to_object_file, link_objects};
 use compiler::compile::compile;
 use std::time::{Duration, Instant};
-use utils::command_line::{build_args, Args};
+use utils::command_line::{build_args, Args, Emit};
 use utils::compile_times::{calc_total_time, time_now};
 
 use std::path::PathBuf;
@@ -20,10 +20,6 @@ fn main() {
         return;
     }
 
-    if args.compile_only && args.assemble_only {
-        panic!("Can't run compiler in compile only and assembly only modes at the same time!")
-    }
-
     if args.verbose {
         println!("Running in debug (verbose) mode!");
     }
@@ -39,22 +35,43 @@ fn main() {
 
     let start: Instant = time_now();
 
+    if args.link {
+        println!("Linking...");
+        let bin_file: PathBuf = link_objects(&args, &hardware_conf);
+        if args.verbose {
+            println!("Binary written to: {}", bin_file.display());
+        }
+        println!("Total compilation time: {:?}", calc_total_time(&start));
+        return;
+    }
+
     // Compile NID program
     let ass_out_file: Option<PathBuf> = if !args.assemble_only {
         println!("Compiling...");
-        Some(compile(&args, &hardware_conf))
+        compile(&args, &hardware_conf)
     } else {
-        None
+        Some(PathBuf::from(&args.filename))
     };
 
     // Run assembler
-    let bin_out_file: Option<PathBuf> = if !args.compile_only {
-        println!("Assembling...");
-        Some(assemble_program(&args, &ass_out_file.unwrap()))
-    } else {
-        None
+    let bin_out_file: Option<PathBuf> = match ass_out_file {
+        Some(ass_file) if args.emit == Emit::Bin => {
+            println!("Assembling...");
+            Some(assemble_program(&args, &ass_file, &hardware_conf))
+        }
+        Some(ass_file) if args.emit == Emit::Obj => {
+            println!("Assembling...");
+            Some(assemble_to_object_file(&args, &ass_file, &hardware_conf))
+        }
+        _ => None,
     };
 
+    if args.verbose {
+        if let Some(bin_file) = bin_out_file {
+            println!("Binary written to: {}", bin_file.display());
+        }
+    }
+
     // Print time
     let exec_time: Duration = calc_total_time(&start);
     println!("Total compilation time: {:?}", exec_time);
@@ -1,81 +1,532 @@
 /*
 * This file handles command line arguments sent to the compiler.
 *
-* TODO: Add a --output | -o flag to specify an ouput file.
+* Every option is described in OPTIONS, which is used both for parsing and for printing --help.
+* Options taking a value accept it either as the next argument or after an =, eg.
+* `--emit ir` or `--emit=ir`. Anything not starting with - is the input file.
+*
+* `nidc link a.o b.o` links object files instead, taking any number of them as input.
 */
 
+use crate::compiler::optimizations::pass_manager::{pass_names, OptLevel};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+/// How far to take the input before stopping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Ir,
+    Ass,
+    Obj,
+    Bin,
+}
 
 #[derive(Debug)]
 /// Possible args that can be used when running compiler.
 pub struct Args {
     pub filename: String,
+    pub link: bool,
+    pub objects: Vec<PathBuf>, // Object files to link
+    pub output: Option<PathBuf>,
     pub verbose: bool,
     pub help: bool,
     pub hardware_conf: PathBuf,
-    pub compile_only: bool,
+    pub include_paths: Vec<PathBuf>, // Where to look for imported files
     pub assemble_only: bool,
     pub string_output: bool,
+    pub mem_report: bool,
+    pub emit: Emit,
+    pub inline_threshold: Option<isize>,
+    pub opt_level: OptLevel,
+    pub pass_flags: Vec<(String, bool)>, // -f<pass> and -fno-<pass>, in the order given
+}
+
+/// A single command line option.
+struct CliOption {
+    short: Option<&'static str>,
+    long: &'static str,
+    value: Option<&'static str>, // Name of the value the option takes, if any
+    help: &'static str,
 }
 
-/// Reads and returns the correct command line args passed by user.
+const OPTIONS: &[CliOption] = &[
+    CliOption {
+        short: Some("-h"),
+        long: "--help",
+        value: None,
+        help: "Prints this message.",
+    },
+    CliOption {
+        short: Some("-v"),
+        long: "--verbose",
+        value: None,
+        help: "Run compiler in verbose mode.",
+    },
+    CliOption {
+        short: Some("-o"),
+        long: "--output",
+        value: Some("file"),
+        help: "Write the output to file.",
+    },
+    CliOption {
+        short: Some("-hc"),
+        long: "--hardware-conf",
+        value: Some("file"),
+        help: "Specify custom hardware configuration.",
+    },
+    CliOption {
+        short: Some("-I"),
+        long: "--include",
+        value: Some("dir"),
+        help: "Look for imported files in dir as well. Can be used several times.",
+    },
+    CliOption {
+        short: Some("-s"),
+        long: "--string-output",
+        value: None,
+        help: "Output binary as a text file, rather than actual binary file.",
+    },
+    CliOption {
+        short: Some("-c"),
+        long: "--compile-only",
+        value: None,
+        help: "Compile to ASS, without assembling to binary. Same as --emit=ass.",
+    },
+    CliOption {
+        short: Some("-a"),
+        long: "--assemble-only",
+        value: None,
+        help: "Only assemble a .ass file.",
+    },
+    CliOption {
+        short: None,
+        long: "--emit",
+        value: Some("tokens|ast|ir|ass|obj|bin"),
+        help: "Stop after the given stage. Tokens, AST and IR are printed.",
+    },
+    CliOption {
+        short: None,
+        long: "--mem-report",
+        value: None,
+        help: "Print the memory, call stack and registers used by every function.",
+    },
+    CliOption {
+        short: None,
+        long: "--inline-threshold",
+        value: Some("n"),
+        help: "Words the program may grow by when inlining functions.",
+    },
+];
+
+/// Reads and returns the correct command line args passed by user. Exits with an error message if
+/// they don't make sense.
 pub fn build_args() -> Args {
+    let cmd_line: Vec<String> = env::args().skip(1).collect();
+
+    match parse_args(&cmd_line) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            eprintln!("Run nidc --help to see the available options.");
+            exit(1);
+        }
+    }
+}
+
+/// Parses the command line args, not including the name of the executable.
+fn parse_args(cmd_line: &[String]) -> Result<Args, String> {
     let mut args: Args = Args {
         filename: String::new(),
+        link: false,
+        objects: Vec::new(),
+        output: None,
         verbose: false,
         help: false,
         hardware_conf: PathBuf::new(),
+        include_paths: Vec::new(),
         string_output: false,
-        compile_only: false,
+        mem_report: false,
         assemble_only: false,
+        emit: Emit::Bin,
+        inline_threshold: None,
+        opt_level: OptLevel::O2,
+        pass_flags: Vec::new(),
     };
+    let mut compile_only: bool = false;
+    let mut emit: Option<Emit> = None;
 
-    let cmd_line: Vec<String> = env::args().collect();
+    let mut arg_iter = cmd_line.iter().peekable();
+    if arg_iter.peek().is_some_and(|arg| *arg == "link") {
+        arg_iter.next();
+        args.link = true;
+    }
 
-    for (i, arg) in cmd_line.iter().enumerate() {
-        if arg.contains(".nid") || arg.contains(".ass") {
+    while let Some(arg) = arg_iter.next() {
+        if !arg.starts_with('-') && args.link {
+            args.objects.push(PathBuf::from(arg));
+            continue;
+        }
+        if !arg.starts_with('-') {
+            if !args.filename.is_empty() {
+                return Err(format!(
+                    "More than one input file given: {} and {arg}",
+                    args.filename
+                ));
+            }
             args.filename = arg.to_owned();
+            continue;
         }
-        if arg == "--verbose" || arg == "-v" {
-            args.verbose = true;
+
+        if let Some(level) = arg.strip_prefix("-O") {
+            args.opt_level = match level {
+                "0" => OptLevel::O0,
+                "1" => OptLevel::O1,
+                "2" => OptLevel::O2,
+                "s" => OptLevel::Os,
+                _ => return Err(format!("Unknown optimization level: {arg}")),
+            };
+            continue;
         }
-        if arg == "--help" || arg == "-h" {
-            args.help = true;
+        if let Some(dir) = arg.strip_prefix("-I").filter(|dir| !dir.is_empty()) {
+            args.include_paths.push(PathBuf::from(dir));
+            continue;
         }
-        if arg == "--hardware-conf" || arg == "-hc" {
-            args.hardware_conf = PathBuf::from(
-                cmd_line
-                    .get(i + 1)
-                    .expect("Error getting path from --hardware-conf!"),
-            );
+        if let Some(flag) = arg.strip_prefix("-f") {
+            args.pass_flags.push(parse_pass_flag(flag)?);
+            continue;
+        }
+
+        // Values can be passed as --option=value as well
+        let (flag, inline_value): (&str, Option<&str>) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        let Some(option) = OPTIONS
+            .iter()
+            .find(|option| option.long == flag || option.short == Some(flag))
+        else {
+            return Err(format!("Unknown option: {flag}"));
+        };
+
+        let value: Option<&str> = match (option.value, inline_value) {
+            (None, Some(_)) => return Err(format!("{} doesn't take a value", option.long)),
+            (None, None) => None,
+            (Some(_), Some(value)) => Some(value),
+            (Some(value_name), None) => match arg_iter.next() {
+                Some(value) => Some(value.as_str()),
+                None => return Err(format!("Missing {value_name} after {flag}")),
+            },
+        };
+
+        match (option.long, value) {
+            ("--help", _) => args.help = true,
+            ("--verbose", _) => args.verbose = true,
+            ("--output", Some(value)) => args.output = Some(PathBuf::from(value)),
+            ("--hardware-conf", Some(value)) => args.hardware_conf = PathBuf::from(value),
+            ("--include", Some(value)) => args.include_paths.push(PathBuf::from(value)),
+            ("--string-output", _) => args.string_output = true,
+            ("--mem-report", _) => args.mem_report = true,
+            ("--compile-only", _) => compile_only = true,
+            ("--assemble-only", _) => args.assemble_only = true,
+            ("--emit", Some(value)) => {
+                emit = Some(match value {
+                    "tokens" => Emit::Tokens,
+                    "ast" => Emit::Ast,
+                    "ir" => Emit::Ir,
+                    "ass" => Emit::Ass,
+                    "obj" => Emit::Obj,
+                    "bin" => Emit::Bin,
+                    _ => return Err(format!("Unknown stage passed to --emit: {value}")),
+                })
+            }
+            ("--inline-threshold", Some(value)) => {
+                args.inline_threshold = Some(
+                    value
+                        .parse::<isize>()
+                        .map_err(|_| format!("--inline-threshold expects a number, got {value}"))?,
+                )
+            }
+            _ => unreachable!("Option {} is not handled!", option.long),
         }
-        if arg == "--string-output" || arg == "-s" {
-            args.string_output = true;
+    }
+
+    if args.help {
+        return Ok(args);
+    }
+
+    // Make sure the modes don't contradict each other
+    if compile_only && args.assemble_only {
+        return Err(String::from(
+            "Can't run compiler in compile only and assembly only modes at the same time!",
+        ));
+    }
+    args.emit = match (emit, compile_only) {
+        (Some(emit), true) if emit != Emit::Ass => {
+            return Err(String::from(
+                "--compile-only can't be used with --emit other than ass",
+            ))
         }
-        if arg == "--compile-only" || arg == "-c" {
-            args.compile_only = true;
+        (Some(emit), _) => emit,
+        (None, true) => Emit::Ass,
+        (None, false) => Emit::Bin,
+    };
+    if args.assemble_only && args.emit != Emit::Bin && args.emit != Emit::Obj {
+        return Err(String::from("--assemble-only can only emit obj or bin"));
+    }
+
+    if args.link {
+        if compile_only || args.assemble_only || args.emit != Emit::Bin {
+            return Err(String::from(
+                "link only takes object files and outputs a binary",
+            ));
         }
-        if arg == "--assemble-only" || arg == "-a" {
-            args.assemble_only = true;
+        if args.objects.is_empty() {
+            return Err(String::from("No object files given to link"));
         }
+        if let Some(missing) = args.objects.iter().find(|object| !object.exists()) {
+            return Err(format!("Object file {} doesn't exist", missing.display()));
+        }
+    } else if args.filename.is_empty() {
+        return Err(String::from("No input file given"));
+    }
+    if !args.link {
+        // Assembling only takes ASS, everything else starts from NID
+        let extension: &str = if args.assemble_only { "ass" } else { "nid" };
+        let input: &Path = Path::new(&args.filename);
+        if input.extension().and_then(|ext| ext.to_str()) != Some(extension) {
+            return Err(format!(
+                "Input file {} isn't a .{extension} file",
+                args.filename
+            ));
+        }
+        if !input.exists() {
+            return Err(format!("Input file {} doesn't exist", args.filename));
+        }
+    }
+    if let Some(output) = &args.output {
+        let inputs: Vec<PathBuf> = match args.link {
+            true => args.objects.clone(),
+            false => vec![PathBuf::from(&args.filename)],
+        };
+        if inputs.iter().any(|input| same_file(input, output)) {
+            return Err(format!(
+                "Output file {} would overwrite the input",
+                output.display()
+            ));
+        }
+    }
+    if !args.hardware_conf.as_os_str().is_empty() && !args.hardware_conf.exists() {
+        return Err(format!(
+            "Hardware config {} doesn't exist",
+            args.hardware_conf.display()
+        ));
+    }
+
+    Ok(args)
+}
+
+/// Checks whether two paths lead to the same file. Paths that don't exist yet are compared as
+/// given.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
     }
+}
 
-    args
+/// Turns what follows -f into the pass it names and whether to turn it on, eg. no-inline is
+/// (inline, false).
+fn parse_pass_flag(flag: &str) -> Result<(String, bool), String> {
+    let (pass, on): (&str, bool) = match flag.strip_prefix("no-") {
+        Some(pass) => (pass, false),
+        None => (flag, true),
+    };
+    if !pass_names().contains(&pass) {
+        return Err(format!(
+            "Unknown optimization pass: {pass}. Known passes are: {}",
+            pass_names().join(", ")
+        ));
+    }
+    Ok((pass.to_string(), on))
 }
 
-/// Prints this not so helpful help message.
+/// Prints the help message, generated from OPTIONS.
 pub fn print_help() {
     let mut message: String = String::new();
     message.push_str("nidc [options] [target].nid\n");
+    message.push_str("nidc link [options] [objects].o\n");
     message.push_str("Options:\n");
-    message.push_str("-h  | --help                  Prints this message.\n");
-    message.push_str("-v  | --verbose               Run compiler in verbose mode.\n");
-    message.push_str("-hc | --hardware-conf         Specify custom hardware configuration.\n");
-    message.push_str("-s  | --string-output         Output binary as a text file, rather than actual binary file.\n");
-    message
-        .push_str("-c  | --compile-only          Compile to ASS, without assembling to binary.\n");
-    message.push_str("-a  | --assemble-only         Only assemble a .ass file.\n");
+
+    for option in OPTIONS.iter() {
+        let mut flags: String = match option.short {
+            Some(short) => format!("{short:<3} | {}", option.long),
+            None => format!("      {}", option.long),
+        };
+        if let Some(value) = option.value {
+            flags.push_str(&format!(" [{value}]"));
+        }
+        message.push_str(&format!("{flags:<38} {}\n", option.help));
+    }
+
+    message.push_str(&format!(
+        "{:<38} {}\n",
+        "-O0 | -O1 | -O2 | -Os", "Optimization level, defaults to -O2."
+    ));
+    message.push_str(&format!(
+        "{:<38} {}\n",
+        "-f[pass] | -fno-[pass]", "Turn a single optimization pass on or off."
+    ));
+    message.push_str(&format!("{:<38} Passes: {}\n", "", pass_names().join(", ")));
 
     println!("{}", message);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the args with an input file that exists.
+    fn parse(flags: &[&str]) -> Result<Args, String> {
+        let mut cmd_line: Vec<String> =
+            vec![concat!(env!("CARGO_MANIFEST_DIR"), "/std/math.nid").to_string()];
+        cmd_line.extend(flags.iter().map(|flag| flag.to_string()));
+        parse_args(&cmd_line)
+    }
+
+    #[test]
+    fn parses_pass_toggles_in_order() {
+        let args: Args = parse(&["-O1", "-finline", "-fno-dead-code", "-fdead-code"]).unwrap();
+        assert_eq!(args.opt_level, OptLevel::O1);
+        assert_eq!(
+            args.pass_flags,
+            [
+                ("inline".to_string(), true),
+                ("dead-code".to_string(), false),
+                ("dead-code".to_string(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn accepts_every_pass_name() {
+        for pass in pass_names() {
+            assert!(parse(&[&format!("-f{pass}")]).is_ok(), "-f{pass}");
+            assert!(parse(&[&format!("-fno-{pass}")]).is_ok(), "-fno-{pass}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_pass_names() {
+        for flag in ["-fbogus", "-fno-bogus", "-f", "-fno-", "-fno-no-inline"] {
+            let err: String = parse(&[flag])
+                .err()
+                .unwrap_or_else(|| panic!("{flag} was accepted"));
+            assert!(
+                err.starts_with("Unknown optimization pass"),
+                "{flag}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert_eq!(
+            parse(&["--bogus"]).err().unwrap(),
+            "Unknown option: --bogus"
+        );
+        assert_eq!(
+            parse(&["-O3"]).err().unwrap(),
+            "Unknown optimization level: -O3"
+        );
+    }
+
+    #[test]
+    fn takes_values_after_the_option_or_after_equals() {
+        let args: Args = parse(&["--inline-threshold", "8", "-o", "out.bin"]).unwrap();
+        assert_eq!(args.inline_threshold, Some(8));
+        assert_eq!(args.output, Some(PathBuf::from("out.bin")));
+
+        let args: Args = parse(&["--inline-threshold=-2", "--emit=ir"]).unwrap();
+        assert_eq!(args.inline_threshold, Some(-2));
+        assert_eq!(args.emit, Emit::Ir);
+
+        assert_eq!(
+            parse(&["--inline-threshold"]).err().unwrap(),
+            "Missing n after --inline-threshold"
+        );
+        assert_eq!(
+            parse(&["--verbose=yes"]).err().unwrap(),
+            "--verbose doesn't take a value"
+        );
+    }
+
+    #[test]
+    fn compile_only_emits_ass() {
+        assert_eq!(parse(&["-c"]).unwrap().emit, Emit::Ass);
+        assert!(parse(&["-c", "--emit", "ir"]).is_err());
+        assert!(parse(&["-c", "-a"]).is_err());
+    }
+
+    #[test]
+    fn mem_report_is_off_by_default() {
+        assert!(!parse(&[]).unwrap().mem_report);
+        assert!(parse(&["--mem-report"]).unwrap().mem_report);
+    }
+
+    #[test]
+    fn needs_exactly_one_existing_input_file() {
+        assert_eq!(parse_args(&[]).err().unwrap(), "No input file given");
+        assert!(parse(&["other.nid"])
+            .err()
+            .unwrap()
+            .starts_with("More than one input file given"));
+        assert_eq!(
+            parse_args(&["missing.nid".to_string()]).err().unwrap(),
+            "Input file missing.nid doesn't exist"
+        );
+    }
+
+    #[test]
+    fn input_file_has_to_be_nid_or_ass_when_assembling() {
+        assert_eq!(
+            parse_args(&["notes.txt".to_string()]).err().unwrap(),
+            "Input file notes.txt isn't a .nid file"
+        );
+        assert_eq!(
+            parse_args(&["-O2".to_string(), "2".to_string()])
+                .err()
+                .unwrap(),
+            "Input file 2 isn't a .nid file"
+        );
+        assert!(parse(&["-a"])
+            .err()
+            .unwrap()
+            .ends_with("math.nid isn't a .ass file"));
+    }
+
+    #[test]
+    fn output_can_not_overwrite_the_input() {
+        let input: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/std/math.nid");
+        assert_eq!(
+            parse(&["-o", input]).err().unwrap(),
+            format!("Output file {input} would overwrite the input")
+        );
+        assert!(parse(&["-o", "math.out"]).is_ok());
+    }
+
+    #[test]
+    fn collects_include_paths_in_order() {
+        let args: Args = parse(&["-Ilib", "--include", "std", "-I", "vendor"]).unwrap();
+        assert_eq!(
+            args.include_paths,
+            [
+                PathBuf::from("lib"),
+                PathBuf::from("std"),
+                PathBuf::from("vendor")
+            ]
+        );
+    }
+}
@@ -4,7 +4,20 @@
 *
 * Currently only TOML files are supported as config files.
 *
-* NOTE: Currently only mem_addresses & registers actually affects compilation.
+* DM can be split into named regions, each with a kind telling the compiler what it is used for:
+*   [[regions]]
+*   name = "screen"
+*   kind = "vram"
+*   start = 200
+*   end = 231
+* Variables are only placed in globals regions, and the hardware call stack lives in the single
+* stack region. Without any regions, DM holds globals followed by a call stack of 20 addresses.
+*
+* CPUs without a multiplier or divider set has_mul or has_div to false, which makes the compiler
+* call the software versions in the math module of the standard library instead.
+*
+* NOTE: Currently only mem_addresses, registers, has_mul, has_div & regions actually affect
+* compilation.
 * TODO: Implement support for extended / custom intructions.
 */
 
@@ -18,6 +31,52 @@ pub struct Hardware {
     pub mem_addresses: u16,          // Number of memory addresses available
     pub registers: u8,               // Number of registers available
     pub extended_instructions: bool, // Whether or not to use extended instruction set
+    #[serde(default = "has_instruction")]
+    pub has_mul: bool, // Whether the CPU can multiply, otherwise it is done in software
+    #[serde(default = "has_instruction")]
+    pub has_div: bool, // Whether the CPU can divide, otherwise it is done in software
+    #[serde(default)]
+    pub inline_threshold: Option<isize>, // Words the program may grow by when inlining
+    #[serde(default)]
+    pub regions: Vec<MemRegion>, // Layout of DM, see memory_layout()
+}
+
+/// A named range of DM addresses, both ends included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemRegion {
+    pub name: String,
+    pub kind: RegionKind,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// What a region of DM is used for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    Stack,   // The call stack of the hardware
+    Globals, // Variables placed by the compiler
+    Heap,    // Memory handed out at runtime
+    Mmio,    // Memory mapped I/O
+    Vram,    // Video RAM read by the screen
+}
+
+// Size of the call stack at the end of DM when no regions are configured
+const DEFAULT_STACK_SIZE: u16 = 20;
+
+/// Optional instructions are assumed to exist unless the config says otherwise.
+fn has_instruction() -> bool {
+    true
+}
+
+impl MemRegion {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.start <= addr && addr <= self.end
+    }
+
+    pub fn size(&self) -> u16 {
+        self.end - self.start + 1
+    }
 }
 
 impl Hardware {
@@ -27,8 +86,73 @@ impl Hardware {
             fs::read_to_string(filename).expect("Failed to read hardware config file!");
         let hardware_conf: Hardware =
             toml::from_str(content.trim()).expect("Failed to parse toml as hardware config!");
+        hardware_conf.check_regions();
         hardware_conf
     }
+
+    /// Returns the regions of DM, sorted by address. Unless the config says otherwise, everything
+    /// but the call stack at the end of DM holds globals.
+    pub fn memory_layout(&self) -> Vec<MemRegion> {
+        if !self.regions.is_empty() {
+            let mut regions: Vec<MemRegion> = self.regions.clone();
+            regions.sort_by_key(|region| region.start);
+            return regions;
+        }
+
+        let stack_start: u16 = self.mem_addresses - DEFAULT_STACK_SIZE;
+        vec![
+            MemRegion {
+                name: String::from("globals"),
+                kind: RegionKind::Globals,
+                start: 0,
+                end: stack_start - 1,
+            },
+            MemRegion {
+                name: String::from("stack"),
+                kind: RegionKind::Stack,
+                start: stack_start,
+                end: self.mem_addresses - 1,
+            },
+        ]
+    }
+
+    /// Makes sure the configured regions fit in DM without overlapping.
+    fn check_regions(&self) {
+        if self.regions.is_empty() {
+            if self.mem_addresses <= DEFAULT_STACK_SIZE {
+                panic!(
+                    "mem_addresses has to be more than {DEFAULT_STACK_SIZE} to fit the call stack!"
+                );
+            }
+            return;
+        }
+
+        let regions: Vec<MemRegion> = self.memory_layout();
+        for region in regions.iter() {
+            if region.start > region.end || region.end >= self.mem_addresses {
+                panic!(
+                    "Memory region {} ({}..{}) is not within the {} addresses of DM!",
+                    region.name, region.start, region.end, self.mem_addresses
+                );
+            }
+        }
+        for pair in regions.windows(2) {
+            if pair[1].start <= pair[0].end {
+                panic!(
+                    "Memory regions {} and {} overlap!",
+                    pair[0].name, pair[1].name
+                );
+            }
+        }
+
+        let count = |kind: RegionKind| regions.iter().filter(|r| r.kind == kind).count();
+        if count(RegionKind::Stack) != 1 {
+            panic!("The hardware config needs exactly one stack region!");
+        }
+        if count(RegionKind::Globals) == 0 {
+            panic!("The hardware config needs at least one globals region!");
+        }
+    }
 }
 
 impl Default for Hardware {
@@ -38,6 +162,104 @@ impl Default for Hardware {
             mem_addresses: 255,
             registers: 8,
             extended_instructions: false,
+            has_mul: true,
+            has_div: true,
+            inline_threshold: None,
+            regions: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str, kind: RegionKind, start: u16, end: u16) -> MemRegion {
+        MemRegion {
+            name: String::from(name),
+            kind,
+            start,
+            end,
+        }
+    }
+
+    fn with_regions(regions: Vec<MemRegion>) -> Hardware {
+        Hardware {
+            regions,
+            ..Hardware::default()
         }
     }
+
+    #[test]
+    fn default_layout_is_globals_then_call_stack() {
+        assert_eq!(
+            Hardware::default().memory_layout(),
+            vec![
+                region("globals", RegionKind::Globals, 0, 234),
+                region("stack", RegionKind::Stack, 235, 254),
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_regions_are_sorted_by_address() {
+        let hardware_conf = with_regions(vec![
+            region("stack", RegionKind::Stack, 230, 254),
+            region("screen", RegionKind::Vram, 100, 131),
+            region("vars", RegionKind::Globals, 0, 99),
+        ]);
+        hardware_conf.check_regions();
+        let names: Vec<String> = hardware_conf
+            .memory_layout()
+            .into_iter()
+            .map(|region| region.name)
+            .collect();
+        assert_eq!(names, ["vars", "screen", "stack"]);
+    }
+
+    #[test]
+    fn regions_are_read_from_toml() {
+        let hardware_conf: Hardware = toml::from_str(
+            "mem_addresses = 255\nregisters = 8\nextended_instructions = false\n\
+             [[regions]]\nname = \"keys\"\nkind = \"mmio\"\nstart = 200\nend = 201\n",
+        )
+        .unwrap();
+        assert_eq!(
+            hardware_conf.regions,
+            [region("keys", RegionKind::Mmio, 200, 201)]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Memory regions vars and screen overlap!")]
+    fn overlapping_regions_are_rejected() {
+        with_regions(vec![
+            region("vars", RegionKind::Globals, 0, 100),
+            region("screen", RegionKind::Vram, 100, 131),
+            region("stack", RegionKind::Stack, 200, 254),
+        ])
+        .check_regions();
+    }
+
+    #[test]
+    #[should_panic(expected = "is not within the 255 addresses of DM")]
+    fn regions_have_to_fit_in_dm() {
+        with_regions(vec![
+            region("vars", RegionKind::Globals, 0, 99),
+            region("stack", RegionKind::Stack, 240, 255),
+        ])
+        .check_regions();
+    }
+
+    #[test]
+    #[should_panic(expected = "needs exactly one stack region")]
+    fn needs_a_stack_region() {
+        with_regions(vec![region("vars", RegionKind::Globals, 0, 99)]).check_regions();
+    }
+
+    #[test]
+    #[should_panic(expected = "needs at least one globals region")]
+    fn needs_a_globals_region() {
+        with_regions(vec![region("stack", RegionKind::Stack, 0, 19)]).check_regions();
+    }
 }
@@ -0,0 +1,103 @@
+/*
+* Numeric literals, shared by the lexers of NID and ASS.
+*
+* Integers are written in decimal, or in hex, binary or octal with a 0x, 0b or 0o prefix. Digits can
+* be split up with _ to make them easier to read, eg. 0b1010_0001. The lexers keep the literal as
+* written, so that errors can show it, and it is turned into a number once its width is known.
+*/
+
+/// Returns the value of an integer literal, or None if it isn't a valid one.
+pub fn parse_int_literal(literal: &str) -> Option<i64> {
+    let digits: String = literal.replace('_', "");
+    let (radix, digits): (u32, &str) = match digits.get(..2).map(|p| p.to_ascii_lowercase()) {
+        Some(prefix) if prefix == "0x" => (16, &digits[2..]),
+        Some(prefix) if prefix == "0b" => (2, &digits[2..]),
+        Some(prefix) if prefix == "0o" => (8, &digits[2..]),
+        _ => (10, &digits[..]),
+    };
+
+    // from_str_radix also takes a sign, which isn't part of a literal
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+/// Returns the char written after a \ in a char literal, eg. n for a newline.
+pub fn escaped_char(escape: char) -> char {
+    match escape {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' | '\'' => escape,
+        _ => panic!("Unknown escape \\{escape} in char literal!"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_radix_in_either_case() {
+        let cases: &[(&str, i64)] = &[
+            ("42", 42),
+            ("0x2a", 42),
+            ("0X2A", 42),
+            ("0xFfFf", 65535),
+            ("0b101010", 42),
+            ("0B101010", 42),
+            ("0o52", 42),
+            ("0O52", 42),
+            ("0", 0),
+        ];
+        for (literal, value) in cases {
+            assert_eq!(parse_int_literal(literal), Some(*value), "{literal}");
+        }
+    }
+
+    #[test]
+    fn ignores_separators() {
+        assert_eq!(parse_int_literal("1_000"), Some(1000));
+        assert_eq!(parse_int_literal("0b1010_0001"), Some(0b1010_0001));
+        assert_eq!(parse_int_literal("0x_FF_FF"), Some(0xFFFF));
+    }
+
+    #[test]
+    fn rejects_invalid_literals() {
+        for literal in [
+            "", "_", "0x", "0b", "0o_", "0b2", "0o8", "0xG", "12a", "1.5",
+        ] {
+            assert_eq!(parse_int_literal(literal), None, "{literal}");
+        }
+    }
+
+    #[test]
+    fn rejects_signs() {
+        for literal in ["-1", "+1", "0x-1", "0b+1"] {
+            assert_eq!(parse_int_literal(literal), None, "{literal}");
+        }
+    }
+
+    #[test]
+    fn escapes_every_char() {
+        let cases: &[(char, char)] = &[
+            ('n', '\n'),
+            ('t', '\t'),
+            ('r', '\r'),
+            ('0', '\0'),
+            ('\\', '\\'),
+            ('\'', '\''),
+        ];
+        for (escape, value) in cases {
+            assert_eq!(escaped_char(*escape), *value, "\\{escape}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown escape \\q in char literal!")]
+    fn panics_on_unknown_escape() {
+        escaped_char('q');
+    }
+}
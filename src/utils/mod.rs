@@ -2,5 +2,5 @@ pub mod command_line;
 pub mod compile_times;
 pub mod error;
 pub mod hardware_conf;
+pub mod literals;
 pub mod nid_fs;
-